@@ -0,0 +1,10 @@
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // use the protoc shipped in protoc-bin-vendored, so nobody needs it installed
+    // SAFETY: build scripts are single threaded
+    unsafe {
+        std::env::set_var("PROTOC", protoc_bin_vendored::protoc_bin_path()?);
+    }
+
+    tonic_prost_build::compile_protos("proto/tasks.proto")?;
+    Ok(())
+}
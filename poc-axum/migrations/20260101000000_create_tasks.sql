@@ -0,0 +1,5 @@
+CREATE TABLE IF NOT EXISTS tasks (
+    task_id SERIAL PRIMARY KEY,
+    name VARCHAR NOT NULL,
+    priority INT
+);
@@ -0,0 +1,46 @@
+-- every request runs in a transaction that does SET LOCAL ROLE tasks_app and sets app.tenant_id,
+-- the policies below then only let it see and write the rows of that tenant.
+-- superusers bypass row level security, so the server must not skip the SET LOCAL ROLE;
+-- when it connects as a regular user that user needs GRANT tasks_app TO <user>.
+DO $$
+BEGIN
+    IF NOT EXISTS (SELECT FROM pg_roles WHERE rolname = 'tasks_app') THEN
+        CREATE ROLE tasks_app NOLOGIN;
+    END IF;
+END
+$$;
+
+GRANT SELECT, INSERT, UPDATE, DELETE ON ALL TABLES IN SCHEMA public TO tasks_app;
+GRANT USAGE ON ALL SEQUENCES IN SCHEMA public TO tasks_app;
+ALTER DEFAULT PRIVILEGES IN SCHEMA public GRANT SELECT, INSERT, UPDATE, DELETE ON TABLES TO tasks_app;
+ALTER DEFAULT PRIVILEGES IN SCHEMA public GRANT USAGE ON SEQUENCES TO tasks_app;
+
+-- rows that existed before tenants belong to the 'default' tenant
+ALTER TABLE tasks ADD COLUMN IF NOT EXISTS tenant_id VARCHAR NOT NULL DEFAULT 'default';
+ALTER TABLE tasks ALTER COLUMN tenant_id SET DEFAULT current_setting('app.tenant_id');
+CREATE INDEX IF NOT EXISTS tasks_tenant_id ON tasks (tenant_id, task_id);
+
+ALTER TABLE webhooks ADD COLUMN IF NOT EXISTS tenant_id VARCHAR NOT NULL DEFAULT 'default';
+ALTER TABLE webhooks ALTER COLUMN tenant_id SET DEFAULT current_setting('app.tenant_id');
+
+ALTER TABLE tasks ENABLE ROW LEVEL SECURITY;
+ALTER TABLE tasks FORCE ROW LEVEL SECURITY;
+DROP POLICY IF EXISTS tenant_isolation ON tasks;
+CREATE POLICY tenant_isolation ON tasks
+    USING (tenant_id = current_setting('app.tenant_id', true))
+    WITH CHECK (tenant_id = current_setting('app.tenant_id', true));
+
+ALTER TABLE webhooks ENABLE ROW LEVEL SECURITY;
+ALTER TABLE webhooks FORCE ROW LEVEL SECURITY;
+DROP POLICY IF EXISTS tenant_isolation ON webhooks;
+CREATE POLICY tenant_isolation ON webhooks
+    USING (tenant_id = current_setting('app.tenant_id', true))
+    WITH CHECK (tenant_id = current_setting('app.tenant_id', true));
+
+-- deliveries follow their webhook, which is already filtered by its own policy
+ALTER TABLE webhook_deliveries ENABLE ROW LEVEL SECURITY;
+ALTER TABLE webhook_deliveries FORCE ROW LEVEL SECURITY;
+DROP POLICY IF EXISTS tenant_isolation ON webhook_deliveries;
+CREATE POLICY tenant_isolation ON webhook_deliveries
+    USING (EXISTS (SELECT 1 FROM webhooks w WHERE w.webhook_id = webhook_deliveries.webhook_id))
+    WITH CHECK (EXISTS (SELECT 1 FROM webhooks w WHERE w.webhook_id = webhook_deliveries.webhook_id));
@@ -0,0 +1,37 @@
+-- subtasks point at their parent, dependencies are "task_id is blocked by blocked_by_id" edges.
+-- the foreign keys include tenant_id: they are checked without row level security,
+-- so a task could otherwise reference a task of another tenant
+ALTER TABLE tasks ADD COLUMN IF NOT EXISTS parent_id INT;
+ALTER TABLE tasks ADD COLUMN IF NOT EXISTS done BOOLEAN NOT NULL DEFAULT false;
+
+DO $$
+BEGIN
+    IF NOT EXISTS (SELECT FROM pg_constraint WHERE conname = 'tasks_tenant_id_task_id_key') THEN
+        ALTER TABLE tasks ADD CONSTRAINT tasks_tenant_id_task_id_key UNIQUE (tenant_id, task_id);
+    END IF;
+    -- no ON DELETE, a task with subtasks can not be deleted
+    IF NOT EXISTS (SELECT FROM pg_constraint WHERE conname = 'tasks_parent_fkey') THEN
+        ALTER TABLE tasks ADD CONSTRAINT tasks_parent_fkey
+            FOREIGN KEY (tenant_id, parent_id) REFERENCES tasks (tenant_id, task_id);
+    END IF;
+END
+$$;
+CREATE INDEX IF NOT EXISTS tasks_parent_id ON tasks (parent_id);
+
+CREATE TABLE IF NOT EXISTS task_dependencies (
+    tenant_id VARCHAR NOT NULL DEFAULT current_setting('app.tenant_id'),
+    task_id INT NOT NULL,
+    blocked_by_id INT NOT NULL,
+    PRIMARY KEY (tenant_id, task_id, blocked_by_id),
+    FOREIGN KEY (tenant_id, task_id) REFERENCES tasks (tenant_id, task_id) ON DELETE CASCADE,
+    FOREIGN KEY (tenant_id, blocked_by_id) REFERENCES tasks (tenant_id, task_id) ON DELETE CASCADE,
+    CHECK (task_id <> blocked_by_id)
+);
+CREATE INDEX IF NOT EXISTS task_dependencies_blocked_by_id ON task_dependencies (blocked_by_id);
+
+ALTER TABLE task_dependencies ENABLE ROW LEVEL SECURITY;
+ALTER TABLE task_dependencies FORCE ROW LEVEL SECURITY;
+DROP POLICY IF EXISTS tenant_isolation ON task_dependencies;
+CREATE POLICY tenant_isolation ON task_dependencies
+    USING (tenant_id = current_setting('app.tenant_id', true))
+    WITH CHECK (tenant_id = current_setting('app.tenant_id', true));
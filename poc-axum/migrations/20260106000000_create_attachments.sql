@@ -0,0 +1,21 @@
+-- the file itself lives in the blob store under its sha256, shared by every attachment with the same content
+CREATE TABLE IF NOT EXISTS attachments (
+    attachment_id SERIAL PRIMARY KEY,
+    tenant_id VARCHAR NOT NULL DEFAULT current_setting('app.tenant_id'),
+    task_id INT NOT NULL,
+    filename VARCHAR NOT NULL,
+    -- sniffed from the content, not the type the client claimed
+    content_type VARCHAR NOT NULL,
+    size BIGINT NOT NULL,
+    sha256 CHAR(64) NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    FOREIGN KEY (tenant_id, task_id) REFERENCES tasks (tenant_id, task_id) ON DELETE CASCADE,
+    UNIQUE (task_id, sha256)
+);
+
+ALTER TABLE attachments ENABLE ROW LEVEL SECURITY;
+ALTER TABLE attachments FORCE ROW LEVEL SECURITY;
+DROP POLICY IF EXISTS tenant_isolation ON attachments;
+CREATE POLICY tenant_isolation ON attachments
+    USING (tenant_id = current_setting('app.tenant_id', true))
+    WITH CHECK (tenant_id = current_setting('app.tenant_id', true));
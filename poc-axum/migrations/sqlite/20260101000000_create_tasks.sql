@@ -0,0 +1,8 @@
+-- the tasks table of migrations/ for the SQLite task store, same versions as the Postgres migrations.
+-- timestamps are RFC 3339 text, booleans 0 or 1
+CREATE TABLE IF NOT EXISTS tasks (
+    task_id INTEGER PRIMARY KEY AUTOINCREMENT,
+    name TEXT NOT NULL,
+    priority INTEGER,
+    due_at TEXT
+);
@@ -0,0 +1,3 @@
+-- no row level security in SQLite, every query of src/sqlite.rs filters on tenant_id itself
+ALTER TABLE tasks ADD COLUMN tenant_id TEXT NOT NULL DEFAULT 'default';
+CREATE INDEX IF NOT EXISTS tasks_tenant_id ON tasks (tenant_id, task_id);
@@ -0,0 +1,21 @@
+-- SQLite can not add a constraint to a table, so the table is rebuilt with parent_id and done.
+-- the foreign key includes tenant_id, a task can not be the subtask of a task of another tenant
+CREATE TABLE tasks_new (
+    task_id INTEGER PRIMARY KEY AUTOINCREMENT,
+    name TEXT NOT NULL,
+    priority INTEGER,
+    due_at TEXT,
+    tenant_id TEXT NOT NULL DEFAULT 'default',
+    parent_id INTEGER,
+    done BOOLEAN NOT NULL DEFAULT FALSE,
+    CONSTRAINT tasks_tenant_id_task_id_key UNIQUE (tenant_id, task_id),
+    -- no ON DELETE, a task with subtasks can not be deleted
+    CONSTRAINT tasks_parent_fkey FOREIGN KEY (tenant_id, parent_id) REFERENCES tasks_new (tenant_id, task_id)
+);
+INSERT INTO tasks_new (task_id, name, priority, due_at, tenant_id)
+    SELECT task_id, name, priority, due_at, tenant_id FROM tasks;
+DROP TABLE tasks;
+ALTER TABLE tasks_new RENAME TO tasks;
+
+CREATE INDEX IF NOT EXISTS tasks_tenant_id ON tasks (tenant_id, task_id);
+CREATE INDEX IF NOT EXISTS tasks_parent_id ON tasks (parent_id);
@@ -0,0 +1,531 @@
+use crate::blob_store::{BlobStore, LocalBlobStore};
+use crate::tasks;
+use crate::tenant::{self, Tenant};
+use crate::{AppState, api_error, internal_error, task_not_found};
+use axum::body::Body;
+use axum::extract::{DefaultBodyLimit, Multipart, Path, State};
+use axum::http::header::{
+    ACCEPT_RANGES, CONTENT_DISPOSITION, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, ETAG, RANGE,
+    X_CONTENT_TYPE_OPTIONS,
+};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::{Json, Router};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use sqlx::PgPool;
+use std::ops::Range;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::io::AsyncWriteExt;
+
+const DEFAULT_DIR: &str = "attachments";
+const DEFAULT_MAX_BYTES: u64 = 10 * 1024 * 1024;
+// enough for every signature infer knows, and to tell text from binary
+const SNIFF_LEN: usize = 512;
+// decided by sniffing the content, the content type sent by the client is ignored
+const ALLOWED_TYPES: [&str; 6] = [
+    "image/png",
+    "image/jpeg",
+    "image/gif",
+    "image/webp",
+    "application/pdf",
+    "text/plain",
+];
+
+static STAGED_COUNT: AtomicU64 = AtomicU64::new(0);
+
+/// Where attachments are stored and how big they can be.
+#[derive(Clone)]
+pub struct Attachments {
+    store: Arc<dyn BlobStore>,
+    staging_dir: PathBuf,
+    max_bytes: u64,
+}
+
+impl Attachments {
+    pub fn new(store: Arc<dyn BlobStore>, staging_dir: PathBuf, max_bytes: u64) -> Self {
+        Attachments {
+            store,
+            staging_dir,
+            max_bytes,
+        }
+    }
+
+    /// Reads `ATTACHMENTS_DIR` and `ATTACHMENT_MAX_BYTES`, files go to a [`LocalBlobStore`].
+    pub fn from_env() -> Self {
+        let dir = PathBuf::from(
+            std::env::var("ATTACHMENTS_DIR").unwrap_or_else(|_| DEFAULT_DIR.to_owned()),
+        );
+        let max_bytes = std::env::var("ATTACHMENT_MAX_BYTES")
+            .ok()
+            .and_then(|max| max.parse().ok())
+            .unwrap_or(DEFAULT_MAX_BYTES);
+        Self::local(dir, max_bytes)
+    }
+
+    /// Blobs in `dir/blobs`, uploads staged in `dir/tmp` so storing them is a rename.
+    pub fn local(dir: PathBuf, max_bytes: u64) -> Self {
+        Self::new(
+            Arc::new(LocalBlobStore::new(dir.join("blobs"))),
+            dir.join("tmp"),
+            max_bytes,
+        )
+    }
+}
+
+#[derive(Serialize, sqlx::FromRow)]
+struct AttachmentRow {
+    attachment_id: i32,
+    task_id: i32,
+    filename: String,
+    content_type: String,
+    size: i64,
+    sha256: String,
+    created_at: DateTime<Utc>,
+}
+
+pub fn create_attachments_router() -> Router<AppState> {
+    Router::new()
+        .route(
+            "/tasks/{task_id}/attachments",
+            // the upload enforces its own limit while streaming, see Attachments::max_bytes
+            get(get_attachments)
+                .post(upload_attachment)
+                .layer(DefaultBodyLimit::disable()),
+        )
+        .route(
+            "/tasks/{task_id}/attachments/{attachment_id}",
+            get(download_attachment),
+        )
+}
+
+async fn get_attachments(
+    State(db_pool): State<PgPool>,
+    tenant: Tenant,
+    Path(task_id): Path<i32>,
+) -> Result<Json<Vec<AttachmentRow>>, (StatusCode, String)> {
+    let mut tx = tenant::begin(&db_pool, &tenant)
+        .await
+        .map_err(internal_error)?;
+    let rows = sqlx::query_as(
+        "SELECT attachment_id, task_id, filename, content_type, size, sha256, created_at
+         FROM attachments WHERE task_id = $1 ORDER BY attachment_id",
+    )
+    .bind(task_id)
+    .fetch_all(&mut *tx)
+    .await
+    .map_err(internal_error)?;
+    tx.commit().await.map_err(internal_error)?;
+
+    Ok(Json(rows))
+}
+
+// removes the staged upload when it was not taken over by the blob store
+struct StagedFile(PathBuf);
+
+impl Drop for StagedFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+/// Streams the `file` field of the multipart body to disk, hashing it on the way.
+/// Uploading the same content to the same task again returns the existing attachment.
+async fn upload_attachment(
+    State(db_pool): State<PgPool>,
+    State(attachments): State<Attachments>,
+    tenant: Tenant,
+    Path(task_id): Path<i32>,
+    mut multipart: Multipart,
+) -> Result<(StatusCode, Json<AttachmentRow>), (StatusCode, String)> {
+    // before reading the body, nothing gets written for a task that is not there
+    tasks::find_task(&db_pool, &tenant, task_id)
+        .await
+        .map_err(internal_error)?
+        .ok_or_else(task_not_found)?;
+
+    let mut field = loop {
+        match multipart.next_field().await.map_err(bad_multipart)? {
+            Some(field) if field.name() == Some("file") => break field,
+            Some(_) => continue,
+            None => {
+                return Err(api_error(StatusCode::BAD_REQUEST, "Missing file field"));
+            }
+        }
+    };
+    let filename = sanitize_filename(field.file_name().unwrap_or_default());
+
+    tokio::fs::create_dir_all(&attachments.staging_dir)
+        .await
+        .map_err(io_error)?;
+    let staged = StagedFile(attachments.staging_dir.join(format!(
+        "{}-{}.part",
+        std::process::id(),
+        STAGED_COUNT.fetch_add(1, Ordering::Relaxed)
+    )));
+    let mut file = tokio::fs::File::create(&staged.0).await.map_err(io_error)?;
+    let mut hasher = Sha256::new();
+    let mut head = Vec::with_capacity(SNIFF_LEN);
+    let mut size: u64 = 0;
+    while let Some(chunk) = field.chunk().await.map_err(bad_multipart)? {
+        size += chunk.len() as u64;
+        if size > attachments.max_bytes {
+            return Err(api_error(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                &format!(
+                    "Attachments can not be larger than {} bytes",
+                    attachments.max_bytes
+                ),
+            ));
+        }
+        if head.len() < SNIFF_LEN {
+            let missing = (SNIFF_LEN - head.len()).min(chunk.len());
+            head.extend_from_slice(&chunk[..missing]);
+        }
+        hasher.update(&chunk);
+        file.write_all(&chunk).await.map_err(io_error)?;
+    }
+    file.flush().await.map_err(io_error)?;
+    drop(file);
+
+    let content_type = sniff(&head)
+        .filter(|content_type| ALLOWED_TYPES.contains(content_type))
+        .ok_or_else(|| {
+            api_error(
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                &format!("Only {ALLOWED_TYPES:?} can be attached"),
+            )
+        })?;
+    let sha256 = hex::encode(hasher.finalize());
+    attachments
+        .store
+        .put(&sha256, &staged.0)
+        .await
+        .map_err(io_error)?;
+
+    let mut tx = tenant::begin(&db_pool, &tenant)
+        .await
+        .map_err(internal_error)?;
+    let inserted: Option<AttachmentRow> = sqlx::query_as(
+        "INSERT INTO attachments (task_id, filename, content_type, size, sha256)
+         VALUES ($1, $2, $3, $4, $5)
+         ON CONFLICT (task_id, sha256) DO NOTHING
+         RETURNING attachment_id, task_id, filename, content_type, size, sha256, created_at",
+    )
+    .bind(task_id)
+    .bind(&filename)
+    .bind(content_type)
+    .bind(size as i64)
+    .bind(&sha256)
+    .fetch_optional(&mut *tx)
+    .await
+    .map_err(internal_error)?;
+    let (status, row) = match inserted {
+        Some(row) => (StatusCode::CREATED, row),
+        None => {
+            let existing = sqlx::query_as(
+                "SELECT attachment_id, task_id, filename, content_type, size, sha256, created_at
+                 FROM attachments WHERE task_id = $1 AND sha256 = $2",
+            )
+            .bind(task_id)
+            .bind(&sha256)
+            .fetch_one(&mut *tx)
+            .await
+            .map_err(internal_error)?;
+            (StatusCode::OK, existing)
+        }
+    };
+    tx.commit().await.map_err(internal_error)?;
+
+    Ok((status, Json(row)))
+}
+
+/// Honours a single `Range: bytes=...`, several ranges get the whole file.
+async fn download_attachment(
+    State(db_pool): State<PgPool>,
+    State(attachments): State<Attachments>,
+    tenant: Tenant,
+    Path((task_id, attachment_id)): Path<(i32, i32)>,
+    headers: HeaderMap,
+) -> Result<Response, (StatusCode, String)> {
+    let mut tx = tenant::begin(&db_pool, &tenant)
+        .await
+        .map_err(internal_error)?;
+    let row: AttachmentRow = sqlx::query_as(
+        "SELECT attachment_id, task_id, filename, content_type, size, sha256, created_at
+         FROM attachments WHERE attachment_id = $1 AND task_id = $2",
+    )
+    .bind(attachment_id)
+    .bind(task_id)
+    .fetch_optional(&mut *tx)
+    .await
+    .map_err(internal_error)?
+    .ok_or_else(|| api_error(StatusCode::NOT_FOUND, "Attachment not found"))?;
+    tx.commit().await.map_err(internal_error)?;
+
+    let size = row.size as u64;
+    let range = headers
+        .get(RANGE)
+        .and_then(|range| range.to_str().ok())
+        .map(|range| parse_range(range, size))
+        .transpose()
+        .map_err(|()| {
+            let mut response =
+                api_error(StatusCode::RANGE_NOT_SATISFIABLE, "Range not satisfiable")
+                    .into_response();
+            response
+                .headers_mut()
+                .insert(CONTENT_RANGE, format!("bytes */{size}").parse().unwrap());
+            response
+        });
+    let range = match range {
+        Ok(range) => range.flatten(),
+        Err(response) => return Ok(response),
+    };
+
+    let (status, bytes) = match &range {
+        Some(range) => (StatusCode::PARTIAL_CONTENT, range.clone()),
+        None => (StatusCode::OK, 0..size),
+    };
+    let stream = attachments
+        .store
+        .get(&row.sha256, bytes.clone())
+        .await
+        .map_err(io_error)?;
+
+    let mut response = (
+        status,
+        [
+            (CONTENT_TYPE, row.content_type),
+            (CONTENT_LENGTH, (bytes.end - bytes.start).to_string()),
+            (ACCEPT_RANGES, "bytes".to_owned()),
+            (ETAG, format!("\"{}\"", row.sha256)),
+            (
+                CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{}\"", row.filename),
+            ),
+            // the browser must not second guess the type sniffed on upload
+            (X_CONTENT_TYPE_OPTIONS, "nosniff".to_owned()),
+        ],
+        Body::from_stream(stream),
+    )
+        .into_response();
+    if range.is_some() {
+        response.headers_mut().insert(
+            CONTENT_RANGE,
+            format!("bytes {}-{}/{size}", bytes.start, bytes.end - 1)
+                .parse()
+                .unwrap(),
+        );
+    }
+
+    Ok(response)
+}
+
+/// `Ok(None)` for a range header to ignore (malformed or several ranges), `Err` for one outside the file.
+fn parse_range(header: &str, size: u64) -> Result<Option<Range<u64>>, ()> {
+    let Some(spec) = header.strip_prefix("bytes=") else {
+        return Ok(None);
+    };
+    if spec.contains(',') {
+        return Ok(None);
+    }
+    let Some((start, end)) = spec.trim().split_once('-') else {
+        return Ok(None);
+    };
+
+    let range = match (start.parse::<u64>(), end.parse::<u64>()) {
+        (Ok(start), Ok(end)) if start <= end => start..(end + 1).min(size),
+        (Ok(start), Err(_)) if end.is_empty() => start..size,
+        // the last `suffix` bytes
+        (Err(_), Ok(suffix)) if start.is_empty() => size.saturating_sub(suffix)..size,
+        _ => return Ok(None),
+    };
+    if range.start >= range.end {
+        return Err(());
+    }
+
+    Ok(Some(range))
+}
+
+fn sniff(head: &[u8]) -> Option<&'static str> {
+    if let Some(kind) = infer::get(head) {
+        return Some(kind.mime_type());
+    }
+    // valid utf-8, apart from a character cut at the end of the sniffed bytes
+    match std::str::from_utf8(head) {
+        Ok(_) => Some("text/plain"),
+        Err(e) if e.error_len().is_none() => Some("text/plain"),
+        Err(_) => None,
+    }
+}
+
+// only the last path component, nothing that breaks out of the quoted Content-Disposition
+fn sanitize_filename(filename: &str) -> String {
+    let name: String = filename
+        .rsplit(['/', '\\'])
+        .next()
+        .unwrap_or_default()
+        .chars()
+        .filter(|c| !c.is_control() && *c != '"')
+        .take(255)
+        .collect();
+    if name.is_empty() {
+        return "attachment".to_owned();
+    }
+    name
+}
+
+fn bad_multipart(e: axum::extract::multipart::MultipartError) -> (StatusCode, String) {
+    api_error(e.status(), &e.body_text())
+}
+
+fn io_error(e: std::io::Error) -> (StatusCode, String) {
+    api_error(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tenant::TENANT_HEADER;
+    use crate::test_harness::new_task;
+    use axum::http::Request;
+    use http_body_util::BodyExt;
+    use serde_json::Value;
+    use tower::ServiceExt;
+
+    const BOUNDARY: &str = "XBOUNDARYX";
+    const PNG: &[u8] = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(parse_range("bytes=0-4", 10), Ok(Some(0..5)));
+        assert_eq!(parse_range("bytes=5-", 10), Ok(Some(5..10)));
+        assert_eq!(parse_range("bytes=-3", 10), Ok(Some(7..10)));
+        assert_eq!(parse_range("bytes=8-100", 10), Ok(Some(8..10)));
+        assert_eq!(parse_range("bytes=10-", 10), Err(()));
+        assert_eq!(parse_range("bytes=-0", 10), Err(()));
+        assert_eq!(parse_range("bytes=0-1,4-5", 10), Ok(None));
+        assert_eq!(parse_range("bytes=5-2", 10), Ok(None));
+        assert_eq!(parse_range("items=0-1", 10), Ok(None));
+    }
+
+    #[test]
+    fn test_sniff_and_filename() {
+        assert_eq!(sniff(PNG), Some("image/png"));
+        assert_eq!(sniff(b"plain notes"), Some("text/plain"));
+        // cut in the middle of a two byte character
+        assert_eq!(sniff(&"ñ".as_bytes()[..1]), Some("text/plain"));
+        assert_eq!(sniff(b"\xff\xfe\x00\x01binary"), None);
+        assert_eq!(sanitize_filename("../../etc/pass\"wd"), "passwd");
+        assert_eq!(sanitize_filename(""), "attachment");
+    }
+
+    fn multipart_body(filename: &str, content: &[u8]) -> Vec<u8> {
+        let mut body = format!(
+            "--{BOUNDARY}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"{filename}\"\r\n\
+             Content-Type: application/octet-stream\r\n\r\n"
+        )
+        .into_bytes();
+        body.extend_from_slice(content);
+        body.extend_from_slice(format!("\r\n--{BOUNDARY}--\r\n").as_bytes());
+        body
+    }
+
+    async fn send(app: &Router, request: axum::http::request::Builder, body: Body) -> Response {
+        let request = request.header(TENANT_HEADER, "team-a").body(body).unwrap();
+        app.clone().oneshot(request).await.unwrap()
+    }
+
+    async fn upload(
+        app: &Router,
+        task_id: i32,
+        filename: &str,
+        content: &[u8],
+    ) -> (StatusCode, Value) {
+        let request = Request::builder()
+            .method("POST")
+            .uri(format!("/tasks/{task_id}/attachments"))
+            .header(
+                CONTENT_TYPE,
+                format!("multipart/form-data; boundary={BOUNDARY}"),
+            );
+        let response = send(app, request, Body::from(multipart_body(filename, content))).await;
+        let status = response.status();
+        let body = response.collect().await.unwrap().to_bytes();
+        (status, serde_json::from_slice(&body).unwrap_or(Value::Null))
+    }
+
+    #[sqlx::test]
+    async fn test_upload_and_download(db_pool: PgPool) {
+        let dir = std::env::temp_dir().join(format!("attachments-test-{}", std::process::id()));
+        let mut state = crate::tests::test_state(db_pool.clone());
+        state.attachments = Attachments::local(dir.clone(), 64);
+        let app = create_attachments_router().with_state(state);
+        let task = new_task("with files");
+        let task_id = tasks::insert_task(&db_pool, &Tenant::new("team-a").unwrap(), &task)
+            .await
+            .unwrap()
+            .task_id;
+
+        let (status, attachment) = upload(&app, task_id, "notes.txt", b"0123456789").await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(attachment["content_type"], "text/plain");
+        assert_eq!(attachment["size"], 10);
+        // same content again, same attachment
+        let (status, again) = upload(&app, task_id, "copy.txt", b"0123456789").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(again["attachment_id"], attachment["attachment_id"]);
+
+        let (status, _) = upload(&app, task_id, "big.txt", &[b'a'; 65]).await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+        let (status, _) = upload(&app, task_id, "x.bin", b"\xff\xfe\x00\x01").await;
+        assert_eq!(status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        let (status, png) = upload(&app, task_id, "pic.txt", PNG).await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(png["content_type"], "image/png");
+        let (status, _) = upload(&app, 999_999, "notes.txt", b"hi").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let uri = format!(
+            "/tasks/{task_id}/attachments/{}",
+            attachment["attachment_id"]
+        );
+        let response = send(&app, Request::builder().uri(&uri), Body::empty()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[CONTENT_TYPE], "text/plain");
+        let body = response.collect().await.unwrap().to_bytes();
+        assert_eq!(&body[..], b"0123456789");
+
+        let request = Request::builder().uri(&uri).header(RANGE, "bytes=2-4");
+        let response = send(&app, request, Body::empty()).await;
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(response.headers()[CONTENT_RANGE], "bytes 2-4/10");
+        let body = response.collect().await.unwrap().to_bytes();
+        assert_eq!(&body[..], b"234");
+
+        let request = Request::builder().uri(&uri).header(RANGE, "bytes=20-");
+        let response = send(&app, request, Body::empty()).await;
+        assert_eq!(response.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(response.headers()[CONTENT_RANGE], "bytes */10");
+
+        let response = send(
+            &app,
+            Request::builder().uri(format!("/tasks/{task_id}/attachments")),
+            Body::empty(),
+        )
+        .await;
+        let body = response.collect().await.unwrap().to_bytes();
+        let list: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(list.as_array().unwrap().len(), 2);
+
+        // nothing left behind in the staging directory
+        let mut staged = tokio::fs::read_dir(dir.join("tmp")).await.unwrap();
+        assert!(staged.next_entry().await.unwrap().is_none());
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+}
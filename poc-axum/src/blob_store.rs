@@ -0,0 +1,101 @@
+use async_trait::async_trait;
+use axum::body::Bytes;
+use std::io;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio_stream::Stream;
+use tokio_util::io::ReaderStream;
+
+pub type BlobStream = Pin<Box<dyn Stream<Item = io::Result<Bytes>> + Send>>;
+
+/// Content addressed storage of attachment files, keyed by the hex sha256 of the content.
+/// Uploads are staged on local disk first so they can be hashed and checked before `put`.
+#[async_trait]
+pub trait BlobStore: Send + Sync {
+    /// Takes over the staged file, storing content that is already there keeps the one copy.
+    async fn put(&self, sha256: &str, staged: &Path) -> io::Result<()>;
+
+    /// Streams the bytes of `range`, which must be inside the blob.
+    async fn get(&self, sha256: &str, range: Range<u64>) -> io::Result<BlobStream>;
+}
+
+/// Blobs as files under `root`, `ab/abcdef...` so no directory gets too large.
+pub struct LocalBlobStore {
+    root: PathBuf,
+}
+
+impl LocalBlobStore {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        LocalBlobStore { root: root.into() }
+    }
+
+    fn path(&self, sha256: &str) -> io::Result<PathBuf> {
+        // the key ends up in a path, never let it be anything but a hash
+        if sha256.len() != 64 || !sha256.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid blob key",
+            ));
+        }
+        Ok(self.root.join(&sha256[..2]).join(sha256))
+    }
+}
+
+#[async_trait]
+impl BlobStore for LocalBlobStore {
+    async fn put(&self, sha256: &str, staged: &Path) -> io::Result<()> {
+        let path = self.path(sha256)?;
+        if tokio::fs::try_exists(&path).await? {
+            return tokio::fs::remove_file(staged).await;
+        }
+        tokio::fs::create_dir_all(path.parent().unwrap()).await?;
+        // a rename, so readers never see a half written blob. staged must be on the same filesystem
+        tokio::fs::rename(staged, &path).await
+    }
+
+    async fn get(&self, sha256: &str, range: Range<u64>) -> io::Result<BlobStream> {
+        let mut file = tokio::fs::File::open(self.path(sha256)?).await?;
+        file.seek(io::SeekFrom::Start(range.start)).await?;
+        let reader = file.take(range.end - range.start);
+
+        Ok(Box::pin(ReaderStream::new(reader)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio_stream::StreamExt;
+
+    const SHA: &str = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+
+    async fn read(store: &LocalBlobStore, range: Range<u64>) -> Vec<u8> {
+        let mut stream = store.get(SHA, range).await.unwrap();
+        let mut bytes = Vec::new();
+        while let Some(chunk) = stream.next().await {
+            bytes.extend_from_slice(&chunk.unwrap());
+        }
+        bytes
+    }
+
+    #[tokio::test]
+    async fn test_put_get_and_dedupe() {
+        let root = std::env::temp_dir().join(format!("blob-store-test-{}", std::process::id()));
+        let store = LocalBlobStore::new(&root);
+        tokio::fs::create_dir_all(&root).await.unwrap();
+
+        for _ in 0..2 {
+            let staged = root.join("staged");
+            tokio::fs::write(&staged, b"hello").await.unwrap();
+            store.put(SHA, &staged).await.unwrap();
+            assert!(!tokio::fs::try_exists(&staged).await.unwrap());
+        }
+        assert_eq!(read(&store, 0..5).await, b"hello");
+        assert_eq!(read(&store, 1..3).await, b"el");
+        assert!(store.get("../../etc/passwd", 0..1).await.is_err());
+
+        tokio::fs::remove_dir_all(&root).await.unwrap();
+    }
+}
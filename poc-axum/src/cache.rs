@@ -0,0 +1,304 @@
+use crate::tasks::TaskRow;
+use crate::tenant::Tenant;
+use lru::LruCache;
+use serde::Serialize;
+use sqlx::PgPool;
+use sqlx::postgres::PgListener;
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// channel the task writes notify on, see tasks::notify_changed
+pub const TASK_CHANGES_CHANNEL: &str = "task_changes";
+
+const DEFAULT_CAPACITY: usize = 1024;
+const DEFAULT_TTL: Duration = Duration::from_secs(30);
+
+#[derive(Clone)]
+pub struct TaskCacheConfig {
+    pub capacity: usize,
+    pub ttl: Duration,
+    /// Also invalidate on NOTIFY from other instances writing to the same database.
+    pub listen: bool,
+}
+
+impl TaskCacheConfig {
+    /// Reads `TASK_CACHE_CAPACITY`, `TASK_CACHE_TTL_SECS` and `TASK_CACHE_LISTEN`.
+    pub fn from_env() -> Self {
+        TaskCacheConfig {
+            capacity: std::env::var("TASK_CACHE_CAPACITY")
+                .ok()
+                .and_then(|capacity| capacity.parse().ok())
+                .unwrap_or(DEFAULT_CAPACITY),
+            ttl: std::env::var("TASK_CACHE_TTL_SECS")
+                .ok()
+                .and_then(|secs| secs.parse().ok())
+                .map_or(DEFAULT_TTL, Duration::from_secs),
+            listen: std::env::var("TASK_CACHE_LISTEN").is_ok_and(|listen| listen == "true"),
+        }
+    }
+}
+
+/// A value read from the cache and how long ago it was loaded from the database.
+pub struct Cached<T> {
+    pub value: T,
+    pub age: Duration,
+}
+
+struct Entry<T> {
+    value: T,
+    loaded_at: Instant,
+}
+
+struct Entries {
+    // None is cached too, so looking up a missing task does not hit the database every time
+    tasks: LruCache<(String, i32), Entry<Option<TaskRow>>>,
+    lists: LruCache<String, Entry<Vec<TaskRow>>>,
+    // bumped by every invalidation, a load that started before it is not stored
+    generation: u64,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub entries: usize,
+}
+
+/// In-process LRU of task lookups and task lists, per tenant, each entry expiring after the ttl.
+#[derive(Clone)]
+pub struct TaskCache {
+    entries: Arc<Mutex<Entries>>,
+    ttl: Duration,
+    hits: Arc<AtomicU64>,
+    misses: Arc<AtomicU64>,
+}
+
+impl TaskCache {
+    pub fn new(config: &TaskCacheConfig) -> Self {
+        let capacity = NonZeroUsize::new(config.capacity).unwrap_or(NonZeroUsize::MIN);
+        TaskCache {
+            entries: Arc::new(Mutex::new(Entries {
+                tasks: LruCache::new(capacity),
+                lists: LruCache::new(capacity),
+                generation: 0,
+            })),
+            ttl: config.ttl,
+            hits: Arc::new(AtomicU64::new(0)),
+            misses: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Take it before reading the database and pass it to `put_*`, so a value read
+    /// before a concurrent write is not cached after that write invalidated it.
+    pub fn generation(&self) -> u64 {
+        self.lock().generation
+    }
+
+    pub fn task(&self, tenant: &Tenant, task_id: i32) -> Option<Cached<Option<TaskRow>>> {
+        let key = (tenant.id().to_owned(), task_id);
+        let mut entries = self.lock();
+        let cached = fresh(&mut entries.tasks, &key, self.ttl);
+        self.count(cached.is_some());
+        cached
+    }
+
+    pub fn put_task(&self, tenant: &Tenant, task_id: i32, task: Option<TaskRow>, generation: u64) {
+        let mut entries = self.lock();
+        if entries.generation == generation {
+            entries
+                .tasks
+                .put((tenant.id().to_owned(), task_id), Entry::new(task));
+        }
+    }
+
+    pub fn tasks(&self, tenant: &Tenant) -> Option<Cached<Vec<TaskRow>>> {
+        let mut entries = self.lock();
+        let cached = fresh(&mut entries.lists, tenant.id(), self.ttl);
+        self.count(cached.is_some());
+        cached
+    }
+
+    pub fn put_tasks(&self, tenant: &Tenant, tasks: Vec<TaskRow>, generation: u64) {
+        let mut entries = self.lock();
+        if entries.generation == generation {
+            entries.lists.put(tenant.id().to_owned(), Entry::new(tasks));
+        }
+    }
+
+    /// Called by every write of a task, drops the task and the lists of its tenant.
+    pub fn invalidate(&self, tenant_id: &str, task_id: i32) {
+        let mut entries = self.lock();
+        entries.generation += 1;
+        entries.tasks.pop(&(tenant_id.to_owned(), task_id));
+        entries.lists.pop(tenant_id);
+    }
+
+    pub fn clear(&self) {
+        let mut entries = self.lock();
+        entries.generation += 1;
+        entries.tasks.clear();
+        entries.lists.clear();
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let entries = self.lock();
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            entries: entries.tasks.len() + entries.lists.len(),
+        }
+    }
+
+    fn count(&self, hit: bool) {
+        let counter = if hit { &self.hits } else { &self.misses };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Entries> {
+        // nothing panics while holding the lock, so it is never poisoned
+        self.entries.lock().unwrap()
+    }
+}
+
+impl<T> Entry<T> {
+    fn new(value: T) -> Self {
+        Entry {
+            value,
+            loaded_at: Instant::now(),
+        }
+    }
+}
+
+fn fresh<K, Q, T>(cache: &mut LruCache<K, Entry<T>>, key: &Q, ttl: Duration) -> Option<Cached<T>>
+where
+    K: std::hash::Hash + Eq + std::borrow::Borrow<Q>,
+    Q: std::hash::Hash + Eq + ?Sized,
+    T: Clone,
+{
+    let entry = cache.get(key)?;
+    let age = entry.loaded_at.elapsed();
+    if age < ttl {
+        return Some(Cached {
+            value: entry.value.clone(),
+            age,
+        });
+    }
+
+    cache.pop(key);
+    None
+}
+
+/// Invalidates `cache` on the NOTIFYs of task writes from every instance, payload `tenant_id:task_id`.
+/// Notifications are lost while the connection is down, so a reconnect clears the whole cache.
+pub fn spawn_listener(db_pool: PgPool, cache: TaskCache) {
+    tokio::spawn(async move {
+        loop {
+            if let Err(e) = listen(&db_pool, &cache).await {
+                eprintln!("Task cache listener failed: {e}");
+            }
+            cache.clear();
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+    });
+}
+
+async fn listen(db_pool: &PgPool, cache: &TaskCache) -> Result<(), sqlx::Error> {
+    let mut listener = PgListener::connect_with(db_pool).await?;
+    listener.listen(TASK_CHANGES_CHANNEL).await?;
+    loop {
+        match listener.try_recv().await? {
+            Some(notification) => {
+                let changed = notification
+                    .payload()
+                    .rsplit_once(':')
+                    .and_then(|(tenant_id, task_id)| Some((tenant_id, task_id.parse().ok()?)));
+                match changed {
+                    Some((tenant_id, task_id)) => cache.invalidate(tenant_id, task_id),
+                    None => cache.clear(),
+                }
+            }
+            // reconnected, whatever was notified meanwhile is gone
+            None => cache.clear(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_cache(ttl: Duration) -> TaskCache {
+        TaskCache::new(&TaskCacheConfig {
+            capacity: 2,
+            ttl,
+            listen: false,
+        })
+    }
+
+    fn task(task_id: i32) -> TaskRow {
+        TaskRow {
+            task_id,
+            name: format!("task {task_id}"),
+            priority: None,
+            due_at: None,
+            parent_id: None,
+            done: false,
+        }
+    }
+
+    #[test]
+    fn test_hits_misses_and_invalidation() {
+        let cache = new_cache(Duration::from_secs(60));
+        let team_a = Tenant::new("team-a").unwrap();
+        let team_b = Tenant::new("team-b").unwrap();
+
+        assert!(cache.task(&team_a, 1).is_none());
+        cache.put_task(&team_a, 1, Some(task(1)), cache.generation());
+        cache.put_tasks(&team_a, vec![task(1)], cache.generation());
+        assert_eq!(cache.task(&team_a, 1).unwrap().value.unwrap().task_id, 1);
+        assert_eq!(cache.tasks(&team_a).unwrap().value.len(), 1);
+        // entries are per tenant
+        assert!(cache.task(&team_b, 1).is_none());
+
+        cache.invalidate("team-a", 1);
+        assert!(cache.task(&team_a, 1).is_none());
+        assert!(cache.tasks(&team_a).is_none());
+        assert_eq!(
+            cache.stats(),
+            CacheStats {
+                hits: 2,
+                misses: 4,
+                entries: 0
+            }
+        );
+    }
+
+    #[test]
+    fn test_load_started_before_a_write_is_not_stored() {
+        let cache = new_cache(Duration::from_secs(60));
+        let team_a = Tenant::new("team-a").unwrap();
+
+        let generation = cache.generation();
+        cache.invalidate("team-a", 1);
+        cache.put_task(&team_a, 1, Some(task(1)), generation);
+        assert!(cache.task(&team_a, 1).is_none());
+    }
+
+    #[test]
+    fn test_expired_and_evicted_entries() {
+        let cache = new_cache(Duration::ZERO);
+        let team_a = Tenant::new("team-a").unwrap();
+        cache.put_task(&team_a, 1, None, cache.generation());
+        assert!(cache.task(&team_a, 1).is_none());
+
+        let cache = new_cache(Duration::from_secs(60));
+        for task_id in 1..=3 {
+            cache.put_task(&team_a, task_id, None, cache.generation());
+        }
+        // capacity 2, the least recently used one is gone
+        assert!(cache.task(&team_a, 1).is_none());
+        assert!(cache.task(&team_a, 3).is_some());
+    }
+}
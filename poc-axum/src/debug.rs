@@ -0,0 +1,296 @@
+use crate::api_error;
+use crate::tls::TlsListener;
+use axum::body::{Body, Bytes};
+use axum::extract::connect_info::Connected;
+use axum::extract::{ConnectInfo, FromRequestParts, Path, Query, Request};
+use axum::http::{HeaderMap, StatusCode, header::CONTENT_TYPE};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{any, get};
+use axum::serve::IncomingStream;
+use axum::{Json, Router};
+use serde::Deserialize;
+use serde_json::{Value, json};
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio_stream::wrappers::ReceiverStream;
+
+// endpoints to test clients and proxies against, they do not touch the database.
+// off unless DEBUG_ROUTES=true, echo shows every header including Authorization
+
+const MAX_ECHO_BODY: usize = 1024 * 1024;
+const MAX_DELAY: Duration = Duration::from_secs(30);
+const MAX_STREAM_LINES: u32 = 1000;
+
+/// The address of the client, as [`ConnectInfo`] when served with
+/// `into_make_service_with_connect_info::<ClientAddr>()`, over plain TCP or TLS.
+#[derive(Clone, Copy, Debug)]
+pub struct ClientAddr(pub SocketAddr);
+
+impl Connected<IncomingStream<'_, TcpListener>> for ClientAddr {
+    fn connect_info(stream: IncomingStream<'_, TcpListener>) -> Self {
+        ClientAddr(*stream.remote_addr())
+    }
+}
+
+impl Connected<IncomingStream<'_, TlsListener>> for ClientAddr {
+    fn connect_info(stream: IncomingStream<'_, TlsListener>) -> Self {
+        ClientAddr(*stream.remote_addr())
+    }
+}
+
+/// Reads `DEBUG_ROUTES`.
+pub fn enabled_from_env() -> bool {
+    std::env::var("DEBUG_ROUTES").is_ok_and(|enabled| enabled == "true")
+}
+
+pub fn create_debug_router<S: Clone + Send + Sync + 'static>() -> Router<S> {
+    Router::new()
+        .route("/debug/echo", any(echo))
+        .route("/debug/delay/{ms}", any(delay))
+        .route("/debug/status/{code}", any(status))
+        .route("/debug/stream/{n}", get(stream))
+}
+
+/// The request as JSON: method, path, query, headers, body and the address of the client.
+async fn echo(request: Request) -> Result<Json<Value>, (StatusCode, String)> {
+    let (mut parts, body) = request.into_parts();
+    // only there when served with connect info, as main and tls do
+    let client_addr = ConnectInfo::<ClientAddr>::from_request_parts(&mut parts, &())
+        .await
+        .ok()
+        .map(|ConnectInfo(ClientAddr(addr))| addr.to_string());
+    let query = Query::<Vec<(String, String)>>::try_from_uri(&parts.uri)
+        .map_err(|e| api_error(StatusCode::BAD_REQUEST, &e.body_text()))?
+        .0;
+    let body = axum::body::to_bytes(body, MAX_ECHO_BODY)
+        .await
+        .map_err(|_| api_error(StatusCode::PAYLOAD_TOO_LARGE, "Body too large to echo"))?;
+
+    Ok(Json(json!({
+        "method": parts.method.as_str(),
+        "path": parts.uri.path(),
+        "version": format!("{:?}", parts.version),
+        "query": group(query),
+        "headers": group(headers(&parts.headers)),
+        "body": body_json(&body),
+        "client_addr": client_addr,
+    })))
+}
+
+// repeated names keep every value, in order
+fn group(pairs: Vec<(String, String)>) -> BTreeMap<String, Vec<String>> {
+    let mut grouped: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for (name, value) in pairs {
+        grouped.entry(name).or_default().push(value);
+    }
+    grouped
+}
+
+fn headers(headers: &HeaderMap) -> Vec<(String, String)> {
+    headers
+        .iter()
+        .map(|(name, value)| {
+            let value = String::from_utf8_lossy(value.as_bytes()).into_owned();
+            (name.as_str().to_owned(), value)
+        })
+        .collect()
+}
+
+// text as is, anything else as hex
+fn body_json(body: &Bytes) -> Value {
+    match std::str::from_utf8(body) {
+        Ok(text) => json!({ "text": text, "len": body.len() }),
+        Err(_) => json!({ "hex": hex::encode(body), "len": body.len() }),
+    }
+}
+
+/// Answers after `ms` milliseconds, to test client timeouts.
+async fn delay(Path(ms): Path<u64>) -> Result<Json<Value>, (StatusCode, String)> {
+    let delay = Duration::from_millis(ms);
+    if delay > MAX_DELAY {
+        return Err(api_error(
+            StatusCode::BAD_REQUEST,
+            &format!("Delay must be at most {} ms", MAX_DELAY.as_millis()),
+        ));
+    }
+    tokio::time::sleep(delay).await;
+
+    Ok(Json(json!({ "delayed_ms": ms })))
+}
+
+/// Answers with the given status, to test retries and error handling.
+async fn status(Path(code): Path<u16>) -> Result<Response, (StatusCode, String)> {
+    let status = StatusCode::from_u16(code)
+        .ok()
+        .filter(|status| (200..600).contains(&status.as_u16()))
+        .ok_or_else(|| {
+            api_error(
+                StatusCode::BAD_REQUEST,
+                "Status must be between 200 and 599",
+            )
+        })?;
+    // these must not have a body
+    if status == StatusCode::NO_CONTENT || status == StatusCode::NOT_MODIFIED {
+        return Ok(status.into_response());
+    }
+
+    Ok((status, Json(json!({ "status": code }))).into_response())
+}
+
+#[derive(Deserialize)]
+struct StreamParams {
+    #[serde(default)]
+    interval_ms: u64,
+}
+
+/// `n` JSON lines sent as separate chunks, `?interval_ms=` apart.
+async fn stream(
+    Path(n): Path<u32>,
+    Query(params): Query<StreamParams>,
+) -> Result<Response, (StatusCode, String)> {
+    let interval = Duration::from_millis(params.interval_ms);
+    if n > MAX_STREAM_LINES || interval > MAX_DELAY {
+        return Err(api_error(
+            StatusCode::BAD_REQUEST,
+            &format!(
+                "At most {MAX_STREAM_LINES} lines, at most {} ms apart",
+                MAX_DELAY.as_millis()
+            ),
+        ));
+    }
+
+    let (sender, receiver) = tokio::sync::mpsc::channel::<Result<Bytes, Infallible>>(1);
+    tokio::spawn(async move {
+        for id in 0..n {
+            if id > 0 {
+                tokio::time::sleep(interval).await;
+            }
+            let line = format!("{}\n", json!({ "id": id, "of": n }));
+            // the client went away
+            if sender.send(Ok(Bytes::from(line))).await.is_err() {
+                return;
+            }
+        }
+    });
+
+    Ok((
+        [(CONTENT_TYPE, "application/x-ndjson")],
+        Body::from_stream(ReceiverStream::new(receiver)),
+    )
+        .into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::extract::connect_info::MockConnectInfo;
+    use axum::http::Method;
+    use http_body_util::BodyExt;
+    use tower::ServiceExt;
+
+    fn app() -> Router {
+        create_debug_router()
+    }
+
+    async fn send(app: Router, request: axum::http::request::Builder, body: Body) -> Response {
+        app.oneshot(request.body(body).unwrap()).await.unwrap()
+    }
+
+    async fn json_body(response: Response) -> Value {
+        let body = response.collect().await.unwrap().to_bytes();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_echo() {
+        let app = app().layer(MockConnectInfo(ClientAddr(SocketAddr::from((
+            [10, 0, 0, 7],
+            4242,
+        )))));
+        let request = Request::builder()
+            .method(Method::PUT)
+            .uri("/debug/echo?tag=a&tag=b&q=x%20y")
+            .header("x-trace", "1")
+            .header("x-trace", "2");
+        let response = send(app, request, Body::from("hello")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let json = json_body(response).await;
+        assert_eq!(json["method"], "PUT");
+        assert_eq!(json["path"], "/debug/echo");
+        assert_eq!(json["query"], json!({ "q": ["x y"], "tag": ["a", "b"] }));
+        assert_eq!(json["headers"]["x-trace"], json!(["1", "2"]));
+        assert_eq!(json["body"], json!({ "text": "hello", "len": 5 }));
+        assert_eq!(json["client_addr"], "10.0.0.7:4242");
+
+        let response = send(
+            self::app(),
+            Request::builder().method(Method::POST).uri("/debug/echo"),
+            Body::from(vec![0xff, 0x00]),
+        )
+        .await;
+        let json = json_body(response).await;
+        assert_eq!(json["body"], json!({ "hex": "ff00", "len": 2 }));
+        assert_eq!(json["client_addr"], Value::Null);
+    }
+
+    #[tokio::test]
+    async fn test_status_and_delay() {
+        let response = send(
+            app(),
+            Request::builder().uri("/debug/status/418"),
+            Body::empty(),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::IM_A_TEAPOT);
+        assert_eq!(json_body(response).await, json!({ "status": 418 }));
+        let request = Request::builder()
+            .method(Method::DELETE)
+            .uri("/debug/status/204");
+        let response = send(app(), request, Body::empty()).await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let response = send(
+            app(),
+            Request::builder().uri("/debug/status/99"),
+            Body::empty(),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let response = send(
+            app(),
+            Request::builder().uri("/debug/delay/20"),
+            Body::empty(),
+        )
+        .await;
+        assert_eq!(json_body(response).await, json!({ "delayed_ms": 20 }));
+        let response = send(
+            app(),
+            Request::builder().uri("/debug/delay/3600000"),
+            Body::empty(),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_stream() {
+        let request = Request::builder().uri("/debug/stream/3?interval_ms=5");
+        let response = send(app(), request, Body::empty()).await;
+        assert_eq!(response.headers()[CONTENT_TYPE], "application/x-ndjson");
+
+        let mut body = response.into_body();
+        let mut lines = Vec::new();
+        while let Some(frame) = body.frame().await {
+            let chunk = frame.unwrap().into_data().unwrap();
+            lines.push(serde_json::from_slice::<Value>(&chunk).unwrap());
+        }
+        assert_eq!(
+            lines,
+            [0, 1, 2].map(|id| json!({ "id": id, "of": 3 })).to_vec()
+        );
+    }
+}
@@ -0,0 +1,42 @@
+use crate::tasks::TaskRow;
+use crate::tenant::Tenant;
+use tokio::sync::broadcast;
+
+// how many events a slow subscriber can fall behind before it starts missing them
+const CHANNEL_CAPACITY: usize = 256;
+
+#[derive(Clone, Debug)]
+pub enum TaskEvent {
+    Created(TaskRow),
+    Updated(TaskRow),
+    Deleted(i32),
+}
+
+/// In-process fan-out of task changes, written by every handler that mutates tasks.
+/// Each event is tagged with its tenant, subscribers only forward the ones of their own.
+#[derive(Clone)]
+pub struct TaskEvents {
+    sender: broadcast::Sender<(Tenant, TaskEvent)>,
+}
+
+impl TaskEvents {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(CHANNEL_CAPACITY);
+        TaskEvents { sender }
+    }
+
+    pub fn publish(&self, tenant: &Tenant, event: TaskEvent) {
+        // an error only means nobody is subscribed right now
+        let _ = self.sender.send((tenant.clone(), event));
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<(Tenant, TaskEvent)> {
+        self.sender.subscribe()
+    }
+}
+
+impl Default for TaskEvents {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,467 @@
+use crate::cache::TaskCache;
+use crate::events::{TaskEvent, TaskEvents};
+use crate::tasks::{self, CreateTaskReq, TaskFilter, TaskRow, UpdateTaskReq};
+use crate::tenant::{Tenant, TenantResolver};
+use async_graphql::connection::{Connection, Edge};
+use async_graphql::http::GraphiQLSource;
+use async_graphql::{
+    Context, EmptySubscription, InputObject, MaybeUndefined, Object, Result, Schema,
+};
+use axum::extract::{FromRef, State};
+use axum::response::{Html, IntoResponse};
+use axum::{Json, Router, routing::get};
+use chrono::{DateTime, Utc};
+use sqlx::PgPool;
+
+pub type TaskSchema = Schema<QueryRoot, MutationRoot, EmptySubscription>;
+
+// a query nested deeper or costlier than this is rejected before touching the database
+const MAX_DEPTH: usize = 8;
+const MAX_COMPLEXITY: usize = 200;
+const MAX_PAGE_SIZE: usize = 100;
+// without `first`, small enough that a page of a few fields stays within MAX_COMPLEXITY
+const DEFAULT_PAGE_SIZE: usize = 20;
+
+pub fn create_schema(
+    db_pool: PgPool,
+    task_events: TaskEvents,
+    task_cache: TaskCache,
+) -> TaskSchema {
+    Schema::build(QueryRoot, MutationRoot, EmptySubscription)
+        .data(db_pool)
+        .data(task_events)
+        .data(task_cache)
+        .limit_depth(MAX_DEPTH)
+        .limit_complexity(MAX_COMPLEXITY)
+        .finish()
+}
+
+#[derive(Clone)]
+struct GraphqlState {
+    schema: TaskSchema,
+    tenant_resolver: TenantResolver,
+}
+
+impl FromRef<GraphqlState> for TaskSchema {
+    fn from_ref(state: &GraphqlState) -> Self {
+        state.schema.clone()
+    }
+}
+
+impl FromRef<GraphqlState> for TenantResolver {
+    fn from_ref(state: &GraphqlState) -> Self {
+        state.tenant_resolver.clone()
+    }
+}
+
+/// `GET /graphql` serves GraphiQL, `POST /graphql` runs a single query or a batch of them.
+pub fn create_graphql_router(schema: TaskSchema, tenant_resolver: TenantResolver) -> Router {
+    Router::new()
+        .route("/graphql", get(graphiql).post(graphql_handler))
+        .with_state(GraphqlState {
+            schema,
+            tenant_resolver,
+        })
+}
+
+async fn graphiql() -> impl IntoResponse {
+    Html(GraphiQLSource::build().endpoint("/graphql").finish())
+}
+
+async fn graphql_handler(
+    State(schema): State<TaskSchema>,
+    tenant: Tenant,
+    Json(request): Json<async_graphql::BatchRequest>,
+) -> Json<async_graphql::BatchResponse> {
+    Json(schema.execute_batch(request.data(tenant)).await)
+}
+
+// TaskRow lives in the tasks-client crate, the GraphQL type is implemented on this wrapper
+struct Task(TaskRow);
+
+#[Object]
+impl Task {
+    async fn id(&self) -> i32 {
+        self.0.task_id
+    }
+
+    async fn name(&self) -> &str {
+        &self.0.name
+    }
+
+    async fn priority(&self) -> Option<i32> {
+        self.0.priority
+    }
+
+    async fn due_at(&self) -> Option<DateTime<Utc>> {
+        self.0.due_at
+    }
+
+    async fn parent_id(&self) -> Option<i32> {
+        self.0.parent_id
+    }
+
+    async fn done(&self) -> bool {
+        self.0.done
+    }
+}
+
+#[derive(InputObject)]
+struct TaskFilterInput {
+    name_contains: Option<String>,
+    min_priority: Option<i32>,
+    max_priority: Option<i32>,
+}
+
+#[derive(InputObject)]
+struct CreateTaskInput {
+    name: String,
+    priority: Option<i32>,
+    due_at: Option<DateTime<Utc>>,
+    parent_id: Option<i32>,
+}
+
+#[derive(InputObject)]
+struct UpdateTaskInput {
+    name: Option<String>,
+    priority: Option<i32>,
+    due_at: Option<DateTime<Utc>>,
+    /// null makes it a top level task again.
+    parent_id: MaybeUndefined<i32>,
+    done: Option<bool>,
+}
+
+pub struct QueryRoot;
+
+#[Object]
+impl QueryRoot {
+    /// Tasks ordered by id, paginated with the cursor of the last edge seen. 20 per page unless
+    /// `first` says otherwise.
+    #[graphql(
+        complexity = "first.unwrap_or(DEFAULT_PAGE_SIZE as i32).max(0) as usize * child_complexity"
+    )]
+    async fn tasks(
+        &self,
+        ctx: &Context<'_>,
+        filter: Option<TaskFilterInput>,
+        first: Option<i32>,
+        after: Option<String>,
+    ) -> Result<Connection<i32, Task>> {
+        let first = match first {
+            Some(first) if first < 0 => return Err("first must not be negative".into()),
+            Some(first) => (first as usize).min(MAX_PAGE_SIZE),
+            None => DEFAULT_PAGE_SIZE,
+        };
+        let after = after
+            .map(|cursor| cursor.parse::<i32>())
+            .transpose()
+            .map_err(|_| "invalid cursor")?;
+        let filter = filter.map_or_else(TaskFilter::default, |f| TaskFilter {
+            name_contains: f.name_contains,
+            min_priority: f.min_priority,
+            max_priority: f.max_priority,
+        });
+
+        // ask for one more row to know if there is a next page
+        let mut rows = tasks::list_tasks(
+            pool(ctx),
+            tenant(ctx),
+            &filter,
+            after,
+            Some(first as i64 + 1),
+        )
+        .await?;
+        let has_next_page = rows.len() > first;
+        rows.truncate(first);
+
+        let mut connection = Connection::new(after.is_some(), has_next_page);
+        connection.edges.extend(
+            rows.into_iter()
+                .map(|row| Edge::new(row.task_id, Task(row))),
+        );
+        Ok(connection)
+    }
+
+    async fn task(&self, ctx: &Context<'_>, id: i32) -> Result<Option<Task>> {
+        Ok(tasks::find_task(pool(ctx), tenant(ctx), id)
+            .await?
+            .map(Task))
+    }
+}
+
+pub struct MutationRoot;
+
+#[Object]
+impl MutationRoot {
+    async fn create_task(&self, ctx: &Context<'_>, input: CreateTaskInput) -> Result<Task> {
+        let task = CreateTaskReq {
+            name: input.name,
+            priority: input.priority,
+            due_at: input.due_at,
+            parent_id: input.parent_id,
+        };
+        let row = tasks::insert_task(pool(ctx), tenant(ctx), &task).await?;
+        cache(ctx).invalidate(tenant(ctx).id(), row.task_id);
+        events(ctx).publish(tenant(ctx), TaskEvent::Created(row.clone()));
+        Ok(Task(row))
+    }
+
+    /// Returns null when the task does not exist.
+    async fn update_task(
+        &self,
+        ctx: &Context<'_>,
+        id: i32,
+        input: UpdateTaskInput,
+    ) -> Result<Option<Task>> {
+        let task = UpdateTaskReq {
+            name: input.name,
+            priority: input.priority,
+            due_at: input.due_at,
+            parent_id: match input.parent_id {
+                MaybeUndefined::Undefined => None,
+                MaybeUndefined::Null => Some(None),
+                MaybeUndefined::Value(parent_id) => Some(Some(parent_id)),
+            },
+            done: input.done,
+        };
+        let row = tasks::update_task(pool(ctx), tenant(ctx), id, &task).await?;
+        if let Some(row) = &row {
+            cache(ctx).invalidate(tenant(ctx).id(), id);
+            events(ctx).publish(tenant(ctx), TaskEvent::Updated(row.clone()));
+        }
+        Ok(row.map(Task))
+    }
+
+    /// Returns false when the task does not exist.
+    async fn delete_task(&self, ctx: &Context<'_>, id: i32) -> Result<bool> {
+        let deleted = tasks::delete_task(pool(ctx), tenant(ctx), id).await?;
+        if deleted {
+            cache(ctx).invalidate(tenant(ctx).id(), id);
+            events(ctx).publish(tenant(ctx), TaskEvent::Deleted(id));
+        }
+        Ok(deleted)
+    }
+}
+
+// registered in create_schema, so they are always there
+fn pool<'a>(ctx: &Context<'a>) -> &'a PgPool {
+    ctx.data_unchecked::<PgPool>()
+}
+
+fn events<'a>(ctx: &Context<'a>) -> &'a TaskEvents {
+    ctx.data_unchecked::<TaskEvents>()
+}
+
+fn cache<'a>(ctx: &Context<'a>) -> &'a TaskCache {
+    ctx.data_unchecked::<TaskCache>()
+}
+
+// added to every request by graphql_handler
+fn tenant<'a>(ctx: &Context<'a>) -> &'a Tenant {
+    ctx.data_unchecked::<Tenant>()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tenant::TENANT_HEADER;
+    use axum::body::Body;
+    use axum::http::{Request, StatusCode};
+    use http_body_util::BodyExt;
+    use serde_json::{Value, json};
+    use sqlx::postgres::PgPoolOptions;
+    use tower::ServiceExt;
+
+    fn test_cache() -> TaskCache {
+        TaskCache::new(&crate::cache::TaskCacheConfig {
+            capacity: 1,
+            ttl: std::time::Duration::ZERO,
+            listen: false,
+        })
+    }
+
+    async fn post_graphql(query: &str) -> Value {
+        // limits are checked before any resolver runs, so the pool never connects
+        let db_pool = PgPoolOptions::new()
+            .connect_lazy("postgres://localhost/unused")
+            .unwrap();
+        post_graphql_to(db_pool, query).await
+    }
+
+    async fn post_graphql_to(db_pool: PgPool, query: &str) -> Value {
+        let app = create_graphql_router(
+            create_schema(db_pool, TaskEvents::new(), test_cache()),
+            TenantResolver::new(None),
+        );
+        let request = Request::builder()
+            .method("POST")
+            .uri("/graphql")
+            .header("content-type", "application/json")
+            .header(TENANT_HEADER, "team-a")
+            .body(Body::from(json!({ "query": query }).to_string()))
+            .unwrap();
+
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = response.collect().await.unwrap();
+        serde_json::from_slice(&body.to_bytes()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_rejects_too_complex_query() {
+        let json =
+            post_graphql("{ tasks(first: 100) { edges { node { id name priority } } } }").await;
+        assert_eq!(json["errors"][0]["message"], "Query is too complex.");
+    }
+
+    // the data of a query that has to succeed
+    async fn query_data(db_pool: &PgPool, query: &str) -> Value {
+        let json = post_graphql_to(db_pool.clone(), query).await;
+        assert_eq!(json.get("errors"), None, "{query}");
+        json["data"].clone()
+    }
+
+    #[sqlx::test]
+    async fn test_tasks_default_page_and_cursors(db_pool: PgPool) {
+        let tenant = Tenant::new("team-a").unwrap();
+        for n in 1..=DEFAULT_PAGE_SIZE + 5 {
+            let task = CreateTaskReq {
+                name: format!("task {n}"),
+                priority: None,
+                due_at: None,
+                parent_id: None,
+            };
+            tasks::insert_task(&db_pool, &tenant, &task).await.unwrap();
+        }
+
+        let data = query_data(&db_pool, "{ tasks { edges { node { id } } } }").await;
+        let edges = data["tasks"]["edges"].as_array().unwrap();
+        assert_eq!(edges.len(), DEFAULT_PAGE_SIZE);
+
+        let page = "edges { node { name } } pageInfo { hasNextPage endCursor }";
+        let data = query_data(&db_pool, &format!("{{ tasks(first: 20) {{ {page} }} }}")).await;
+        assert_eq!(data["tasks"]["edges"][19]["node"]["name"], "task 20");
+        assert_eq!(data["tasks"]["pageInfo"]["hasNextPage"], true);
+        let cursor = &data["tasks"]["pageInfo"]["endCursor"];
+        let data = query_data(
+            &db_pool,
+            &format!("{{ tasks(first: 20, after: {cursor}) {{ {page} }} }}"),
+        )
+        .await;
+        let edges = data["tasks"]["edges"].as_array().unwrap();
+        assert_eq!(edges.len(), 5);
+        assert_eq!(edges[0]["node"]["name"], "task 21");
+        assert_eq!(data["tasks"]["pageInfo"]["hasNextPage"], false);
+    }
+
+    #[sqlx::test]
+    async fn test_name_filter_has_no_wildcards(db_pool: PgPool) {
+        let tenant = Tenant::new("team-a").unwrap();
+        for name in ["a_b", "axb", "50%", "50 items", r"back\slash"] {
+            let task = CreateTaskReq {
+                name: name.to_owned(),
+                priority: None,
+                due_at: None,
+                parent_id: None,
+            };
+            tasks::insert_task(&db_pool, &tenant, &task).await.unwrap();
+        }
+
+        for (contains, expected) in [("_", "a_b"), ("50%", "50%"), (r"\\", r"back\slash")] {
+            let data = query_data(
+                &db_pool,
+                &format!(r#"{{ tasks(filter: {{ nameContains: "{contains}" }}) {{ edges {{ node {{ name }} }} }} }}"#),
+            )
+            .await;
+            assert_eq!(
+                data["tasks"]["edges"],
+                json!([{ "node": { "name": expected } }]),
+                "{contains}"
+            );
+        }
+    }
+
+    #[sqlx::test]
+    async fn test_task_mutations(db_pool: PgPool) {
+        let data = query_data(
+            &db_pool,
+            r#"mutation { createTask(input: { name: "parent" }) { id } }"#,
+        )
+        .await;
+        let parent_id = &data["createTask"]["id"];
+        let data = query_data(
+            &db_pool,
+            &format!(
+                r#"mutation {{ createTask(input: {{ name: "child", priority: 2, parentId: {parent_id} }}) {{ id parentId }} }}"#
+            ),
+        )
+        .await;
+        assert_eq!(&data["createTask"]["parentId"], parent_id);
+        let id = &data["createTask"]["id"];
+
+        let data = query_data(
+            &db_pool,
+            &format!("{{ task(id: {id}) {{ name priority done }} }}"),
+        )
+        .await;
+        assert_eq!(
+            data["task"],
+            json!({ "name": "child", "priority": 2, "done": false })
+        );
+
+        // null moves it to the top level, the fields left out stay as they are
+        let data = query_data(
+            &db_pool,
+            &format!(
+                "mutation {{ updateTask(id: {id}, input: {{ parentId: null, done: true }}) {{ name parentId done }} }}"
+            ),
+        )
+        .await;
+        assert_eq!(
+            data["updateTask"],
+            json!({ "name": "child", "parentId": null, "done": true })
+        );
+        let data = query_data(
+            &db_pool,
+            "mutation { updateTask(id: 999999, input: { done: true }) { id } }",
+        )
+        .await;
+        assert_eq!(data["updateTask"], Value::Null);
+
+        let delete = format!("mutation {{ deleteTask(id: {id}) }}");
+        assert_eq!(query_data(&db_pool, &delete).await["deleteTask"], true);
+        assert_eq!(query_data(&db_pool, &delete).await["deleteTask"], false);
+        let data = query_data(&db_pool, &format!("{{ task(id: {id}) {{ id }} }}")).await;
+        assert_eq!(data["task"], Value::Null);
+    }
+
+    #[tokio::test]
+    async fn test_rejects_too_deep_query() {
+        let json = post_graphql(
+            "{ __schema { types { fields { type { ofType { ofType { ofType { ofType { name } } } } } } } } }",
+        )
+        .await;
+        assert_eq!(json["errors"][0]["message"], "Query is nested too deep.");
+    }
+
+    #[tokio::test]
+    async fn test_graphiql_page() {
+        let db_pool = PgPoolOptions::new()
+            .connect_lazy("postgres://localhost/unused")
+            .unwrap();
+        let app = create_graphql_router(
+            create_schema(db_pool, TaskEvents::new(), test_cache()),
+            TenantResolver::new(None),
+        );
+        let request = Request::builder()
+            .uri("/graphql")
+            .body(Body::empty())
+            .unwrap();
+
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.collect().await.unwrap().to_bytes();
+        assert!(String::from_utf8_lossy(&body).contains("graphiql"));
+    }
+}
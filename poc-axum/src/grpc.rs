@@ -0,0 +1,425 @@
+use crate::AppState;
+use crate::cache::TaskCache;
+use crate::events::{TaskEvent, TaskEvents};
+use crate::tasks::{self, CreateTaskReq, TaskError, TaskFilter, TaskRow, UpdateTaskReq};
+use crate::tenant::{Tenant, TenantError, TenantResolver};
+use axum::extract::{Request, State};
+use axum::middleware::{self, Next};
+use axum::response::Response;
+use axum::{Router, http::header::CONTENT_TYPE};
+use chrono::{DateTime, Utc};
+use proto::task_service_server::{TaskService, TaskServiceServer};
+use sqlx::PgPool;
+use std::pin::Pin;
+use tokio_stream::wrappers::BroadcastStream;
+use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
+use tokio_stream::{Stream, StreamExt};
+use tonic::{Status, service::Routes};
+use tower::ServiceExt;
+
+pub mod proto {
+    tonic::include_proto!("tasks.v1");
+}
+
+const DEFAULT_PAGE_SIZE: i32 = 50;
+const MAX_PAGE_SIZE: i32 = 500;
+
+/// Sends requests with a gRPC content type to the tonic service and the rest to `rest`,
+/// so both are served on the same port.
+pub fn multiplex(rest: Router, state: AppState) -> Router {
+    let grpc = Routes::new(TaskServiceServer::new(TaskGrpcService {
+        db_pool: state.db_pool,
+        task_events: state.task_events,
+        tenant_resolver: state.tenant_resolver,
+        task_cache: state.task_cache,
+    }))
+    .into_axum_router();
+
+    rest.layer(middleware::from_fn_with_state(grpc, route_grpc))
+}
+
+async fn route_grpc(State(grpc): State<Router>, request: Request, next: Next) -> Response {
+    let is_grpc = request
+        .headers()
+        .get(CONTENT_TYPE)
+        .is_some_and(|content_type| content_type.as_bytes().starts_with(b"application/grpc"));
+    if !is_grpc {
+        return next.run(request).await;
+    }
+
+    match grpc.oneshot(request).await {
+        Ok(response) => response,
+        Err(infallible) => match infallible {},
+    }
+}
+
+impl From<TaskRow> for proto::Task {
+    fn from(row: TaskRow) -> Self {
+        proto::Task {
+            id: row.task_id,
+            name: row.name,
+            priority: row.priority,
+            due_at: row.due_at.map(|due_at| due_at.to_rfc3339()),
+            parent_id: row.parent_id,
+            done: row.done,
+        }
+    }
+}
+
+impl From<TaskEvent> for proto::TaskEvent {
+    fn from(event: TaskEvent) -> Self {
+        use proto::task_event::Kind;
+
+        let (kind, task_id, task) = match event {
+            TaskEvent::Created(row) => (Kind::Created, row.task_id, Some(row.into())),
+            TaskEvent::Updated(row) => (Kind::Updated, row.task_id, Some(row.into())),
+            TaskEvent::Deleted(task_id) => (Kind::Deleted, task_id, None),
+        };
+        proto::TaskEvent {
+            kind: kind.into(),
+            task_id,
+            task,
+        }
+    }
+}
+
+fn internal(e: sqlx::Error) -> Status {
+    Status::internal(e.to_string())
+}
+
+fn parse_due_at(due_at: Option<String>) -> Result<Option<DateTime<Utc>>, Status> {
+    due_at
+        .map(|due_at| DateTime::parse_from_rfc3339(&due_at).map(|due_at| due_at.to_utc()))
+        .transpose()
+        .map_err(|_| Status::invalid_argument("due_at must be an RFC 3339 timestamp"))
+}
+
+// the tenant comes from the request metadata, the same x-tenant-id or authorization headers as REST
+fn tenant<T>(
+    tenant_resolver: &TenantResolver,
+    request: &tonic::Request<T>,
+) -> Result<Tenant, Status> {
+    tenant_resolver
+        .resolve(&request.metadata().clone().into_headers())
+        .map_err(|e| match e {
+            TenantError::InvalidToken => Status::unauthenticated(e.message()),
+            TenantError::Missing | TenantError::Invalid => Status::invalid_argument(e.message()),
+        })
+}
+
+fn task_error(e: TaskError) -> Status {
+    match e {
+        TaskError::ParentNotFound => Status::invalid_argument(e.to_string()),
+        TaskError::ParentCycle | TaskError::HasSubtasks => {
+            Status::failed_precondition(e.to_string())
+        }
+        TaskError::Database(e) => internal(e),
+    }
+}
+
+fn not_found(task_id: i32) -> Status {
+    Status::not_found(format!("task {task_id} not found"))
+}
+
+struct TaskGrpcService {
+    db_pool: PgPool,
+    task_events: TaskEvents,
+    tenant_resolver: TenantResolver,
+    task_cache: TaskCache,
+}
+
+#[tonic::async_trait]
+impl TaskService for TaskGrpcService {
+    async fn list(
+        &self,
+        request: tonic::Request<proto::ListTasksRequest>,
+    ) -> Result<tonic::Response<proto::ListTasksResponse>, Status> {
+        let tenant = tenant(&self.tenant_resolver, &request)?;
+        let request = request.into_inner();
+        let page_size = match request.page_size {
+            0 => DEFAULT_PAGE_SIZE,
+            size if size < 0 => {
+                return Err(Status::invalid_argument("page_size must not be negative"));
+            }
+            size => size.min(MAX_PAGE_SIZE),
+        };
+        let after = match request.page_token.as_str() {
+            "" => None,
+            token => Some(
+                token
+                    .parse::<i32>()
+                    .map_err(|_| Status::invalid_argument("invalid page_token"))?,
+            ),
+        };
+        let filter = TaskFilter {
+            name_contains: request.name_contains,
+            min_priority: request.min_priority,
+            max_priority: request.max_priority,
+        };
+
+        // ask for one more row to know if there is a next page
+        let mut rows = tasks::list_tasks(
+            &self.db_pool,
+            &tenant,
+            &filter,
+            after,
+            Some(page_size as i64 + 1),
+        )
+        .await
+        .map_err(internal)?;
+        let next_page_token = if rows.len() > page_size as usize {
+            rows.truncate(page_size as usize);
+            rows.last()
+                .map(|row| row.task_id.to_string())
+                .unwrap_or_default()
+        } else {
+            String::new()
+        };
+
+        Ok(tonic::Response::new(proto::ListTasksResponse {
+            tasks: rows.into_iter().map(Into::into).collect(),
+            next_page_token,
+        }))
+    }
+
+    async fn get(
+        &self,
+        request: tonic::Request<proto::GetTaskRequest>,
+    ) -> Result<tonic::Response<proto::Task>, Status> {
+        let tenant = tenant(&self.tenant_resolver, &request)?;
+        let task_id = request.into_inner().id;
+        let row = tasks::find_task(&self.db_pool, &tenant, task_id)
+            .await
+            .map_err(internal)?
+            .ok_or_else(|| not_found(task_id))?;
+
+        Ok(tonic::Response::new(row.into()))
+    }
+
+    async fn create(
+        &self,
+        request: tonic::Request<proto::CreateTaskRequest>,
+    ) -> Result<tonic::Response<proto::Task>, Status> {
+        let tenant = tenant(&self.tenant_resolver, &request)?;
+        let request = request.into_inner();
+        let task = CreateTaskReq {
+            name: request.name,
+            priority: request.priority,
+            due_at: parse_due_at(request.due_at)?,
+            parent_id: request.parent_id,
+        };
+        let row = tasks::insert_task(&self.db_pool, &tenant, &task)
+            .await
+            .map_err(task_error)?;
+        self.task_cache.invalidate(tenant.id(), row.task_id);
+        self.task_events
+            .publish(&tenant, TaskEvent::Created(row.clone()));
+
+        Ok(tonic::Response::new(row.into()))
+    }
+
+    async fn update(
+        &self,
+        request: tonic::Request<proto::UpdateTaskRequest>,
+    ) -> Result<tonic::Response<proto::Task>, Status> {
+        let tenant = tenant(&self.tenant_resolver, &request)?;
+        let request = request.into_inner();
+        let task = UpdateTaskReq {
+            name: request.name,
+            priority: request.priority,
+            due_at: parse_due_at(request.due_at)?,
+            parent_id: request
+                .parent_id
+                .map(|parent_id| (parent_id != 0).then_some(parent_id)),
+            done: request.done,
+        };
+        let row = tasks::update_task(&self.db_pool, &tenant, request.id, &task)
+            .await
+            .map_err(task_error)?
+            .ok_or_else(|| not_found(request.id))?;
+        self.task_cache.invalidate(tenant.id(), request.id);
+        self.task_events
+            .publish(&tenant, TaskEvent::Updated(row.clone()));
+
+        Ok(tonic::Response::new(row.into()))
+    }
+
+    async fn delete(
+        &self,
+        request: tonic::Request<proto::DeleteTaskRequest>,
+    ) -> Result<tonic::Response<proto::DeleteTaskResponse>, Status> {
+        let tenant = tenant(&self.tenant_resolver, &request)?;
+        let task_id = request.into_inner().id;
+        let deleted = tasks::delete_task(&self.db_pool, &tenant, task_id)
+            .await
+            .map_err(task_error)?;
+        if !deleted {
+            return Err(not_found(task_id));
+        }
+        self.task_cache.invalidate(tenant.id(), task_id);
+        self.task_events
+            .publish(&tenant, TaskEvent::Deleted(task_id));
+
+        Ok(tonic::Response::new(proto::DeleteTaskResponse {}))
+    }
+
+    type WatchStream = Pin<Box<dyn Stream<Item = Result<proto::TaskEvent, Status>> + Send>>;
+
+    async fn watch(
+        &self,
+        request: tonic::Request<proto::WatchTasksRequest>,
+    ) -> Result<tonic::Response<Self::WatchStream>, Status> {
+        let tenant = tenant(&self.tenant_resolver, &request)?;
+        let events =
+            BroadcastStream::new(self.task_events.subscribe()).filter_map(
+                move |event| match event {
+                    Ok((event_tenant, event)) if event_tenant == tenant => Some(Ok(event.into())),
+                    Ok(_) => None,
+                    Err(BroadcastStreamRecvError::Lagged(missed)) => Some(Err(Status::data_loss(
+                        format!("watcher fell behind and missed {missed} events"),
+                    ))),
+                },
+            );
+
+        Ok(tonic::Response::new(Box::pin(events)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::proto::task_event::Kind;
+    use super::proto::task_service_client::TaskServiceClient;
+    use super::proto::*;
+    use crate::tenant::TENANT_HEADER;
+    use sqlx::PgPool;
+    use tokio::net::TcpListener;
+    use tonic::service::interceptor::InterceptedService;
+    use tonic::transport::Channel;
+    use tonic::{Code, Request, Status};
+
+    type Client = TaskServiceClient<
+        InterceptedService<
+            Channel,
+            Box<dyn FnMut(Request<()>) -> Result<Request<()>, Status> + Send>,
+        >,
+    >;
+
+    async fn connect(address: std::net::SocketAddr, tenant_id: &'static str) -> Client {
+        let channel = Channel::from_shared(format!("http://{address}"))
+            .unwrap()
+            .connect()
+            .await
+            .unwrap();
+        TaskServiceClient::with_interceptor(
+            channel,
+            Box::new(move |mut request: Request<()>| {
+                request
+                    .metadata_mut()
+                    .insert(TENANT_HEADER, tenant_id.parse().unwrap());
+                Ok(request)
+            }),
+        )
+    }
+
+    #[sqlx::test]
+    async fn test_task_service_through_client(db_pool: PgPool) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let app = crate::create_tasks_router(crate::tests::test_state(db_pool));
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let mut client = connect(address, "team-a").await;
+        let mut watch = client
+            .watch(WatchTasksRequest {})
+            .await
+            .unwrap()
+            .into_inner();
+
+        let created = client
+            .create(CreateTaskRequest {
+                name: "write proto".to_owned(),
+                priority: Some(2),
+                due_at: None,
+                parent_id: None,
+            })
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(created.name, "write proto");
+
+        let updated = client
+            .update(UpdateTaskRequest {
+                id: created.id,
+                name: None,
+                priority: Some(5),
+                due_at: None,
+                parent_id: None,
+                done: None,
+            })
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(updated.name, "write proto");
+        assert_eq!(updated.priority, Some(5));
+
+        let fetched = client
+            .get(GetTaskRequest { id: created.id })
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(fetched, updated);
+
+        client
+            .create(CreateTaskRequest {
+                name: "second".to_owned(),
+                priority: None,
+                due_at: None,
+                parent_id: None,
+            })
+            .await
+            .unwrap();
+        let page = client
+            .list(ListTasksRequest {
+                page_size: 1,
+                ..Default::default()
+            })
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(page.tasks, vec![updated]);
+        assert_eq!(page.next_page_token, created.id.to_string());
+
+        // another tenant neither sees the task nor shows up in the watch
+        let mut other = connect(address, "team-b").await;
+        let status = other
+            .get(GetTaskRequest { id: created.id })
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::NotFound);
+        other
+            .create(CreateTaskRequest {
+                name: "not yours".to_owned(),
+                priority: None,
+                due_at: None,
+                parent_id: None,
+            })
+            .await
+            .unwrap();
+
+        client
+            .delete(DeleteTaskRequest { id: created.id })
+            .await
+            .unwrap();
+        let status = client
+            .get(GetTaskRequest { id: created.id })
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::NotFound);
+
+        let kinds = [Kind::Created, Kind::Updated, Kind::Created, Kind::Deleted];
+        for kind in kinds {
+            let event = watch.message().await.unwrap().unwrap();
+            assert_eq!(event.kind(), kind);
+        }
+    }
+}
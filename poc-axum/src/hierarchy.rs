@@ -0,0 +1,466 @@
+use crate::tasks::TaskRow;
+use crate::tenant::{self, Tenant};
+use crate::{AppState, api_error, internal_error, task_not_found};
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::routing::{get, put};
+use axum::{Json, Router};
+use serde::{Deserialize, Serialize};
+use sqlx::{PgPool, Postgres, Transaction};
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+
+// subtasks (tasks.parent_id) and "blocked by" edges (task_dependencies), see migrations/*_task_hierarchy.sql
+
+/// A task with its dependencies and, recursively, its subtasks.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TaskTree {
+    #[serde(flatten)]
+    pub task: TaskRow,
+    pub blocked_by: Vec<i32>,
+    pub subtasks: Vec<TaskTree>,
+}
+
+#[derive(Debug)]
+pub enum DependencyError {
+    TaskNotFound,
+    /// The blocker already waits, directly or not, on the task.
+    Cycle,
+    Database(sqlx::Error),
+}
+
+impl From<sqlx::Error> for DependencyError {
+    fn from(e: sqlx::Error) -> Self {
+        if e.as_database_error()
+            .is_some_and(|e| e.is_foreign_key_violation())
+        {
+            return DependencyError::TaskNotFound;
+        }
+        DependencyError::Database(e)
+    }
+}
+
+pub fn create_hierarchy_router() -> Router<AppState> {
+    Router::new()
+        .route("/tasks/ready", get(get_ready_tasks))
+        .route("/tasks/{task_id}/tree", get(get_task_tree))
+        .route(
+            "/tasks/{task_id}/blocked_by/{blocked_by_id}",
+            put(add_blocker).delete(remove_blocker),
+        )
+}
+
+async fn get_task_tree(
+    State(db_pool): State<PgPool>,
+    tenant: Tenant,
+    Path(task_id): Path<i32>,
+) -> Result<Json<TaskTree>, (StatusCode, String)> {
+    let tree = task_tree(&db_pool, &tenant, task_id)
+        .await
+        .map_err(internal_error)?
+        .ok_or_else(task_not_found)?;
+
+    Ok(Json(tree))
+}
+
+async fn add_blocker(
+    State(db_pool): State<PgPool>,
+    tenant: Tenant,
+    Path((task_id, blocked_by_id)): Path<(i32, i32)>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    insert_dependency(&db_pool, &tenant, task_id, blocked_by_id)
+        .await
+        .map_err(|e| match e {
+            DependencyError::TaskNotFound => task_not_found(),
+            DependencyError::Cycle => api_error(
+                StatusCode::CONFLICT,
+                &format!("Task {blocked_by_id} already depends on task {task_id}"),
+            ),
+            DependencyError::Database(e) => internal_error(e),
+        })?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn remove_blocker(
+    State(db_pool): State<PgPool>,
+    tenant: Tenant,
+    Path((task_id, blocked_by_id)): Path<(i32, i32)>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let deleted = delete_dependency(&db_pool, &tenant, task_id, blocked_by_id)
+        .await
+        .map_err(internal_error)?;
+    if !deleted {
+        return Err(api_error(StatusCode::NOT_FOUND, "Dependency not found"));
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn get_ready_tasks(
+    State(db_pool): State<PgPool>,
+    tenant: Tenant,
+) -> Result<Json<Vec<TaskRow>>, (StatusCode, String)> {
+    let rows = ready_tasks(&db_pool, &tenant)
+        .await
+        .map_err(internal_error)?;
+
+    Ok(Json(rows))
+}
+
+/// Serializes the changes to the hierarchy of a tenant until the transaction ends,
+/// so two concurrent edges can not close a cycle that neither check saw.
+pub async fn lock(tx: &mut Transaction<'_, Postgres>, tenant: &Tenant) -> Result<(), sqlx::Error> {
+    sqlx::query("SELECT pg_advisory_xact_lock(hashtext('task_hierarchy:' || $1))")
+        .bind(tenant.id())
+        .execute(&mut **tx)
+        .await?;
+
+    Ok(())
+}
+
+/// Whether `ancestor_id` is `task_id` itself or one of its parents, grandparents...
+pub async fn is_ancestor(
+    tx: &mut Transaction<'_, Postgres>,
+    ancestor_id: i32,
+    task_id: i32,
+) -> Result<bool, sqlx::Error> {
+    // UNION, not UNION ALL, so it ends even if the data already had a loop
+    sqlx::query_scalar(
+        "WITH RECURSIVE ancestors (task_id) AS (
+             SELECT $2::int
+             UNION
+             SELECT t.parent_id FROM tasks t JOIN ancestors a ON t.task_id = a.task_id
+             WHERE t.parent_id IS NOT NULL
+         )
+         SELECT EXISTS (SELECT 1 FROM ancestors WHERE task_id = $1)",
+    )
+    .bind(ancestor_id)
+    .bind(task_id)
+    .fetch_one(&mut **tx)
+    .await
+}
+
+pub async fn task_tree(
+    pg_pool: &PgPool,
+    tenant: &Tenant,
+    task_id: i32,
+) -> Result<Option<TaskTree>, sqlx::Error> {
+    let mut tx = tenant::begin(pg_pool, tenant).await?;
+    let rows: Vec<TaskRow> = sqlx::query_as(
+        "WITH RECURSIVE tree AS (
+             SELECT task_id, name, priority, due_at, parent_id, done FROM tasks WHERE task_id = $1
+             UNION
+             SELECT t.task_id, t.name, t.priority, t.due_at, t.parent_id, t.done
+             FROM tasks t JOIN tree ON t.parent_id = tree.task_id
+         )
+         SELECT * FROM tree ORDER BY task_id",
+    )
+    .bind(task_id)
+    .fetch_all(&mut *tx)
+    .await?;
+    let task_ids: Vec<i32> = rows.iter().map(|row| row.task_id).collect();
+    let edges: Vec<(i32, i32)> = sqlx::query_as(
+        "SELECT task_id, blocked_by_id FROM task_dependencies
+         WHERE task_id = ANY($1) ORDER BY blocked_by_id",
+    )
+    .bind(&task_ids)
+    .fetch_all(&mut *tx)
+    .await?;
+    tx.commit().await?;
+
+    let mut blocked_by: HashMap<i32, Vec<i32>> = HashMap::new();
+    for (task_id, blocked_by_id) in edges {
+        blocked_by.entry(task_id).or_default().push(blocked_by_id);
+    }
+    let mut subtasks: HashMap<i32, Vec<TaskRow>> = HashMap::new();
+    let mut root = None;
+    for row in rows {
+        match row.parent_id {
+            Some(parent_id) if row.task_id != task_id => {
+                subtasks.entry(parent_id).or_default().push(row)
+            }
+            _ => root = Some(row),
+        }
+    }
+
+    Ok(root.map(|root| build_tree(root, &mut subtasks, &mut blocked_by)))
+}
+
+fn build_tree(
+    task: TaskRow,
+    subtasks: &mut HashMap<i32, Vec<TaskRow>>,
+    blocked_by: &mut HashMap<i32, Vec<i32>>,
+) -> TaskTree {
+    let children = subtasks.remove(&task.task_id).unwrap_or_default();
+    TaskTree {
+        blocked_by: blocked_by.remove(&task.task_id).unwrap_or_default(),
+        subtasks: children
+            .into_iter()
+            .map(|child| build_tree(child, subtasks, blocked_by))
+            .collect(),
+        task,
+    }
+}
+
+/// Adds "`task_id` is blocked by `blocked_by_id`", adding it twice is a no-op.
+pub async fn insert_dependency(
+    pg_pool: &PgPool,
+    tenant: &Tenant,
+    task_id: i32,
+    blocked_by_id: i32,
+) -> Result<(), DependencyError> {
+    let mut tx = tenant::begin(pg_pool, tenant).await?;
+    lock(&mut tx, tenant).await?;
+    // the new edge closes a cycle when the blocker is already blocked, transitively, by the task
+    let cycle: bool = sqlx::query_scalar(
+        "WITH RECURSIVE blockers (task_id) AS (
+             SELECT $2::int
+             UNION
+             SELECT d.blocked_by_id FROM task_dependencies d JOIN blockers b ON d.task_id = b.task_id
+         )
+         SELECT EXISTS (SELECT 1 FROM blockers WHERE task_id = $1)",
+    )
+    .bind(task_id)
+    .bind(blocked_by_id)
+    .fetch_one(&mut *tx)
+    .await?;
+    if cycle {
+        return Err(DependencyError::Cycle);
+    }
+
+    sqlx::query(
+        "INSERT INTO task_dependencies (task_id, blocked_by_id) VALUES ($1, $2)
+         ON CONFLICT DO NOTHING",
+    )
+    .bind(task_id)
+    .bind(blocked_by_id)
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+
+    Ok(())
+}
+
+/// Returns `false` when there was no such dependency.
+pub async fn delete_dependency(
+    pg_pool: &PgPool,
+    tenant: &Tenant,
+    task_id: i32,
+    blocked_by_id: i32,
+) -> Result<bool, sqlx::Error> {
+    let mut tx = tenant::begin(pg_pool, tenant).await?;
+    let result =
+        sqlx::query("DELETE FROM task_dependencies WHERE task_id = $1 AND blocked_by_id = $2")
+            .bind(task_id)
+            .bind(blocked_by_id)
+            .execute(&mut *tx)
+            .await?;
+    tx.commit().await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+/// Open tasks whose blockers are all done, in topological order: a subtask comes before
+/// its parent and every other task above it.
+pub async fn ready_tasks(pg_pool: &PgPool, tenant: &Tenant) -> Result<Vec<TaskRow>, sqlx::Error> {
+    let mut tx = tenant::begin(pg_pool, tenant).await?;
+    let rows: Vec<TaskRow> = sqlx::query_as(
+        "SELECT t.task_id, t.name, t.priority, t.due_at, t.parent_id, t.done FROM tasks t
+         WHERE NOT t.done AND NOT EXISTS (
+             SELECT 1 FROM task_dependencies d JOIN tasks b ON b.task_id = d.blocked_by_id
+             WHERE d.task_id = t.task_id AND NOT b.done
+         )",
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+    let task_ids: Vec<i32> = rows.iter().map(|row| row.task_id).collect();
+    // (subtask, ancestor) pairs, the parent in between may not be ready itself
+    let edges: Vec<(i32, i32)> = sqlx::query_as(
+        "WITH RECURSIVE up (task_id, ancestor_id) AS (
+             SELECT task_id, parent_id FROM tasks WHERE task_id = ANY($1) AND parent_id IS NOT NULL
+             UNION
+             SELECT up.task_id, t.parent_id FROM up JOIN tasks t ON t.task_id = up.ancestor_id
+             WHERE t.parent_id IS NOT NULL
+         )
+         SELECT task_id, ancestor_id FROM up",
+    )
+    .bind(&task_ids)
+    .fetch_all(&mut *tx)
+    .await?;
+    tx.commit().await?;
+
+    Ok(topological_order(rows, &edges))
+}
+
+/// Kahn's algorithm, `(before, after)` edges, ties go to the lowest id.
+/// Edges to tasks outside `tasks` are ignored, a cycle would leave its tasks out.
+fn topological_order(tasks: Vec<TaskRow>, edges: &[(i32, i32)]) -> Vec<TaskRow> {
+    let mut by_id: HashMap<i32, TaskRow> = tasks.into_iter().map(|t| (t.task_id, t)).collect();
+    let mut incoming: HashMap<i32, usize> = by_id.keys().map(|&id| (id, 0)).collect();
+    let mut outgoing: HashMap<i32, Vec<i32>> = HashMap::new();
+    for &(before, after) in edges {
+        if by_id.contains_key(&before) && by_id.contains_key(&after) {
+            *incoming.entry(after).or_default() += 1;
+            outgoing.entry(before).or_default().push(after);
+        }
+    }
+
+    let mut next: BinaryHeap<Reverse<i32>> = incoming
+        .iter()
+        .filter(|(_, count)| **count == 0)
+        .map(|(&id, _)| Reverse(id))
+        .collect();
+    let mut ordered = Vec::with_capacity(by_id.len());
+    while let Some(Reverse(id)) = next.pop() {
+        for after in outgoing.remove(&id).unwrap_or_default() {
+            let count = incoming.get_mut(&after).unwrap();
+            *count -= 1;
+            if *count == 0 {
+                next.push(Reverse(after));
+            }
+        }
+        ordered.extend(by_id.remove(&id));
+    }
+
+    ordered
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tasks::{self, CreateTaskReq, TaskError, UpdateTaskReq};
+    use crate::tenant::TENANT_HEADER;
+    use crate::test_harness::{new_subtask, new_task};
+    use axum::body::Body;
+    use axum::http::Request;
+    use http_body_util::BodyExt;
+    use serde_json::{Value, json};
+    use tower::ServiceExt;
+
+    fn row(task_id: i32, parent_id: Option<i32>) -> TaskRow {
+        TaskRow {
+            task_id,
+            name: format!("task {task_id}"),
+            priority: None,
+            due_at: None,
+            parent_id,
+            done: false,
+        }
+    }
+
+    #[test]
+    fn test_topological_order() {
+        let tasks = vec![row(1, None), row(2, None), row(3, None), row(4, None)];
+        let ordered = topological_order(tasks, &[(3, 1), (4, 3), (2, 4), (9, 1)]);
+        let ids: Vec<i32> = ordered.iter().map(|t| t.task_id).collect();
+        assert_eq!(ids, vec![2, 4, 3, 1]);
+    }
+
+    async fn create(db_pool: &PgPool, name: &str, parent_id: Option<i32>) -> i32 {
+        let task = CreateTaskReq {
+            parent_id,
+            ..new_task(name)
+        };
+        tasks::insert_task(db_pool, &Tenant::new("team-a").unwrap(), &task)
+            .await
+            .unwrap()
+            .task_id
+    }
+
+    async fn send(app: &Router, method: &str, uri: &str) -> (StatusCode, Value) {
+        let request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(TENANT_HEADER, "team-a")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = response.collect().await.unwrap().to_bytes();
+        (status, serde_json::from_slice(&body).unwrap_or(Value::Null))
+    }
+
+    #[sqlx::test]
+    async fn test_tree_dependencies_and_ready(db_pool: PgPool) {
+        let app = create_hierarchy_router().with_state(crate::tests::test_state(db_pool.clone()));
+        let release = create(&db_pool, "release", None).await;
+        let build = create(&db_pool, "build", Some(release)).await;
+        let test = create(&db_pool, "test", Some(release)).await;
+        let lint = create(&db_pool, "lint", Some(test)).await;
+
+        let (status, _) = send(&app, "PUT", &format!("/tasks/{test}/blocked_by/{build}")).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        let (status, _) = send(&app, "PUT", &format!("/tasks/{build}/blocked_by/{lint}")).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        // test waits on build, which waits on lint
+        let (status, _) = send(&app, "PUT", &format!("/tasks/{lint}/blocked_by/{test}")).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        let (status, _) = send(&app, "PUT", &format!("/tasks/{lint}/blocked_by/999999")).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let (status, tree) = send(&app, "GET", &format!("/tasks/{release}/tree")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(tree["name"], "release");
+        assert_eq!(tree["subtasks"][0]["name"], "build");
+        assert_eq!(tree["subtasks"][0]["blocked_by"], json!([lint]));
+        assert_eq!(tree["subtasks"][1]["subtasks"][0]["name"], "lint");
+
+        // build waits on lint, test on build, so lint and release are ready, subtask first
+        let (_, ready) = send(&app, "GET", "/tasks/ready").await;
+        let names: Vec<&str> = ready
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|t| t["name"].as_str().unwrap())
+            .collect();
+        assert_eq!(names, vec!["lint", "release"]);
+
+        let done = UpdateTaskReq {
+            name: None,
+            priority: None,
+            due_at: None,
+            parent_id: None,
+            done: Some(true),
+        };
+        let tenant = Tenant::new("team-a").unwrap();
+        tasks::update_task(&db_pool, &tenant, lint, &done)
+            .await
+            .unwrap();
+        let (_, ready) = send(&app, "GET", "/tasks/ready").await;
+        assert_eq!(ready[0]["name"], "build");
+
+        let (status, _) = send(&app, "DELETE", &format!("/tasks/{build}/blocked_by/{lint}")).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        let (status, _) = send(&app, "DELETE", &format!("/tasks/{build}/blocked_by/{lint}")).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[sqlx::test]
+    async fn test_parent_rules(db_pool: PgPool) {
+        let tenant = Tenant::new("team-a").unwrap();
+        let parent = create(&db_pool, "parent", None).await;
+        let child = create(&db_pool, "child", Some(parent)).await;
+
+        let move_under = |parent_id| UpdateTaskReq {
+            name: None,
+            priority: None,
+            due_at: None,
+            parent_id: Some(Some(parent_id)),
+            done: None,
+        };
+        let result = tasks::update_task(&db_pool, &tenant, parent, &move_under(child)).await;
+        assert!(matches!(result, Err(TaskError::ParentCycle)));
+        let result = tasks::update_task(&db_pool, &tenant, parent, &move_under(parent)).await;
+        assert!(matches!(result, Err(TaskError::ParentCycle)));
+        let result = tasks::update_task(&db_pool, &tenant, child, &move_under(999_999)).await;
+        assert!(matches!(result, Err(TaskError::ParentNotFound)));
+        let result = tasks::delete_task(&db_pool, &tenant, parent).await;
+        assert!(matches!(result, Err(TaskError::HasSubtasks)));
+
+        // another tenant can not hang its tasks under ours
+        let task = new_subtask("intruder", parent);
+        let result = tasks::insert_task(&db_pool, &Tenant::new("team-b").unwrap(), &task).await;
+        assert!(matches!(result, Err(TaskError::ParentNotFound)));
+    }
+}
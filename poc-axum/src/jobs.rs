@@ -0,0 +1,384 @@
+use crate::tenant::Tenant;
+use crate::{tasks, webhooks};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use sqlx::{PgExecutor, PgPool};
+use std::time::Duration;
+
+// durable job queue over the jobs table, see migrations/*_create_jobs.sql for the statuses
+
+const DEFAULT_WORKERS: usize = 4;
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+const BASE_BACKOFF: Duration = Duration::from_secs(2);
+const MAX_BACKOFF: Duration = Duration::from_secs(60 * 60);
+// a running job whose lock is older than this belongs to a worker that died, it is picked up again
+const STALE_LOCK_SECS: f64 = 5.0 * 60.0;
+pub const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Job {
+    /// POSTs the task to the reminder webhook, unless the task was deleted or its due time changed.
+    TaskReminder {
+        tenant_id: String,
+        task_id: i32,
+        due_at: DateTime<Utc>,
+    },
+    /// Sends one row of webhook_deliveries.
+    WebhookDelivery { tenant_id: String, delivery_id: i64 },
+}
+
+impl Job {
+    fn kind(&self) -> &'static str {
+        match self {
+            Job::TaskReminder { .. } => "task_reminder",
+            Job::WebhookDelivery { .. } => "webhook_delivery",
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct JobsConfig {
+    pub workers: usize,
+    pub reminder_webhook_url: Option<String>,
+}
+
+impl JobsConfig {
+    /// Reads `JOB_WORKERS` and `REMINDER_WEBHOOK_URL`.
+    pub fn from_env() -> Self {
+        JobsConfig {
+            workers: std::env::var("JOB_WORKERS")
+                .ok()
+                .and_then(|workers| workers.parse().ok())
+                .unwrap_or(DEFAULT_WORKERS),
+            reminder_webhook_url: std::env::var("REMINDER_WEBHOOK_URL").ok(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct JobContext {
+    db_pool: PgPool,
+    http_client: reqwest::Client,
+    config: JobsConfig,
+}
+
+impl JobContext {
+    pub fn new(db_pool: PgPool, config: JobsConfig) -> Self {
+        JobContext {
+            db_pool,
+            http_client: reqwest::Client::new(),
+            config,
+        }
+    }
+}
+
+/// Adds a job that becomes runnable at `run_at`, takes an executor so it can join the caller's transaction.
+pub async fn enqueue<'e>(
+    executor: impl PgExecutor<'e>,
+    job: &Job,
+    run_at: DateTime<Utc>,
+) -> Result<i64, sqlx::Error> {
+    sqlx::query_scalar(
+        "INSERT INTO jobs (kind, payload, run_at) VALUES ($1, $2, $3) RETURNING job_id",
+    )
+    .bind(job.kind())
+    .bind(sqlx::types::Json(job))
+    .bind(run_at)
+    .fetch_one(executor)
+    .await
+}
+
+pub fn spawn_workers(context: JobContext) {
+    if context.config.reminder_webhook_url.is_none() {
+        println!("REMINDER_WEBHOOK_URL is not set, task reminders are not sent");
+    }
+    for _ in 0..context.config.workers {
+        let context = context.clone();
+        tokio::spawn(async move {
+            loop {
+                match run_next(&context).await {
+                    Ok(true) => {}
+                    Ok(false) => tokio::time::sleep(POLL_INTERVAL).await,
+                    Err(e) => {
+                        eprintln!("Failed to run job: {e}");
+                        tokio::time::sleep(POLL_INTERVAL).await;
+                    }
+                }
+            }
+        });
+    }
+}
+
+#[derive(sqlx::FromRow)]
+struct ClaimedJob {
+    job_id: i64,
+    payload: Value,
+    attempts: i32,
+    max_attempts: i32,
+}
+
+/// Claims and runs one due job, returns `false` when there was nothing to run.
+pub async fn run_next(context: &JobContext) -> Result<bool, sqlx::Error> {
+    // SKIP LOCKED lets every worker claim a different row without waiting on each other
+    let claimed: Option<ClaimedJob> = sqlx::query_as(
+        "UPDATE jobs SET status = 'running', attempts = attempts + 1, locked_at = now()
+         WHERE job_id = (
+             SELECT job_id FROM jobs
+             WHERE (status = 'pending' AND run_at <= now())
+                OR (status = 'running' AND locked_at < now() - make_interval(secs => $1))
+             ORDER BY run_at
+             LIMIT 1
+             FOR UPDATE SKIP LOCKED
+         )
+         RETURNING job_id, payload, attempts, max_attempts",
+    )
+    .bind(STALE_LOCK_SECS)
+    .fetch_optional(&context.db_pool)
+    .await?;
+    let Some(claimed) = claimed else {
+        return Ok(false);
+    };
+
+    let result = match serde_json::from_value::<Job>(claimed.payload) {
+        Ok(job) => run(&job, context, claimed.attempts >= claimed.max_attempts).await,
+        Err(e) => {
+            // it will never parse, no point in retrying
+            return dead_letter(
+                &context.db_pool,
+                claimed.job_id,
+                &format!("invalid payload: {e}"),
+            )
+            .await
+            .map(|_| true);
+        }
+    };
+
+    match result {
+        Ok(()) => {
+            sqlx::query("UPDATE jobs SET status = 'done', locked_at = NULL WHERE job_id = $1")
+                .bind(claimed.job_id)
+                .execute(&context.db_pool)
+                .await?;
+        }
+        Err(error) if claimed.attempts >= claimed.max_attempts => {
+            dead_letter(&context.db_pool, claimed.job_id, &error).await?;
+        }
+        Err(error) => {
+            let run_at = Utc::now() + backoff(claimed.attempts);
+            sqlx::query(
+                "UPDATE jobs SET status = 'pending', locked_at = NULL, run_at = $2, last_error = $3
+                 WHERE job_id = $1",
+            )
+            .bind(claimed.job_id)
+            .bind(run_at)
+            .bind(error)
+            .execute(&context.db_pool)
+            .await?;
+        }
+    }
+
+    Ok(true)
+}
+
+async fn dead_letter(db_pool: &PgPool, job_id: i64, error: &str) -> Result<(), sqlx::Error> {
+    eprintln!("Job {job_id} moved to dead letter: {error}");
+    sqlx::query(
+        "UPDATE jobs SET status = 'dead', locked_at = NULL, last_error = $2 WHERE job_id = $1",
+    )
+    .bind(job_id)
+    .bind(error)
+    .execute(db_pool)
+    .await?;
+
+    Ok(())
+}
+
+/// 2s, 4s, 8s... after each failed attempt, capped at one hour.
+fn backoff(attempts: i32) -> Duration {
+    let exponent = attempts.saturating_sub(1).clamp(0, 20) as u32;
+    BASE_BACKOFF
+        .saturating_mul(2u32.pow(exponent))
+        .min(MAX_BACKOFF)
+}
+
+async fn run(job: &Job, context: &JobContext, final_attempt: bool) -> Result<(), String> {
+    match job {
+        Job::TaskReminder {
+            tenant_id,
+            task_id,
+            due_at,
+        } => send_reminder(context, &job_tenant(tenant_id)?, *task_id, *due_at).await,
+        Job::WebhookDelivery {
+            tenant_id,
+            delivery_id,
+        } => {
+            webhooks::deliver(
+                &context.db_pool,
+                &context.http_client,
+                &job_tenant(tenant_id)?,
+                *delivery_id,
+                final_attempt,
+            )
+            .await
+        }
+    }
+}
+
+// jobs act for the tenant that enqueued them, through the same row level security as requests
+fn job_tenant(tenant_id: &str) -> Result<Tenant, String> {
+    Tenant::new(tenant_id).map_err(|e| e.message().to_owned())
+}
+
+async fn send_reminder(
+    context: &JobContext,
+    tenant: &Tenant,
+    task_id: i32,
+    due_at: DateTime<Utc>,
+) -> Result<(), String> {
+    // not configured, said once by spawn_workers. done rather than retried into the dead letters
+    let Some(url) = &context.config.reminder_webhook_url else {
+        return Ok(());
+    };
+    let task = tasks::find_task(&context.db_pool, tenant, task_id)
+        .await
+        .map_err(|e| e.to_string())?;
+    let Some(task) = task.filter(|task| task.due_at == Some(due_at)) else {
+        // deleted or rescheduled, the reminder for the new due time is another job
+        return Ok(());
+    };
+
+    context
+        .http_client
+        .post(url)
+        .timeout(WEBHOOK_TIMEOUT)
+        .json(&json!({ "event": "task.reminder", "task": task }))
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|e| e.to_string())?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tasks::CreateTaskReq;
+    use crate::test_harness::new_task;
+    use axum::{Json, Router, extract::State, routing::post};
+    use tokio::net::TcpListener;
+    use tokio::sync::mpsc;
+
+    fn context(db_pool: PgPool, reminder_webhook_url: Option<String>) -> JobContext {
+        JobContext::new(
+            db_pool,
+            JobsConfig {
+                workers: 1,
+                reminder_webhook_url,
+            },
+        )
+    }
+
+    async fn job_status(db_pool: &PgPool) -> (String, i32) {
+        sqlx::query_as("SELECT status, attempts FROM jobs")
+            .fetch_one(db_pool)
+            .await
+            .unwrap()
+    }
+
+    #[test]
+    fn test_backoff_doubles_until_cap() {
+        assert_eq!(backoff(1), Duration::from_secs(2));
+        assert_eq!(backoff(2), Duration::from_secs(4));
+        assert_eq!(backoff(3), Duration::from_secs(8));
+        assert_eq!(backoff(100), MAX_BACKOFF);
+    }
+
+    #[sqlx::test]
+    async fn test_reminder_is_sent_when_task_is_due(db_pool: PgPool) {
+        let (sender, mut receiver) = mpsc::unbounded_channel::<Value>();
+        let receiver_app = Router::new()
+            .route(
+                "/reminders",
+                post(
+                    |State(sender): State<mpsc::UnboundedSender<Value>>,
+                     Json(body): Json<Value>| async move {
+                        sender.send(body).unwrap();
+                    },
+                ),
+            )
+            .with_state(sender);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/reminders", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, receiver_app).await.unwrap() });
+
+        let task = tasks::insert_task(
+            &db_pool,
+            &Tenant::new("team-a").unwrap(),
+            &CreateTaskReq {
+                due_at: Some(Utc::now()),
+                ..new_task("pay rent")
+            },
+        )
+        .await
+        .unwrap();
+
+        let context = context(db_pool.clone(), Some(url));
+        assert!(run_next(&context).await.unwrap());
+        assert!(!run_next(&context).await.unwrap());
+
+        let body = receiver.recv().await.unwrap();
+        assert_eq!(body["event"], "task.reminder");
+        assert_eq!(body["task"]["task_id"], task.task_id);
+        assert_eq!(job_status(&db_pool).await, ("done".to_owned(), 1));
+    }
+
+    async fn insert_due_task(db_pool: &PgPool) {
+        tasks::insert_task(
+            db_pool,
+            &Tenant::new("team-a").unwrap(),
+            &CreateTaskReq {
+                due_at: Some(Utc::now()),
+                ..new_task("pay rent")
+            },
+        )
+        .await
+        .unwrap();
+    }
+
+    #[sqlx::test]
+    async fn test_reminder_without_webhook_url_is_done(db_pool: PgPool) {
+        insert_due_task(&db_pool).await;
+        let context = context(db_pool.clone(), None);
+        assert!(run_next(&context).await.unwrap());
+        assert_eq!(job_status(&db_pool).await, ("done".to_owned(), 1));
+    }
+
+    #[sqlx::test]
+    async fn test_failing_job_is_retried_then_dead_lettered(db_pool: PgPool) {
+        insert_due_task(&db_pool).await;
+        sqlx::query("UPDATE jobs SET max_attempts = 2")
+            .execute(&db_pool)
+            .await
+            .unwrap();
+        // nothing listens on port 1, every attempt is refused
+        let context = context(
+            db_pool.clone(),
+            Some("http://127.0.0.1:1/reminders".to_owned()),
+        );
+
+        assert!(run_next(&context).await.unwrap());
+        assert_eq!(job_status(&db_pool).await, ("pending".to_owned(), 1));
+        // backed off, so it is not due yet
+        assert!(!run_next(&context).await.unwrap());
+
+        sqlx::query("UPDATE jobs SET run_at = now()")
+            .execute(&db_pool)
+            .await
+            .unwrap();
+        assert!(run_next(&context).await.unwrap());
+        assert_eq!(job_status(&db_pool).await, ("dead".to_owned(), 2));
+    }
+}
@@ -1,284 +1,1089 @@
-use axum::response::IntoResponse;
+use attachments::Attachments;
+use axum::http::HeaderMap;
+use axum::http::header::{AGE, CACHE_CONTROL, HeaderName};
 use axum::{
     Json, Router,
-    extract::{Path, State},
+    extract::{FromRef, Path, Query, State},
     http::StatusCode,
     routing::get,
 };
-use serde::{Deserialize, Serialize};
-use serde_json::{Value, json};
+use cache::{CacheStats, TaskCache, TaskCacheConfig};
+use debug::ClientAddr;
+use events::{TaskEvent, TaskEvents};
+use replica::{DbPools, ReplicaConfig, SessionToken};
+use serde_json::json;
 use sqlx::{PgPool, postgres::PgPoolOptions};
+use std::sync::Arc;
+use std::time::Duration;
+use store::{PgTaskStore, TaskStore};
+use tasks::{CreateTaskReq, ListTasksQuery, TaskError, TaskRow, UpdateTaskReq};
+use tenant::{Tenant, TenantResolver};
+use tls::{CertResolver, TlsConfig};
 use tokio::net::TcpListener;
 
+mod attachments;
+mod blob_store;
+mod cache;
+mod debug;
+mod events;
+mod graphql;
+mod grpc;
+mod hierarchy;
+mod jobs;
+mod replica;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+mod store;
+mod tasks;
+mod tenant;
+#[cfg(test)]
+mod test_harness;
+mod tls;
+mod webhooks;
+
 #[tokio::main]
 async fn main() {
-    //run_hello_world().await;
-
     run_basic_crud().await;
     println!("Hello, world!");
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use axum::body::Body;
-    use axum::http::Request;
-    use http_body_util::BodyExt;
-    use tower::ServiceExt;
-    #[tokio::test]
-    async fn test() {
-        let app = create_hello_world_router();
-        let request = Request::builder()
-            .uri("/health")
-            .body(Body::empty())
-            .unwrap();
+async fn run_basic_crud() {
+    // TODO how this load the properties?
+    dotenvy::dotenv().expect("Failed to initialize dotenvy.");
+    let server_address =
+        std::env::var("SERVER_ADDRESS").unwrap_or_else(|_| "127.0.0.1:8080".to_owned());
+    let database_url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+    if store::is_sqlite_url(&database_url) {
+        let router = create_sqlite_router_from_env(&database_url).await;
+        return serve(&server_address, router).await;
+    }
 
-        let response = app
-            // oneshot is provided by tower TODO why that works?
-            .oneshot(request)
-            .await
-            .unwrap();
-        assert_eq!(response.status(), StatusCode::OK);
+    let db_pool = PgPoolOptions::new()
+        .max_connections(16)
+        .connect(&database_url)
+        .await
+        .expect("Failed to connect to database");
+    sqlx::migrate!()
+        .run(&db_pool)
+        .await
+        .expect("Failed to run migrations");
+    jobs::spawn_workers(jobs::JobContext::new(
+        db_pool.clone(),
+        jobs::JobsConfig::from_env(),
+    ));
 
-        let body = response.collect().await.unwrap();
-        let json: Value = serde_json::from_slice(&body.to_bytes()).unwrap();
-        assert_eq!(json["status"], "ok");
-        assert_eq!(json["message"], "Server is running!");
+    let cache_config = TaskCacheConfig::from_env();
+    let task_cache = TaskCache::new(&cache_config);
+    if cache_config.listen {
+        cache::spawn_listener(db_pool.clone(), task_cache.clone());
     }
+
+    // reads go to the replica as long as it is healthy, see replica.rs
+    let replica_config = ReplicaConfig::from_env();
+    let replica_pool = replica_config
+        .as_ref()
+        .map(|config| replica::replica_pool(&config.url).expect("Invalid DATABASE_REPLICA_URL"));
+    let db_pools = DbPools::new(db_pool, replica_pool);
+    if let Some(config) = replica_config {
+        replica::spawn_health_checks(db_pools.clone(), config.health_interval);
+    }
+
+    let mut state = AppState::new(
+        db_pools,
+        TenantResolver::from_env(),
+        task_cache,
+        Attachments::from_env(),
+    );
+    state.debug_routes = debug::enabled_from_env();
+    let router = create_tasks_router(state);
+
+    serve(&server_address, router).await;
 }
 
-async fn run_hello_world() {
-    let app = create_hello_world_router();
+/// Serves over HTTPS when TLS is configured, plain HTTP otherwise.
+async fn serve(server_address: &str, router: Router) {
+    let listener = TcpListener::bind(server_address)
+        .await
+        .expect("Failed to bind to address");
+    println!("Listening on {}", listener.local_addr().unwrap());
 
-    // run our app with hyper, listening globally on port 3000
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await.unwrap();
-    axum::serve(listener, app).await.unwrap();
+    match TlsConfig::from_env() {
+        Some(tls_config) => serve_tls(listener, router, tls_config).await,
+        None => axum::serve(
+            listener,
+            router.into_make_service_with_connect_info::<ClientAddr>(),
+        )
+        .await
+        .expect("Failed to run server"),
+    }
+
+    println!("Hello, world!");
 }
 
-fn create_hello_world_router() -> Router {
-    async fn hello_world() -> String {
-        "Hello, World2!".to_string()
+async fn serve_tls(listener: TcpListener, router: Router, tls_config: TlsConfig) {
+    let resolver = Arc::new(
+        CertResolver::load(&tls_config.cert_path, &tls_config.key_path)
+            .unwrap_or_else(|e| panic!("Failed to load TLS certificate: {e}")),
+    );
+    // certificates are reloaded while the watcher lives
+    let _watcher = tls::watch(resolver.clone())
+        .unwrap_or_else(|e| panic!("Failed to watch TLS certificate: {e}"));
+
+    if let Some(redirect_address) = tls_config.redirect_address {
+        let redirect_listener = TcpListener::bind(redirect_address)
+            .await
+            .expect("Failed to bind redirect address");
+        println!(
+            "Redirecting HTTP on {} to HTTPS",
+            redirect_listener.local_addr().unwrap()
+        );
+        let https_port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            tls::serve_redirect(redirect_listener, https_port)
+                .await
+                .expect("Failed to run redirect server")
+        });
     }
 
-    async fn mirror_body_string(body: String) -> String {
-        let concat = format!("body: {}", body);
-        concat
+    tls::serve(listener, router, resolver)
+        .await
+        .expect("Failed to run server");
+}
+
+#[derive(Clone)]
+struct AppState {
+    db_pool: PgPool,
+    db_pools: DbPools,
+    task_store: Arc<dyn TaskStore>,
+    task_events: TaskEvents,
+    tenant_resolver: TenantResolver,
+    task_cache: TaskCache,
+    attachments: Attachments,
+    /// Serve the `/debug` routes.
+    debug_routes: bool,
+}
+
+impl AppState {
+    fn new(
+        db_pools: DbPools,
+        tenant_resolver: TenantResolver,
+        task_cache: TaskCache,
+        attachments: Attachments,
+    ) -> Self {
+        AppState {
+            db_pool: db_pools.primary().clone(),
+            task_store: Arc::new(PgTaskStore::new(db_pools.clone())),
+            db_pools,
+            task_events: TaskEvents::new(),
+            tenant_resolver,
+            task_cache,
+            attachments,
+            debug_routes: false,
+        }
     }
+}
 
-    async fn health_check() -> impl IntoResponse {
-        Json(json!({ "status": "ok", "message": "Server is running!" }))
+impl FromRef<AppState> for PgPool {
+    fn from_ref(state: &AppState) -> Self {
+        state.db_pool.clone()
     }
+}
 
-    async fn get_by(Path(id): Path<i32>) -> Result<Json<Value>, MyApiError> {
-        if id < 1 {
-            return Err(MyApiError::InvalidInput("Invalid id".to_owned()));
-        }
+impl FromRef<AppState> for DbPools {
+    fn from_ref(state: &AppState) -> Self {
+        state.db_pools.clone()
+    }
+}
 
-        Ok(Json(json!({ "id": id })))
+impl FromRef<AppState> for Arc<dyn TaskStore> {
+    fn from_ref(state: &AppState) -> Self {
+        state.task_store.clone()
     }
+}
 
-    let app = Router::new()
-        .route("/", get(|| async { "Hello, World!" }))
-        .route("/hello", get(hello_world))
-        .route("/mirror", get(mirror_body_string))
-        .route("/health", get(health_check))
-        .route("/get_by/{id}", get(get_by));
-    app
+impl FromRef<AppState> for TaskEvents {
+    fn from_ref(state: &AppState) -> Self {
+        state.task_events.clone()
+    }
 }
 
-async fn run_basic_crud() {
-    // TODO how this load the properties?
-    dotenvy::dotenv().expect("Failed to initialize dotenvy.");
-    let server_address =
-        std::env::var("SERVER_ADDRESS").unwrap_or_else(|_| "127.0.0.1:8080".to_owned());
-    let database_url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+impl FromRef<AppState> for TenantResolver {
+    fn from_ref(state: &AppState) -> Self {
+        state.tenant_resolver.clone()
+    }
+}
 
-    let db_pool = PgPoolOptions::new()
-        .max_connections(16)
-        .connect(&database_url)
-        .await
-        .expect("Failed to connect to database");
+impl FromRef<AppState> for TaskCache {
+    fn from_ref(state: &AppState) -> Self {
+        state.task_cache.clone()
+    }
+}
 
-    let router = Router::new()
+impl FromRef<AppState> for Attachments {
+    fn from_ref(state: &AppState) -> Self {
+        state.attachments.clone()
+    }
+}
+
+/// REST, GraphQL and gRPC (multiplexed by content type) over the same tasks table.
+/// Every route acts for the tenant resolved from the request, see [`TenantResolver`].
+fn create_tasks_router(state: AppState) -> Router {
+    let mut rest = create_task_routes()
+        .merge(hierarchy::create_hierarchy_router())
+        .merge(webhooks::create_webhooks_router())
+        .merge(attachments::create_attachments_router())
+        .with_state(state.clone())
+        .merge(graphql::create_graphql_router(
+            graphql::create_schema(
+                state.db_pool.clone(),
+                state.task_events.clone(),
+                state.task_cache.clone(),
+            ),
+            state.tenant_resolver.clone(),
+        ));
+    if state.debug_routes {
+        rest = rest.merge(debug::create_debug_router());
+    }
+
+    grpc::multiplex(rest, state)
+}
+
+/// The `/tasks` CRUD routes, over the [`TaskStore`] of the state.
+fn create_task_routes<S>() -> Router<S>
+where
+    Arc<dyn TaskStore>: FromRef<S>,
+    TaskEvents: FromRef<S>,
+    TaskCache: FromRef<S>,
+    TenantResolver: FromRef<S>,
+    S: Clone + Send + Sync + 'static,
+{
+    Router::new()
         .route("/tasks", get(get_tasks).post(create_task))
         .route(
             "/tasks/{task_id}",
             get(get_task).patch(update_task).delete(delete_task),
         )
-        .with_state(db_pool);
+        .route("/cache/stats", get(get_cache_stats))
+}
 
-    let listener = TcpListener::bind(server_address)
-        .await
-        .expect("Failed to bind to address");
-    println!("Listening on {}", listener.local_addr().unwrap());
+/// All a server on SQLite has: the task routes, no GraphQL, gRPC, jobs, webhooks,
+/// subtask trees or attachments, those need Postgres.
+#[cfg(feature = "sqlite")]
+#[derive(Clone)]
+struct SqliteState {
+    task_store: Arc<dyn TaskStore>,
+    task_events: TaskEvents,
+    tenant_resolver: TenantResolver,
+    task_cache: TaskCache,
+    debug_routes: bool,
+}
+
+#[cfg(feature = "sqlite")]
+impl FromRef<SqliteState> for Arc<dyn TaskStore> {
+    fn from_ref(state: &SqliteState) -> Self {
+        state.task_store.clone()
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl FromRef<SqliteState> for TaskEvents {
+    fn from_ref(state: &SqliteState) -> Self {
+        state.task_events.clone()
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl FromRef<SqliteState> for TenantResolver {
+    fn from_ref(state: &SqliteState) -> Self {
+        state.tenant_resolver.clone()
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl FromRef<SqliteState> for TaskCache {
+    fn from_ref(state: &SqliteState) -> Self {
+        state.task_cache.clone()
+    }
+}
 
-    axum::serve(listener, router)
+#[cfg(feature = "sqlite")]
+fn create_sqlite_router(state: SqliteState) -> Router {
+    let mut router = create_task_routes();
+    if state.debug_routes {
+        router = router.merge(debug::create_debug_router());
+    }
+
+    router.with_state(state)
+}
+
+#[cfg(feature = "sqlite")]
+async fn create_sqlite_router_from_env(database_url: &str) -> Router {
+    let pool = sqlite::connect(database_url)
         .await
-        .expect("Failed to run server");
+        .expect("Failed to open database");
+    sqlite::migrate(&pool)
+        .await
+        .expect("Failed to run migrations");
+    // LISTEN is Postgres only, TASK_CACHE_LISTEN has nothing to listen to
+    create_sqlite_router(SqliteState {
+        task_store: Arc::new(sqlite::SqliteTaskStore::new(pool)),
+        task_events: TaskEvents::new(),
+        tenant_resolver: TenantResolver::from_env(),
+        task_cache: TaskCache::new(&TaskCacheConfig::from_env()),
+        debug_routes: debug::enabled_from_env(),
+    })
+}
 
-    println!("Hello, world!");
+#[cfg(not(feature = "sqlite"))]
+async fn create_sqlite_router_from_env(_database_url: &str) -> Router {
+    panic!("DATABASE_URL is a SQLite database, build with --features sqlite");
 }
 
 async fn create_task(
     // TODO how works State? idem Json
-    State(db_pool): State<PgPool>,
+    State(task_store): State<Arc<dyn TaskStore>>,
+    State(task_events): State<TaskEvents>,
+    State(task_cache): State<TaskCache>,
+    tenant: Tenant,
     Json(task): Json<CreateTaskReq>,
-) -> Result<(StatusCode), (StatusCode, String)> {
+) -> Result<(StatusCode, HeaderMap, Json<TaskRow>), (StatusCode, String)> {
     dbg!(&task);
 
-    // when the table task is not db, this line throws error: "error: error returned from database: relation "tasks" does not exist"
-    // throws that in compile time WHY?
-    // row is type unknown, TODO why?
-    let row = sqlx::query_as!(
-        CreateTaskRow,
-        "INSERT INTO tasks (name, priority) VALUES ($1, $2) RETURNING task_id",
-        task.name,
-        task.priority
-    )
-    .fetch_one(&db_pool)
-    .await
-    .map_err(|e| {
-        (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            json!({"success": false, "message": e.to_string()}).to_string(),
-        )
-    })?;
+    let row = task_store
+        .insert_task(&tenant, &task)
+        .await
+        .map_err(task_error)?;
+    task_cache.invalidate(tenant.id(), row.task_id);
+    task_events.publish(&tenant, TaskEvent::Created(row.clone()));
+    let headers = task_store.session_headers().await.map_err(internal_error)?;
 
-    Ok(StatusCode::CREATED)
+    Ok((StatusCode::CREATED, headers, Json(row)))
 }
 
 // TODO State(pg_pool) learn more
+/// Tasks ordered by id, `?after=&limit=` pages through them. Only the unfiltered full list is cached.
+/// With a session token the cache is skipped, it may hold what a lagging replica returned.
 async fn get_tasks(
-    State(pg_pool): State<PgPool>,
-) -> Result<Json<Vec<TaskRow>>, (StatusCode, String)> {
-    let rows = sqlx::query_as!(TaskRow, "SELECT * FROM tasks")
-        .fetch_all(&pg_pool)
+    State(task_store): State<Arc<dyn TaskStore>>,
+    State(task_cache): State<TaskCache>,
+    tenant: Tenant,
+    SessionToken(token): SessionToken,
+    Query(query): Query<ListTasksQuery>,
+) -> Result<([(HeaderName, String); 2], Json<Vec<TaskRow>>), (StatusCode, String)> {
+    if query.limit.is_some_and(|limit| limit < 0) {
+        return Err(api_error(
+            StatusCode::BAD_REQUEST,
+            "limit must not be negative",
+        ));
+    }
+    let whole_list = query == ListTasksQuery::default() && token.is_none();
+    if whole_list && let Some(cached) = task_cache.tasks(&tenant) {
+        return Ok((cache_headers(cached.age), Json(cached.value)));
+    }
+
+    let generation = task_cache.generation();
+    let (after, limit) = (query.after, query.limit);
+    let rows = task_store
+        .list_tasks(&tenant, &query.into(), after, limit, token)
         .await
-        .map_err(|e| {
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                json!({"success": false, "message": e.to_string()}).to_string(),
-            )
-        })?;
+        .map_err(internal_error)?;
+    if whole_list {
+        task_cache.put_tasks(&tenant, rows.clone(), generation);
+    }
 
-    Ok(Json(rows))
+    Ok((cache_headers(Duration::ZERO), Json(rows)))
 }
 
 async fn get_task(
-    State(pg_pool): State<PgPool>,
+    State(task_store): State<Arc<dyn TaskStore>>,
+    State(task_cache): State<TaskCache>,
+    tenant: Tenant,
+    SessionToken(token): SessionToken,
     Path(task_id): Path<i32>,
-) -> Result<Json<TaskRow>, (StatusCode, String)> {
-    let row = sqlx::query_as!(TaskRow, "SELECT * FROM tasks WHERE task_id = $1", task_id)
-        .fetch_one(&pg_pool)
-        .await
-        .map_err(|e| {
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                json!({"success": false, "message": e.to_string()}).to_string(),
-            )
-        })?;
+) -> Result<([(HeaderName, String); 2], Json<TaskRow>), (StatusCode, String)> {
+    let cached = match token {
+        Some(_) => None,
+        None => task_cache.task(&tenant, task_id),
+    };
+    let (row, age) = match cached {
+        Some(cached) => (cached.value, cached.age),
+        None => {
+            let generation = task_cache.generation();
+            let row = task_store
+                .find_task(&tenant, task_id, token)
+                .await
+                .map_err(internal_error)?;
+            task_cache.put_task(&tenant, task_id, row.clone(), generation);
+            (row, Duration::ZERO)
+        }
+    };
+    let row = row.ok_or_else(task_not_found)?;
 
-    Ok(Json(row))
+    Ok((cache_headers(age), Json(row)))
+}
+
+// private: the cached rows belong to the tenant of the request. no-cache: a write invalidates the
+// server's cache but can not reach the client's, so the client asks again every time
+fn cache_headers(age: Duration) -> [(HeaderName, String); 2] {
+    [
+        (CACHE_CONTROL, "private, no-cache".to_owned()),
+        (AGE, age.as_secs().to_string()),
+    ]
+}
+
+async fn get_cache_stats(State(task_cache): State<TaskCache>) -> Json<CacheStats> {
+    Json(task_cache.stats())
 }
 
 async fn update_task(
-    State(db_pool): State<PgPool>,
+    State(task_store): State<Arc<dyn TaskStore>>,
+    State(task_events): State<TaskEvents>,
+    State(task_cache): State<TaskCache>,
+    tenant: Tenant,
     Path(task_id): Path<i32>,
     Json(task): Json<UpdateTaskReq>,
-) -> Result<(), (StatusCode, String)> {
-    let mut query = "UPDATE tasks SET task_id = $1".to_owned();
-    let mut i = 2;
-    if task.name.is_some() {
-        query.push_str(&format!(", name = ${i}"));
-        i = i + 1;
-    }
-    if task.priority.is_some() {
-        query.push_str(&format!(", priority = ${i}"));
-        i = i + 1;
-    }
-
-    query.push_str(&format!(" WHERE task_id = $1"));
-
-    let mut s = sqlx::query(&query).bind(task_id);
-    if task.name.is_some() {
-        s = s.bind(task.name);
-    }
-
-    if task.priority.is_some() {
-        s = s.bind(task.priority);
-    }
-
-    s.execute(&db_pool).await.map_err(|e| {
-        (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            json!({"success": false, "message": e.to_string()}).to_string(),
-        )
-    })?;
+) -> Result<HeaderMap, (StatusCode, String)> {
+    let row = task_store
+        .update_task(&tenant, task_id, &task)
+        .await
+        .map_err(task_error)?
+        .ok_or_else(task_not_found)?;
+    task_cache.invalidate(tenant.id(), task_id);
+    task_events.publish(&tenant, TaskEvent::Updated(row));
 
-    Ok(())
+    task_store.session_headers().await.map_err(internal_error)
 }
 
 async fn delete_task(
-    State(db_pool): State<PgPool>,
+    State(task_store): State<Arc<dyn TaskStore>>,
+    State(task_events): State<TaskEvents>,
+    State(task_cache): State<TaskCache>,
+    tenant: Tenant,
     Path(task_id): Path<i32>,
-) -> Result<(StatusCode), (StatusCode, String)> {
-    sqlx::query!("DELETE FROM tasks WHERE task_id = $1", task_id)
-        .execute(&db_pool)
+) -> Result<(StatusCode, HeaderMap), (StatusCode, String)> {
+    let deleted = task_store
+        .delete_task(&tenant, task_id)
         .await
-        .map_err(|e| {
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                json!({"success": false, "message": e.to_string()}).to_string(),
-            )
-        })?;
+        .map_err(task_error)?;
+    if !deleted {
+        return Err(task_not_found());
+    }
+    task_cache.invalidate(tenant.id(), task_id);
+    task_events.publish(&tenant, TaskEvent::Deleted(task_id));
+    let headers = task_store.session_headers().await.map_err(internal_error)?;
 
-    Ok(StatusCode::NO_CONTENT)
+    Ok((StatusCode::NO_CONTENT, headers))
 }
 
-#[derive(Serialize)]
-struct TaskRow {
-    task_id: i32,
-    name: String,
-    priority: Option<i32>,
+fn api_error(status: StatusCode, message: &str) -> (StatusCode, String) {
+    (
+        status,
+        json!({"success": false, "message": message}).to_string(),
+    )
 }
 
-#[derive(Deserialize, Debug)]
-struct CreateTaskReq {
-    name: String,
-    priority: Option<i32>,
+fn internal_error(e: sqlx::Error) -> (StatusCode, String) {
+    api_error(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string())
 }
 
-#[derive(Serialize)]
-struct CreateTaskRow {
-    task_id: i32,
+fn task_error(e: TaskError) -> (StatusCode, String) {
+    match e {
+        TaskError::ParentNotFound => api_error(StatusCode::BAD_REQUEST, &e.to_string()),
+        TaskError::ParentCycle | TaskError::HasSubtasks => {
+            api_error(StatusCode::CONFLICT, &e.to_string())
+        }
+        TaskError::Database(e) => internal_error(e),
+    }
 }
 
-#[derive(Deserialize)]
-struct UpdateTaskReq {
-    name: Option<String>,
-    priority: Option<i32>,
+fn task_not_found() -> (StatusCode, String) {
+    api_error(StatusCode::NOT_FOUND, "Task not found")
 }
 
-enum MyApiError {
-    NotFound,
-    InvalidInput(String),
-    InternalError,
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+    use axum::http::{Method, Request};
+    use chrono::{TimeZone, Utc};
+    use futures_util::TryStreamExt;
+    use http_body_util::BodyExt;
+    use serde_json::Value;
+    use test_harness::{ApiFailure, TestServer, new_subtask, new_task};
+    use tower::ServiceExt;
 
-impl IntoResponse for MyApiError {
-    fn into_response(self) -> axum::response::Response {
-        let (status, error_message) = match self {
-            MyApiError::NotFound => (StatusCode::NOT_FOUND, "Not Found".to_string()),
-            MyApiError::InvalidInput(msg) => (StatusCode::BAD_REQUEST, msg),
-            MyApiError::InternalError => (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "Internal Server Error".to_string(),
-            ),
+    fn test_cache() -> TaskCache {
+        TaskCache::new(&TaskCacheConfig {
+            capacity: 16,
+            ttl: Duration::from_secs(60),
+            listen: false,
+        })
+    }
+
+    pub(crate) fn test_state(db_pool: PgPool) -> AppState {
+        let attachments =
+            Attachments::local(std::env::temp_dir().join("poc-axum-attachments"), 1024);
+        AppState::new(
+            DbPools::new(db_pool, None),
+            TenantResolver::new(None),
+            test_cache(),
+            attachments,
+        )
+    }
+
+    /// The router of a server on a fresh in-memory SQLite database.
+    #[cfg(feature = "sqlite")]
+    pub(crate) async fn test_sqlite_router() -> Router {
+        let pool = sqlite::connect("sqlite::memory:").await.unwrap();
+        sqlite::migrate(&pool).await.unwrap();
+        create_sqlite_router(SqliteState {
+            task_store: Arc::new(sqlite::SqliteTaskStore::new(pool)),
+            task_events: TaskEvents::new(),
+            tenant_resolver: TenantResolver::new(None),
+            task_cache: test_cache(),
+            debug_routes: false,
+        })
+    }
+
+    async fn send(
+        app: &Router,
+        method: &str,
+        uri: &str,
+        tenant_id: Option<&str>,
+        body: Value,
+    ) -> (StatusCode, Value) {
+        let mut request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header("content-type", "application/json");
+        if let Some(tenant_id) = tenant_id {
+            request = request.header(tenant::TENANT_HEADER, tenant_id);
+        }
+        let body = if body.is_null() {
+            Body::empty()
+        } else {
+            Body::from(body.to_string())
         };
 
-        let body = Json(json!({"error": error_message}));
+        let response = app
+            .clone()
+            .oneshot(request.body(body).unwrap())
+            .await
+            .unwrap();
+        let status = response.status();
+        let body = response.collect().await.unwrap().to_bytes();
+        (status, serde_json::from_slice(&body).unwrap_or(Value::Null))
+    }
+
+    #[sqlx::test]
+    async fn test_tenants_only_see_their_own_tasks(db_pool: PgPool) {
+        let app = create_tasks_router(test_state(db_pool));
+        let (status, _) = send(
+            &app,
+            "POST",
+            "/tasks",
+            Some("team-a"),
+            json!({ "name": "a's task" }),
+        )
+        .await;
+        assert_eq!(status, StatusCode::CREATED);
+        let (_, tasks) = send(&app, "GET", "/tasks", Some("team-a"), Value::Null).await;
+        let task_id = tasks[0]["task_id"].as_i64().unwrap();
+        let uri = format!("/tasks/{task_id}");
+
+        let (status, tasks) = send(&app, "GET", "/tasks", Some("team-b"), Value::Null).await;
+        assert_eq!((status, tasks), (StatusCode::OK, json!([])));
+        let (status, _) = send(&app, "GET", &uri, Some("team-b"), Value::Null).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _) = send(
+            &app,
+            "PATCH",
+            &uri,
+            Some("team-b"),
+            json!({ "name": "stolen" }),
+        )
+        .await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _) = send(&app, "DELETE", &uri, Some("team-b"), Value::Null).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let (status, task) = send(&app, "GET", &uri, Some("team-a"), Value::Null).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(task["name"], "a's task");
+
+        let (status, _) = send(&app, "GET", "/tasks", None, Value::Null).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[sqlx::test]
+    async fn test_debug_routes_only_when_enabled(db_pool: PgPool) {
+        let app = create_tasks_router(test_state(db_pool.clone()));
+        let (status, _) = send(&app, "GET", "/debug/echo", None, Value::Null).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let app = create_tasks_router(AppState {
+            debug_routes: true,
+            ..test_state(db_pool)
+        });
+        let (status, echo) = send(&app, "POST", "/debug/echo", None, json!({ "a": 1 })).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(echo["body"]["text"], r#"{"a":1}"#);
+    }
+
+    async fn get_with_headers(app: &Router, uri: &str) -> axum::http::HeaderMap {
+        let request = Request::builder()
+            .uri(uri)
+            .header(tenant::TENANT_HEADER, "team-a")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        response.headers().clone()
+    }
+
+    #[sqlx::test]
+    async fn test_task_reads_are_cached_until_written(db_pool: PgPool) {
+        let state = test_state(db_pool);
+        let task_cache = state.task_cache.clone();
+        let app = create_tasks_router(state);
+        send(
+            &app,
+            "POST",
+            "/tasks",
+            Some("team-a"),
+            json!({ "name": "cached" }),
+        )
+        .await;
+        let (_, tasks) = send(&app, "GET", "/tasks", Some("team-a"), Value::Null).await;
+        let uri = format!("/tasks/{}", tasks[0]["task_id"]);
+
+        let headers = get_with_headers(&app, &uri).await;
+        assert_eq!(headers[CACHE_CONTROL], "private, no-cache");
+        assert_eq!(headers[AGE], "0");
+        get_with_headers(&app, &uri).await;
+        get_with_headers(&app, "/tasks").await;
+        let stats = task_cache.stats();
+        assert_eq!((stats.hits, stats.misses), (2, 2));
+
+        send(
+            &app,
+            "PATCH",
+            &uri,
+            Some("team-a"),
+            json!({ "name": "renamed" }),
+        )
+        .await;
+        let (_, task) = send(&app, "GET", &uri, Some("team-a"), Value::Null).await;
+        assert_eq!(task["name"], "renamed");
+        let (_, tasks) = send(&app, "GET", "/tasks", Some("team-a"), Value::Null).await;
+        assert_eq!(tasks[0]["name"], "renamed");
+
+        let (status, stats) = send(&app, "GET", "/cache/stats", None, Value::Null).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(stats["misses"], 4);
+    }
+
+    #[sqlx::test]
+    async fn test_notify_from_other_instances_invalidates(db_pool: PgPool) {
+        let state = test_state(db_pool.clone());
+        let task_cache = state.task_cache.clone();
+        cache::spawn_listener(db_pool.clone(), task_cache.clone());
+        let tenant = Tenant::new("team-a").unwrap();
+        let row = tasks::insert_task(&db_pool, &tenant, &new_task("shared"))
+            .await
+            .unwrap();
+
+        // another instance writing the task, this cache only hears about it through NOTIFY.
+        // retried because the first writes can happen before the listener is subscribed
+        for _ in 0..100 {
+            task_cache.put_task(
+                &tenant,
+                row.task_id,
+                Some(row.clone()),
+                task_cache.generation(),
+            );
+            let update = UpdateTaskReq {
+                name: Some("renamed elsewhere".to_owned()),
+                ..Default::default()
+            };
+            tasks::update_task(&db_pool, &tenant, row.task_id, &update)
+                .await
+                .unwrap();
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            if task_cache.task(&tenant, row.task_id).is_none() {
+                return;
+            }
+        }
+        panic!("cache was never invalidated by NOTIFY");
+    }
+
+    // the black-box tests every task store passes, each runs as test_x::postgres
+    // and, with the sqlite feature, as test_x::sqlite
+    macro_rules! store_tests {
+        ($($test:ident),* $(,)?) => {$(
+            mod $test {
+                use super::*;
+
+                #[sqlx::test]
+                async fn postgres(db_pool: PgPool) {
+                    super::$test(TestServer::start(db_pool).await).await;
+                }
+
+                #[cfg(feature = "sqlite")]
+                #[tokio::test]
+                async fn sqlite() {
+                    super::$test(TestServer::start_sqlite().await).await;
+                }
+            }
+        )*};
+    }
+
+    store_tests!(
+        test_create_and_get_task,
+        test_rejects_bad_requests,
+        test_list_tasks,
+        test_list_tasks_pages_and_filters,
+        test_name_filter_is_literal,
+        test_client_sdk,
+        test_update_task,
+        test_delete_task,
+    );
+
+    fn failure(status: StatusCode, message: &str) -> ApiFailure {
+        ApiFailure {
+            status,
+            message: message.to_owned(),
+        }
+    }
+
+    async fn test_create_and_get_task(server: TestServer) {
+        let client = server.client("team-a");
+        let due_at = Utc.with_ymd_and_hms(2030, 1, 2, 3, 4, 5).unwrap();
+
+        let created = client
+            .create_task(&CreateTaskReq {
+                priority: Some(2),
+                due_at: Some(due_at),
+                ..new_task("write tests")
+            })
+            .await
+            .unwrap();
+        assert_eq!(created.name, "write tests");
+        assert_eq!(created.priority, Some(2));
+        assert_eq!(created.due_at, Some(due_at));
+        assert!(!created.done);
+        assert_eq!(client.get_task(created.task_id).await, Ok(created.clone()));
+
+        assert_eq!(
+            client.get_task(created.task_id + 1).await,
+            Err(failure(StatusCode::NOT_FOUND, "Task not found"))
+        );
+        assert_eq!(
+            client.create_task(&new_subtask("orphan", 999_999)).await,
+            Err(failure(StatusCode::BAD_REQUEST, "Parent task not found"))
+        );
+    }
+
+    async fn test_rejects_bad_requests(server: TestServer) {
+        let client = server.client("team-a");
+
+        let missing_name = client
+            .request(Method::POST, "/tasks")
+            .json(&json!({ "priority": 1 }));
+        let result = test_harness::send(missing_name).await;
+        assert_eq!(result.unwrap_err().status, StatusCode::UNPROCESSABLE_ENTITY);
+        let malformed = client
+            .request(Method::POST, "/tasks")
+            .header("content-type", "application/json")
+            .body("{");
+        let result = test_harness::send(malformed).await;
+        assert_eq!(result.unwrap_err().status, StatusCode::BAD_REQUEST);
+        let result = test_harness::send(client.request(Method::GET, "/tasks/abc")).await;
+        assert_eq!(result.unwrap_err().status, StatusCode::BAD_REQUEST);
+
+        let invalid_tenant = server.client("not a tenant!");
+        assert_eq!(
+            invalid_tenant.list_tasks().await.unwrap_err().status,
+            StatusCode::BAD_REQUEST
+        );
+    }
+
+    async fn test_list_tasks(server: TestServer) {
+        let client = server.client("team-a");
+        assert_eq!(client.list_tasks().await, Ok(vec![]));
+
+        let created = client.create_tasks("chore", 3).await;
+        assert_eq!(client.list_tasks().await, Ok(created));
+        assert_eq!(server.client("team-b").list_tasks().await, Ok(vec![]));
+    }
+
+    async fn test_list_tasks_pages_and_filters(server: TestServer) {
+        let created = server.client("team-a").create_tasks("chore", 5).await;
+        let client = server.client("team-a");
+
+        let page = ListTasksQuery {
+            after: Some(created[1].task_id),
+            limit: Some(2),
+            ..Default::default()
+        };
+        let rows: Vec<TaskRow> = client
+            .json(client.request(Method::GET, "/tasks").query(&page))
+            .await
+            .unwrap();
+        assert_eq!(rows, created[2..4]);
+        let filtered = ListTasksQuery {
+            name_contains: Some("chore 5".to_owned()),
+            ..Default::default()
+        };
+        let rows: Vec<TaskRow> = client
+            .json(client.request(Method::GET, "/tasks").query(&filtered))
+            .await
+            .unwrap();
+        assert_eq!(rows, created[4..]);
+
+        let negative = client.request(Method::GET, "/tasks?limit=-1");
+        let result = test_harness::send(negative).await;
+        assert_eq!(result.unwrap_err().status, StatusCode::BAD_REQUEST);
+    }
+
+    // the same on both stores: `%`, `_` and `\` in the filter only match themselves
+    async fn test_name_filter_is_literal(server: TestServer) {
+        let client = server.client("team-a");
+        for name in ["a_b", "axb", "50%", "50 items", r"back\slash"] {
+            client.create_task(&new_task(name)).await.unwrap();
+        }
+
+        for (contains, expected) in [("_", "a_b"), ("50%", "50%"), (r"\", r"back\slash")] {
+            let filtered = ListTasksQuery {
+                name_contains: Some(contains.to_owned()),
+                ..Default::default()
+            };
+            let rows: Vec<TaskRow> = client
+                .json(client.request(Method::GET, "/tasks").query(&filtered))
+                .await
+                .unwrap();
+            let names: Vec<&str> = rows.iter().map(|row| row.name.as_str()).collect();
+            assert_eq!(names, [expected], "{contains}");
+        }
+    }
+
+    async fn test_client_sdk(server: TestServer) {
+        let client = tasks_client::TasksClient::new(
+            server.base_url(),
+            tasks_client::Auth::Tenant("team-a".to_owned()),
+        )
+        .unwrap();
+
+        let parent = client.create_task(&new_task("parent")).await.unwrap();
+        let child = client
+            .create_task(&new_subtask("child", parent.task_id))
+            .await
+            .unwrap();
+        let done = UpdateTaskReq {
+            done: Some(true),
+            ..Default::default()
+        };
+        client.update_task(child.task_id, &done).await.unwrap();
+        assert!(client.get_task(child.task_id).await.unwrap().done);
+        assert!(matches!(
+            client.delete_task(parent.task_id).await,
+            Err(tasks_client::ClientError::Conflict(_))
+        ));
+        client.delete_task(child.task_id).await.unwrap();
+        assert!(matches!(
+            client.get_task(child.task_id).await,
+            Err(tasks_client::ClientError::NotFound(message)) if message == "Task not found"
+        ));
+
+        for n in 1..=4 {
+            client
+                .create_task(&new_task(&format!("chore {n}")))
+                .await
+                .unwrap();
+        }
+        let query = ListTasksQuery {
+            name_contains: Some("chore".to_owned()),
+            limit: Some(3),
+            ..Default::default()
+        };
+        let tasks: Vec<TaskRow> = client.tasks(query).try_collect().await.unwrap();
+        let names: Vec<&str> = tasks.iter().map(|task| task.name.as_str()).collect();
+        assert_eq!(names, ["chore 1", "chore 2", "chore 3", "chore 4"]);
+    }
+
+    #[sqlx::test]
+    async fn test_session_token_with_replica(db_pool: PgPool) {
+        // the replica is the same database, so it has always replayed the writes
+        let db_pools = DbPools::new(db_pool.clone(), Some(db_pool.clone()));
+        db_pools.check_replica().await;
+        let app = create_tasks_router(AppState {
+            task_store: Arc::new(PgTaskStore::new(db_pools.clone())),
+            db_pools,
+            ..test_state(db_pool)
+        });
+
+        let request = Request::builder()
+            .method("POST")
+            .uri("/tasks")
+            .header("content-type", "application/json")
+            .header(tenant::TENANT_HEADER, "team-a")
+            .body(Body::from(json!({ "name": "replicated" }).to_string()))
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let token = response.headers()[replica::SESSION_TOKEN_HEADER].clone();
+
+        let read = |token: &str| {
+            Request::builder()
+                .uri("/tasks")
+                .header(tenant::TENANT_HEADER, "team-a")
+                .header(replica::SESSION_TOKEN_HEADER, token)
+                .body(Body::empty())
+                .unwrap()
+        };
+        let response = app
+            .clone()
+            .oneshot(read(token.to_str().unwrap()))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.collect().await.unwrap().to_bytes();
+        let tasks: Vec<TaskRow> = serde_json::from_slice(&body).unwrap();
+        assert_eq!(tasks[0].name, "replicated");
+
+        let response = app.oneshot(read("not an lsn")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    async fn test_update_task(server: TestServer) {
+        let client = server.client("team-a");
+        let parent = client.create_task(&new_task("parent")).await.unwrap();
+        let child = client
+            .create_task(&CreateTaskReq {
+                priority: Some(1),
+                ..new_subtask("child", parent.task_id)
+            })
+            .await
+            .unwrap();
+
+        let update = UpdateTaskReq {
+            name: Some("renamed".to_owned()),
+            done: Some(true),
+            ..Default::default()
+        };
+        client.update_task(child.task_id, &update).await.unwrap();
+        let updated = client.get_task(child.task_id).await.unwrap();
+        // fields left out of the request keep their value
+        assert_eq!(
+            updated,
+            TaskRow {
+                name: "renamed".to_owned(),
+                done: true,
+                ..child.clone()
+            }
+        );
+
+        let detach = UpdateTaskReq {
+            parent_id: Some(None),
+            ..Default::default()
+        };
+        client.update_task(child.task_id, &detach).await.unwrap();
+        assert_eq!(
+            client.get_task(child.task_id).await.unwrap().parent_id,
+            None
+        );
+
+        let cycle = UpdateTaskReq {
+            parent_id: Some(Some(parent.task_id)),
+            ..Default::default()
+        };
+        assert_eq!(
+            client
+                .update_task(parent.task_id, &cycle)
+                .await
+                .unwrap_err()
+                .status,
+            StatusCode::CONFLICT
+        );
+        assert_eq!(
+            client.update_task(999_999, &update).await,
+            Err(failure(StatusCode::NOT_FOUND, "Task not found"))
+        );
+    }
+
+    async fn test_delete_task(server: TestServer) {
+        let client = server.client("team-a");
+        let parent = client.create_task(&new_task("parent")).await.unwrap();
+        let child = client
+            .create_task(&new_subtask("child", parent.task_id))
+            .await
+            .unwrap();
+
+        assert_eq!(
+            client.delete_task(parent.task_id).await.unwrap_err().status,
+            StatusCode::CONFLICT
+        );
+        client.delete_task(child.task_id).await.unwrap();
+        client.delete_task(parent.task_id).await.unwrap();
+        assert_eq!(
+            client.delete_task(parent.task_id).await,
+            Err(failure(StatusCode::NOT_FOUND, "Task not found"))
+        );
+        assert_eq!(client.list_tasks().await, Ok(vec![]));
+    }
+
+    #[sqlx::test]
+    async fn test_tree_and_blockers(db_pool: PgPool) {
+        let server = TestServer::start(db_pool).await;
+        let client = server.client("team-a");
+        let [release, build, docs] = <[TaskRow; 3]>::try_from(client.create_tasks("step", 3).await)
+            .unwrap()
+            .map(|task| task.task_id);
+        let tests = client
+            .create_task(&new_subtask("tests", build))
+            .await
+            .unwrap();
+
+        client.add_blocker(release, build).await.unwrap();
+        client.add_blocker(release, docs).await.unwrap();
+        assert_eq!(
+            client.add_blocker(build, release).await.unwrap_err().status,
+            StatusCode::CONFLICT
+        );
+        assert_eq!(
+            client.add_blocker(release, 999_999).await,
+            Err(failure(StatusCode::NOT_FOUND, "Task not found"))
+        );
+
+        let tree = client.task_tree(build).await.unwrap();
+        assert_eq!(tree.task.task_id, build);
+        assert_eq!(tree.subtasks.len(), 1);
+        assert_eq!(tree.subtasks[0].task, tests);
+        let mut blocked_by = client.task_tree(release).await.unwrap().blocked_by;
+        blocked_by.sort();
+        assert_eq!(blocked_by, vec![build, docs]);
+        assert_eq!(
+            client.task_tree(999_999).await.unwrap_err().status,
+            StatusCode::NOT_FOUND
+        );
+
+        let ready: Vec<i32> = client
+            .ready_tasks()
+            .await
+            .unwrap()
+            .iter()
+            .map(|task| task.task_id)
+            .collect();
+        assert!(!ready.contains(&release));
 
-        // TODO how this tuple has into_response?
-        (status, body).into_response()
+        client.remove_blocker(release, docs).await.unwrap();
+        assert_eq!(
+            client.remove_blocker(release, docs).await,
+            Err(failure(StatusCode::NOT_FOUND, "Dependency not found"))
+        );
+        assert_eq!(
+            client.task_tree(release).await.unwrap().blocked_by,
+            vec![build]
+        );
     }
 }
@@ -0,0 +1,357 @@
+use crate::api_error;
+use axum::extract::FromRequestParts;
+use axum::http::request::Parts;
+use axum::http::{HeaderMap, HeaderValue, StatusCode};
+use sqlx::PgPool;
+use sqlx::postgres::PgPoolOptions;
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Duration;
+
+// writes go to the primary, GET /tasks and GET /tasks/{id} to the replica when there is one.
+// a write answers with the WAL position of the primary as a session token, a read sending it back
+// only goes to the replica once the replica has replayed up to there
+
+pub const SESSION_TOKEN_HEADER: &str = "x-session-token";
+
+const DEFAULT_HEALTH_INTERVAL: Duration = Duration::from_secs(5);
+const HEALTH_TIMEOUT: Duration = Duration::from_secs(2);
+// the replay position on a standby, the primary's own position when the replica is not one (tests)
+const REPLAY_LSN_QUERY: &str = "SELECT (CASE WHEN pg_is_in_recovery() THEN pg_last_wal_replay_lsn()
+                                ELSE pg_current_wal_lsn() END)::text";
+
+#[derive(Clone)]
+pub struct ReplicaConfig {
+    pub url: String,
+    pub health_interval: Duration,
+}
+
+impl ReplicaConfig {
+    /// Reads `DATABASE_REPLICA_URL` and `REPLICA_HEALTH_INTERVAL_SECS`, `None` without a replica.
+    pub fn from_env() -> Option<Self> {
+        Some(ReplicaConfig {
+            url: std::env::var("DATABASE_REPLICA_URL").ok()?,
+            health_interval: std::env::var("REPLICA_HEALTH_INTERVAL_SECS")
+                .ok()
+                .and_then(|secs| secs.parse().ok())
+                .map_or(DEFAULT_HEALTH_INTERVAL, Duration::from_secs),
+        })
+    }
+}
+
+/// The pool of the replica, not connected until the first check. It gives up on a connection as
+/// soon as a health check would, so a silent replica does not hold up reads for long.
+pub fn replica_pool(url: &str) -> Result<PgPool, sqlx::Error> {
+    PgPoolOptions::new()
+        .max_connections(16)
+        .acquire_timeout(HEALTH_TIMEOUT)
+        .connect_lazy(url)
+}
+
+/// A position in the WAL, Postgres writes it as two hex halves: `16/B374D848`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Lsn(u64);
+
+impl FromStr for Lsn {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (high, low) = s.split_once('/').ok_or(())?;
+        let high = u32::from_str_radix(high, 16).map_err(|_| ())?;
+        let low = u32::from_str_radix(low, 16).map_err(|_| ())?;
+        Ok(Lsn((u64::from(high) << 32) | u64::from(low)))
+    }
+}
+
+impl fmt::Display for Lsn {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:X}/{:X}", self.0 >> 32, self.0 as u32)
+    }
+}
+
+/// The token a read sends back in `X-Session-Token`, the position of the last write it has to see.
+pub struct SessionToken(pub Option<Lsn>);
+
+impl<S: Send + Sync> FromRequestParts<S> for SessionToken {
+    type Rejection = (StatusCode, String);
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let Some(value) = parts.headers.get(SESSION_TOKEN_HEADER) else {
+            return Ok(SessionToken(None));
+        };
+        value
+            .to_str()
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .map(|lsn| SessionToken(Some(lsn)))
+            .ok_or_else(|| api_error(StatusCode::BAD_REQUEST, "Invalid session token"))
+    }
+}
+
+struct Replica {
+    pool: PgPool,
+    healthy: AtomicBool,
+    // last replay position seen, 0 until the first check
+    replayed: AtomicU64,
+}
+
+/// The primary pool and, optionally, a replica pool for reads.
+#[derive(Clone)]
+pub struct DbPools {
+    primary: PgPool,
+    replica: Option<Arc<Replica>>,
+}
+
+impl DbPools {
+    pub fn new(primary: PgPool, replica: Option<PgPool>) -> Self {
+        DbPools {
+            primary,
+            replica: replica.map(|pool| {
+                Arc::new(Replica {
+                    pool,
+                    healthy: AtomicBool::new(false),
+                    replayed: AtomicU64::new(0),
+                })
+            }),
+        }
+    }
+
+    pub fn primary(&self) -> &PgPool {
+        &self.primary
+    }
+
+    /// Runs the read on the replica when it is healthy and has replayed `token`, on the primary
+    /// otherwise. A replica whose connection fails is marked down and the read runs on the primary.
+    pub async fn read<T, F, Fut>(&self, token: Option<Lsn>, read: F) -> Result<T, sqlx::Error>
+    where
+        F: Fn(PgPool) -> Fut,
+        Fut: Future<Output = Result<T, sqlx::Error>>,
+    {
+        if let Some(replica) = self.replica_for(token).await {
+            match answered_in_time(read(replica.pool.clone())).await {
+                Err(e) if is_connection_error(&e) => {
+                    eprintln!("Replica failed, reading from the primary: {e}");
+                    replica.healthy.store(false, Ordering::Relaxed);
+                }
+                result => return result,
+            }
+        }
+
+        read(self.primary.clone()).await
+    }
+
+    /// The token for a response to a write, `None` without a replica as reads all go to the primary.
+    pub async fn session_token(&self) -> Result<Option<Lsn>, sqlx::Error> {
+        if self.replica.is_none() {
+            return Ok(None);
+        }
+        let lsn: String = sqlx::query_scalar("SELECT pg_current_wal_lsn()::text")
+            .fetch_one(&self.primary)
+            .await?;
+
+        Ok(lsn.parse().ok())
+    }
+
+    /// `X-Session-Token` for the response to a write.
+    pub async fn session_headers(&self) -> Result<HeaderMap, sqlx::Error> {
+        let mut headers = HeaderMap::new();
+        if let Some(lsn) = self.session_token().await? {
+            let value = HeaderValue::from_str(&lsn.to_string()).expect("an LSN is ASCII");
+            headers.insert(SESSION_TOKEN_HEADER, value);
+        }
+
+        Ok(headers)
+    }
+
+    async fn replica_for(&self, token: Option<Lsn>) -> Option<&Replica> {
+        let replica = self.replica.as_deref()?;
+        if !replica.healthy.load(Ordering::Relaxed) {
+            return None;
+        }
+        let Some(token) = token else {
+            return Some(replica);
+        };
+        if Lsn(replica.replayed.load(Ordering::Relaxed)) >= token {
+            return Some(replica);
+        }
+
+        // the last check may be behind the replica, ask again before giving up on it
+        match answered_in_time(replay_lsn(&replica.pool)).await {
+            Ok(replayed) => {
+                replica.replayed.fetch_max(replayed.0, Ordering::Relaxed);
+                (replayed >= token).then_some(replica)
+            }
+            Err(e) => {
+                eprintln!("Replica failed, reading from the primary: {e}");
+                replica.healthy.store(false, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+
+    /// Marks the replica up or down, and records how far it has replayed.
+    pub async fn check_replica(&self) {
+        let Some(replica) = self.replica.as_deref() else {
+            return;
+        };
+        let replayed = tokio::time::timeout(HEALTH_TIMEOUT, replay_lsn(&replica.pool)).await;
+        match replayed {
+            Ok(Ok(replayed)) => {
+                replica.replayed.fetch_max(replayed.0, Ordering::Relaxed);
+                replica.healthy.store(true, Ordering::Relaxed);
+            }
+            Ok(Err(e)) => {
+                if replica.healthy.swap(false, Ordering::Relaxed) {
+                    eprintln!("Replica is down, reading from the primary: {e}");
+                }
+            }
+            Err(_) => {
+                if replica.healthy.swap(false, Ordering::Relaxed) {
+                    eprintln!("Replica is not answering, reading from the primary");
+                }
+            }
+        }
+    }
+}
+
+async fn replay_lsn(pool: &PgPool) -> Result<Lsn, sqlx::Error> {
+    let lsn: Option<String> = sqlx::query_scalar(REPLAY_LSN_QUERY).fetch_one(pool).await?;
+    // no replay position yet, as good as behind everything
+    Ok(lsn.and_then(|lsn| lsn.parse().ok()).unwrap_or(Lsn(0)))
+}
+
+// a replica dropping packets answers nothing rather than an error, waiting on it longer than a
+// health check does is failed like a broken connection
+async fn answered_in_time<T>(
+    query: impl Future<Output = Result<T, sqlx::Error>>,
+) -> Result<T, sqlx::Error> {
+    tokio::time::timeout(HEALTH_TIMEOUT, query)
+        .await
+        .unwrap_or_else(|_| {
+            Err(sqlx::Error::Io(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "replica did not answer in time",
+            )))
+        })
+}
+
+fn is_connection_error(e: &sqlx::Error) -> bool {
+    matches!(
+        e,
+        sqlx::Error::Io(_)
+            | sqlx::Error::Tls(_)
+            | sqlx::Error::PoolTimedOut
+            | sqlx::Error::PoolClosed
+            | sqlx::Error::WorkerCrashed
+    )
+}
+
+/// Checks the replica every `interval`, the first time right away.
+pub fn spawn_health_checks(db_pools: DbPools, interval: Duration) {
+    tokio::spawn(async move {
+        let mut ticks = tokio::time::interval(interval);
+        loop {
+            ticks.tick().await;
+            db_pools.check_replica().await;
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn test_lsn_parse_and_order() {
+        let lsn: Lsn = "16/B374D848".parse().unwrap();
+        assert_eq!(lsn, Lsn(0x16_B374_D848));
+        assert_eq!(lsn.to_string(), "16/B374D848");
+        assert!("0/FFFFFFFF".parse::<Lsn>().unwrap() < "1/0".parse().unwrap());
+        assert!("16".parse::<Lsn>().is_err());
+        assert!("x/1".parse::<Lsn>().is_err());
+    }
+
+    fn unreachable_pool() -> PgPool {
+        PgPoolOptions::new()
+            .acquire_timeout(Duration::from_millis(200))
+            .connect_lazy("postgres://postgres@127.0.0.1:1/unused")
+            .unwrap()
+    }
+
+    #[sqlx::test]
+    async fn test_routes_reads_by_token(db_pool: PgPool) {
+        // the same database as primary and replica, the replica is never behind it
+        let db_pools = DbPools::new(db_pool.clone(), Some(db_pool.clone()));
+        assert!(db_pools.replica_for(None).await.is_none());
+        db_pools.check_replica().await;
+        assert!(db_pools.replica_for(None).await.is_some());
+
+        let token = db_pools.session_token().await.unwrap().unwrap();
+        assert!(db_pools.replica_for(Some(token)).await.is_some());
+        // a write the replica has not replayed yet
+        let ahead = Lsn(token.0 + (1 << 40));
+        assert!(db_pools.replica_for(Some(ahead)).await.is_none());
+
+        let no_replica = DbPools::new(db_pool, None);
+        assert_eq!(no_replica.session_token().await.unwrap(), None);
+        assert!(no_replica.session_headers().await.unwrap().is_empty());
+    }
+
+    #[sqlx::test]
+    async fn test_fails_over_to_primary(db_pool: PgPool) {
+        let db_pools = DbPools::new(db_pool, Some(unreachable_pool()));
+        db_pools.check_replica().await;
+        assert!(db_pools.replica_for(None).await.is_none());
+
+        // marked up, the first read finds it down and still answers from the primary
+        let replica = db_pools.replica.as_deref().unwrap();
+        replica.healthy.store(true, Ordering::Relaxed);
+        let one: i32 = db_pools
+            .read(None, |pool| async move {
+                sqlx::query_scalar("SELECT 1").fetch_one(&pool).await
+            })
+            .await
+            .unwrap();
+        assert_eq!(one, 1);
+        assert!(!replica.healthy.load(Ordering::Relaxed));
+    }
+
+    #[sqlx::test]
+    async fn test_fails_over_quickly_from_a_silent_replica(db_pool: PgPool) {
+        // takes connections and never answers, like a host dropping packets after the check
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!(
+            "postgres://postgres@{}/unused",
+            listener.local_addr().unwrap()
+        );
+        tokio::spawn(async move {
+            let mut silent = Vec::new();
+            while let Ok((stream, _)) = listener.accept().await {
+                silent.push(stream);
+            }
+        });
+        let db_pools = DbPools::new(db_pool, Some(replica_pool(&url).unwrap()));
+        let replica = db_pools.replica.as_deref().unwrap();
+
+        let read = |token| {
+            replica.healthy.store(true, Ordering::Relaxed);
+            db_pools.read(token, |pool| async move {
+                sqlx::query_scalar::<_, i32>("SELECT 1")
+                    .fetch_one(&pool)
+                    .await
+            })
+        };
+        // the read itself, then the replay position asked for a token
+        for token in [None, Some(Lsn(1))] {
+            let start = Instant::now();
+            assert_eq!(read(token).await.unwrap(), 1);
+            assert!(start.elapsed() < HEALTH_TIMEOUT * 2);
+            assert!(!replica.healthy.load(Ordering::Relaxed));
+        }
+    }
+}
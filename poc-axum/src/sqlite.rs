@@ -0,0 +1,286 @@
+use crate::replica::Lsn;
+use crate::store::TaskStore;
+use crate::tasks::{self, CreateTaskReq, TaskError, TaskFilter, TaskRow, UpdateTaskReq};
+use crate::tenant::Tenant;
+use async_trait::async_trait;
+use sqlx::sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions};
+use sqlx::{QueryBuilder, Sqlite, SqlitePool};
+use std::str::FromStr;
+
+// the task store for DATABASE_URL=sqlite:..., built with the `sqlite` feature.
+// the same queries as tasks.rs, tenant_id is filtered on in each one as SQLite has no row level
+// security. writes do not schedule reminders, deliver webhooks or NOTIFY, those need Postgres
+
+const TASK_COLUMNS: &str = "task_id, name, priority, due_at, parent_id, done";
+
+/// Opens the database, creating the file if needed. An in-memory database lives in its one
+/// connection, so its pool never opens a second one nor closes the first.
+pub async fn connect(database_url: &str) -> Result<SqlitePool, sqlx::Error> {
+    let options = SqliteConnectOptions::from_str(database_url)?
+        .create_if_missing(true)
+        .journal_mode(SqliteJournalMode::Wal);
+    let pool_options = if database_url.contains(":memory:") || database_url.contains("mode=memory")
+    {
+        SqlitePoolOptions::new()
+            .max_connections(1)
+            .idle_timeout(None)
+            .max_lifetime(None)
+    } else {
+        SqlitePoolOptions::new().max_connections(16)
+    };
+
+    pool_options.connect_with(options).await
+}
+
+/// Runs migrations/sqlite, the SQLite version of the tasks table of migrations/.
+pub async fn migrate(pool: &SqlitePool) -> Result<(), sqlx::migrate::MigrateError> {
+    sqlx::migrate!("./migrations/sqlite").run(pool).await
+}
+
+fn violates_foreign_key(e: &sqlx::Error) -> bool {
+    e.as_database_error()
+        .is_some_and(|e| e.is_foreign_key_violation())
+}
+
+pub struct SqliteTaskStore {
+    pool: SqlitePool,
+}
+
+impl SqliteTaskStore {
+    pub fn new(pool: SqlitePool) -> Self {
+        SqliteTaskStore { pool }
+    }
+}
+
+#[async_trait]
+impl TaskStore for SqliteTaskStore {
+    // there is no replica, every read sees every write
+    async fn list_tasks(
+        &self,
+        tenant: &Tenant,
+        filter: &TaskFilter,
+        after: Option<i32>,
+        limit: Option<i64>,
+        _token: Option<Lsn>,
+    ) -> Result<Vec<TaskRow>, sqlx::Error> {
+        let mut query: QueryBuilder<Sqlite> = QueryBuilder::new(format!(
+            "SELECT {TASK_COLUMNS} FROM tasks WHERE tenant_id = "
+        ));
+        query.push_bind(tenant.id());
+        if let Some(name) = &filter.name_contains {
+            // LIKE ignores the case of ASCII letters, like ILIKE
+            query
+                .push(" AND name LIKE ")
+                .push_bind(tasks::contains_pattern(name))
+                .push(" ESCAPE '\\'");
+        }
+        if let Some(min) = filter.min_priority {
+            query.push(" AND priority >= ").push_bind(min);
+        }
+        if let Some(max) = filter.max_priority {
+            query.push(" AND priority <= ").push_bind(max);
+        }
+        if let Some(after) = after {
+            query.push(" AND task_id > ").push_bind(after);
+        }
+        query.push(" ORDER BY task_id");
+        if let Some(limit) = limit {
+            query.push(" LIMIT ").push_bind(limit);
+        }
+
+        query.build_query_as().fetch_all(&self.pool).await
+    }
+
+    async fn find_task(
+        &self,
+        tenant: &Tenant,
+        task_id: i32,
+        _token: Option<Lsn>,
+    ) -> Result<Option<TaskRow>, sqlx::Error> {
+        sqlx::query_as(&format!(
+            "SELECT {TASK_COLUMNS} FROM tasks WHERE tenant_id = ? AND task_id = ?"
+        ))
+        .bind(tenant.id())
+        .bind(task_id)
+        .fetch_optional(&self.pool)
+        .await
+    }
+
+    async fn insert_task(
+        &self,
+        tenant: &Tenant,
+        task: &CreateTaskReq,
+    ) -> Result<TaskRow, TaskError> {
+        sqlx::query_as(&format!(
+            "INSERT INTO tasks (tenant_id, name, priority, due_at, parent_id) VALUES (?, ?, ?, ?, ?)
+             RETURNING {TASK_COLUMNS}"
+        ))
+        .bind(tenant.id())
+        .bind(&task.name)
+        .bind(task.priority)
+        .bind(task.due_at)
+        .bind(task.parent_id)
+        .fetch_one(&self.pool)
+        .await
+        .map_err(|e| {
+            if violates_foreign_key(&e) {
+                return TaskError::ParentNotFound;
+            }
+            e.into()
+        })
+    }
+
+    async fn update_task(
+        &self,
+        tenant: &Tenant,
+        task_id: i32,
+        task: &UpdateTaskReq,
+    ) -> Result<Option<TaskRow>, TaskError> {
+        // takes the write lock up front, the cycle check then sees the hierarchy the update changes
+        let mut tx = self.pool.begin_with("BEGIN IMMEDIATE").await?;
+        if let Some(Some(parent_id)) = task.parent_id
+            && is_ancestor(&mut tx, tenant, task_id, parent_id).await?
+        {
+            return Err(TaskError::ParentCycle);
+        }
+
+        let mut query: QueryBuilder<Sqlite> = QueryBuilder::new("UPDATE tasks SET task_id = ");
+        query.push_bind(task_id);
+        if let Some(name) = &task.name {
+            query.push(", name = ").push_bind(name);
+        }
+        if let Some(priority) = task.priority {
+            query.push(", priority = ").push_bind(priority);
+        }
+        if let Some(due_at) = task.due_at {
+            query.push(", due_at = ").push_bind(due_at);
+        }
+        if let Some(parent_id) = task.parent_id {
+            query.push(", parent_id = ").push_bind(parent_id);
+        }
+        if let Some(done) = task.done {
+            query.push(", done = ").push_bind(done);
+        }
+        query
+            .push(" WHERE tenant_id = ")
+            .push_bind(tenant.id())
+            .push(" AND task_id = ")
+            .push_bind(task_id)
+            .push(format!(" RETURNING {TASK_COLUMNS}"));
+
+        let row: Option<TaskRow> = query
+            .build_query_as()
+            .fetch_optional(&mut *tx)
+            .await
+            .map_err(|e| {
+                if violates_foreign_key(&e) {
+                    return TaskError::ParentNotFound;
+                }
+                e.into()
+            })?;
+        tx.commit().await?;
+
+        Ok(row)
+    }
+
+    async fn delete_task(&self, tenant: &Tenant, task_id: i32) -> Result<bool, TaskError> {
+        let deleted: Option<i32> = sqlx::query_scalar(
+            "DELETE FROM tasks WHERE tenant_id = ? AND task_id = ? RETURNING task_id",
+        )
+        .bind(tenant.id())
+        .bind(task_id)
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(|e| {
+            if violates_foreign_key(&e) {
+                return TaskError::HasSubtasks;
+            }
+            e.into()
+        })?;
+
+        Ok(deleted.is_some())
+    }
+}
+
+/// Whether `ancestor_id` is `task_id` itself or one of its parents, grandparents...
+async fn is_ancestor(
+    tx: &mut sqlx::Transaction<'_, Sqlite>,
+    tenant: &Tenant,
+    ancestor_id: i32,
+    task_id: i32,
+) -> Result<bool, sqlx::Error> {
+    // UNION, not UNION ALL, so it ends even if the data already had a loop
+    sqlx::query_scalar(
+        "WITH RECURSIVE ancestors (task_id) AS (
+             SELECT ?1
+             UNION
+             SELECT t.parent_id FROM tasks t JOIN ancestors a ON t.task_id = a.task_id
+             WHERE t.tenant_id = ?2 AND t.parent_id IS NOT NULL
+         )
+         SELECT EXISTS (SELECT 1 FROM ancestors WHERE task_id = ?3)",
+    )
+    .bind(task_id)
+    .bind(tenant.id())
+    .bind(ancestor_id)
+    .fetch_one(&mut **tx)
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn store() -> SqliteTaskStore {
+        let pool = connect("sqlite::memory:").await.unwrap();
+        migrate(&pool).await.unwrap();
+        SqliteTaskStore::new(pool)
+    }
+
+    fn task(name: &str, parent_id: Option<i32>) -> CreateTaskReq {
+        CreateTaskReq {
+            name: name.to_owned(),
+            priority: None,
+            due_at: None,
+            parent_id,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_tenants_and_hierarchy() {
+        let store = store().await;
+        let (a, b) = (
+            Tenant::new("team-a").unwrap(),
+            Tenant::new("team-b").unwrap(),
+        );
+        let parent = store.insert_task(&a, &task("parent", None)).await.unwrap();
+        let child = store
+            .insert_task(&a, &task("child", Some(parent.task_id)))
+            .await
+            .unwrap();
+
+        // a parent of another tenant is as good as missing
+        let result = store
+            .insert_task(&b, &task("stray", Some(parent.task_id)))
+            .await;
+        assert!(matches!(result, Err(TaskError::ParentNotFound)));
+        assert_eq!(
+            store.find_task(&b, parent.task_id, None).await.unwrap(),
+            None
+        );
+        let filter = TaskFilter::default();
+        let rows = store.list_tasks(&b, &filter, None, None, None).await;
+        assert_eq!(rows.unwrap(), vec![]);
+        assert!(!store.delete_task(&b, child.task_id).await.unwrap());
+
+        let move_under = |parent_id| UpdateTaskReq {
+            parent_id: Some(Some(parent_id)),
+            ..Default::default()
+        };
+        let result = store
+            .update_task(&a, parent.task_id, &move_under(child.task_id))
+            .await;
+        assert!(matches!(result, Err(TaskError::ParentCycle)));
+        let result = store.delete_task(&a, parent.task_id).await;
+        assert!(matches!(result, Err(TaskError::HasSubtasks)));
+    }
+}
@@ -0,0 +1,124 @@
+use crate::replica::{DbPools, Lsn};
+use crate::tasks::{self, CreateTaskReq, TaskError, TaskFilter, TaskRow, UpdateTaskReq};
+use crate::tenant::Tenant;
+use async_trait::async_trait;
+use axum::http::HeaderMap;
+
+// the REST task routes go through a TaskStore, so they run on either database.
+// GraphQL, gRPC, jobs, webhooks, subtask trees and attachments stay on Postgres
+
+pub fn is_sqlite_url(database_url: &str) -> bool {
+    database_url.starts_with("sqlite:")
+}
+
+/// Tasks of a tenant, stored in Postgres or, with the `sqlite` feature, in SQLite.
+/// Both have the same semantics: keyset pages by id, partial updates, the written row returned.
+#[async_trait]
+pub trait TaskStore: Send + Sync {
+    /// Lists tasks ordered by id, starting after the `after` id.
+    /// `token` is the session token of the request, reads must see the write it stands for.
+    async fn list_tasks(
+        &self,
+        tenant: &Tenant,
+        filter: &TaskFilter,
+        after: Option<i32>,
+        limit: Option<i64>,
+        token: Option<Lsn>,
+    ) -> Result<Vec<TaskRow>, sqlx::Error>;
+
+    async fn find_task(
+        &self,
+        tenant: &Tenant,
+        task_id: i32,
+        token: Option<Lsn>,
+    ) -> Result<Option<TaskRow>, sqlx::Error>;
+
+    async fn insert_task(
+        &self,
+        tenant: &Tenant,
+        task: &CreateTaskReq,
+    ) -> Result<TaskRow, TaskError>;
+
+    /// Updates only the fields present in `task`, `None` when the task does not exist.
+    async fn update_task(
+        &self,
+        tenant: &Tenant,
+        task_id: i32,
+        task: &UpdateTaskReq,
+    ) -> Result<Option<TaskRow>, TaskError>;
+
+    /// Returns `false` when there was no task to delete.
+    async fn delete_task(&self, tenant: &Tenant, task_id: i32) -> Result<bool, TaskError>;
+
+    /// Headers for the response to a write, the session token when reads may lag behind it.
+    async fn session_headers(&self) -> Result<HeaderMap, sqlx::Error> {
+        Ok(HeaderMap::new())
+    }
+}
+
+/// The queries of [`tasks`], reads on the replica when there is one.
+pub struct PgTaskStore {
+    db_pools: DbPools,
+}
+
+impl PgTaskStore {
+    pub fn new(db_pools: DbPools) -> Self {
+        PgTaskStore { db_pools }
+    }
+}
+
+#[async_trait]
+impl TaskStore for PgTaskStore {
+    async fn list_tasks(
+        &self,
+        tenant: &Tenant,
+        filter: &TaskFilter,
+        after: Option<i32>,
+        limit: Option<i64>,
+        token: Option<Lsn>,
+    ) -> Result<Vec<TaskRow>, sqlx::Error> {
+        self.db_pools
+            .read(token, |pool| async move {
+                tasks::list_tasks(&pool, tenant, filter, after, limit).await
+            })
+            .await
+    }
+
+    async fn find_task(
+        &self,
+        tenant: &Tenant,
+        task_id: i32,
+        token: Option<Lsn>,
+    ) -> Result<Option<TaskRow>, sqlx::Error> {
+        self.db_pools
+            .read(token, |pool| async move {
+                tasks::find_task(&pool, tenant, task_id).await
+            })
+            .await
+    }
+
+    async fn insert_task(
+        &self,
+        tenant: &Tenant,
+        task: &CreateTaskReq,
+    ) -> Result<TaskRow, TaskError> {
+        tasks::insert_task(self.db_pools.primary(), tenant, task).await
+    }
+
+    async fn update_task(
+        &self,
+        tenant: &Tenant,
+        task_id: i32,
+        task: &UpdateTaskReq,
+    ) -> Result<Option<TaskRow>, TaskError> {
+        tasks::update_task(self.db_pools.primary(), tenant, task_id, task).await
+    }
+
+    async fn delete_task(&self, tenant: &Tenant, task_id: i32) -> Result<bool, TaskError> {
+        tasks::delete_task(self.db_pools.primary(), tenant, task_id).await
+    }
+
+    async fn session_headers(&self) -> Result<HeaderMap, sqlx::Error> {
+        self.db_pools.session_headers().await
+    }
+}
@@ -0,0 +1,297 @@
+use crate::cache::TASK_CHANGES_CHANNEL;
+use crate::hierarchy;
+use crate::jobs::{self, Job};
+use crate::tenant::{self, Tenant};
+use crate::webhooks;
+use sqlx::{PgPool, Postgres, QueryBuilder};
+use std::fmt;
+
+// the JSON bodies are defined next to the client, so both ends share them
+pub use tasks_client::{CreateTaskReq, ListTasksQuery, TaskRow, UpdateTaskReq};
+
+// queries over the tasks table, shared by the REST, GraphQL and gRPC handlers.
+// each one runs in a tenant transaction, row level security hides the tasks of other tenants
+
+const PARENT_FKEY: &str = "tasks_parent_fkey";
+
+#[derive(Default)]
+pub struct TaskFilter {
+    pub name_contains: Option<String>,
+    pub min_priority: Option<i32>,
+    pub max_priority: Option<i32>,
+}
+
+/// The LIKE pattern of names containing `text`, its `%`, `_` and `\` matching only themselves.
+/// Goes with `ESCAPE '\'`.
+pub fn contains_pattern(text: &str) -> String {
+    let mut pattern = String::with_capacity(text.len() + 2);
+    pattern.push('%');
+    for c in text.chars() {
+        if matches!(c, '\\' | '%' | '_') {
+            pattern.push('\\');
+        }
+        pattern.push(c);
+    }
+    pattern.push('%');
+    pattern
+}
+
+impl From<ListTasksQuery> for TaskFilter {
+    fn from(query: ListTasksQuery) -> Self {
+        TaskFilter {
+            name_contains: query.name_contains,
+            min_priority: query.min_priority,
+            max_priority: query.max_priority,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum TaskError {
+    ParentNotFound,
+    /// The new parent is the task itself or one of its subtasks.
+    ParentCycle,
+    HasSubtasks,
+    Database(sqlx::Error),
+}
+
+impl fmt::Display for TaskError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TaskError::ParentNotFound => write!(f, "Parent task not found"),
+            TaskError::ParentCycle => {
+                write!(
+                    f,
+                    "A task can not be a subtask of itself or of its subtasks"
+                )
+            }
+            TaskError::HasSubtasks => write!(f, "Task has subtasks, delete or move them first"),
+            TaskError::Database(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl From<sqlx::Error> for TaskError {
+    fn from(e: sqlx::Error) -> Self {
+        TaskError::Database(e)
+    }
+}
+
+// the parent foreign key failed: a missing parent on insert or update, remaining subtasks on delete
+fn violates_parent_fkey(e: &sqlx::Error) -> bool {
+    e.as_database_error()
+        .is_some_and(|e| e.constraint() == Some(PARENT_FKEY))
+}
+
+/// Lists tasks ordered by id, starting after the `after` id (keyset pagination).
+pub async fn list_tasks(
+    pg_pool: &PgPool,
+    tenant: &Tenant,
+    filter: &TaskFilter,
+    after: Option<i32>,
+    limit: Option<i64>,
+) -> Result<Vec<TaskRow>, sqlx::Error> {
+    let mut query: QueryBuilder<Postgres> = QueryBuilder::new(
+        "SELECT task_id, name, priority, due_at, parent_id, done FROM tasks WHERE TRUE",
+    );
+    if let Some(name) = &filter.name_contains {
+        query
+            .push(" AND name ILIKE ")
+            .push_bind(contains_pattern(name))
+            .push(" ESCAPE '\\'");
+    }
+    if let Some(min) = filter.min_priority {
+        query.push(" AND priority >= ").push_bind(min);
+    }
+    if let Some(max) = filter.max_priority {
+        query.push(" AND priority <= ").push_bind(max);
+    }
+    if let Some(after) = after {
+        query.push(" AND task_id > ").push_bind(after);
+    }
+    query.push(" ORDER BY task_id");
+    if let Some(limit) = limit {
+        query.push(" LIMIT ").push_bind(limit);
+    }
+
+    let mut tx = tenant::begin(pg_pool, tenant).await?;
+    let rows = query.build_query_as().fetch_all(&mut *tx).await?;
+    tx.commit().await?;
+
+    Ok(rows)
+}
+
+pub async fn find_task(
+    pg_pool: &PgPool,
+    tenant: &Tenant,
+    task_id: i32,
+) -> Result<Option<TaskRow>, sqlx::Error> {
+    let mut tx = tenant::begin(pg_pool, tenant).await?;
+    let row = sqlx::query_as!(
+        TaskRow,
+        "SELECT task_id, name, priority, due_at, parent_id, done FROM tasks WHERE task_id = $1",
+        task_id
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+    tx.commit().await?;
+
+    Ok(row)
+}
+
+/// Inserts the task and, when it has a due time, schedules its reminder in the same transaction.
+/// The tenant_id column defaults to the tenant of the transaction.
+pub async fn insert_task(
+    pg_pool: &PgPool,
+    tenant: &Tenant,
+    task: &CreateTaskReq,
+) -> Result<TaskRow, TaskError> {
+    let mut tx = tenant::begin(pg_pool, tenant).await?;
+    // when the table task is not db, this line throws error: "error: error returned from database: relation "tasks" does not exist"
+    // throws that in compile time WHY?
+    let row = sqlx::query_as!(
+        TaskRow,
+        "INSERT INTO tasks (name, priority, due_at, parent_id) VALUES ($1, $2, $3, $4)
+         RETURNING task_id, name, priority, due_at, parent_id, done",
+        task.name,
+        task.priority,
+        task.due_at,
+        task.parent_id
+    )
+    .fetch_one(&mut *tx)
+    .await
+    .map_err(|e| {
+        if violates_parent_fkey(&e) {
+            return TaskError::ParentNotFound;
+        }
+        e.into()
+    })?;
+    schedule_reminder(&mut tx, tenant, &row).await?;
+    webhooks::enqueue_deliveries(&mut tx, tenant, webhooks::TASK_CREATED, &row).await?;
+    notify_changed(&mut tx, tenant, row.task_id).await?;
+    tx.commit().await?;
+
+    Ok(row)
+}
+
+/// Updates only the fields present in `task`, returns `None` when the task does not exist.
+/// A new due time schedules a new reminder, the one for the old due time is skipped when it runs.
+pub async fn update_task(
+    pg_pool: &PgPool,
+    tenant: &Tenant,
+    task_id: i32,
+    task: &UpdateTaskReq,
+) -> Result<Option<TaskRow>, TaskError> {
+    let mut tx = tenant::begin(pg_pool, tenant).await?;
+    if let Some(Some(parent_id)) = task.parent_id {
+        // a concurrent move could otherwise close the loop this check did not see
+        hierarchy::lock(&mut tx, tenant).await?;
+        if hierarchy::is_ancestor(&mut tx, task_id, parent_id).await? {
+            return Err(TaskError::ParentCycle);
+        }
+    }
+
+    let mut query: QueryBuilder<Postgres> = QueryBuilder::new("UPDATE tasks SET task_id = ");
+    query.push_bind(task_id);
+    if let Some(name) = &task.name {
+        query.push(", name = ").push_bind(name);
+    }
+    if let Some(priority) = task.priority {
+        query.push(", priority = ").push_bind(priority);
+    }
+    if let Some(due_at) = task.due_at {
+        query.push(", due_at = ").push_bind(due_at);
+    }
+    if let Some(parent_id) = task.parent_id {
+        query.push(", parent_id = ").push_bind(parent_id);
+    }
+    if let Some(done) = task.done {
+        query.push(", done = ").push_bind(done);
+    }
+    query
+        .push(" WHERE task_id = ")
+        .push_bind(task_id)
+        .push(" RETURNING task_id, name, priority, due_at, parent_id, done");
+
+    let row: Option<TaskRow> = query
+        .build_query_as()
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(|e| {
+            if violates_parent_fkey(&e) {
+                return TaskError::ParentNotFound;
+            }
+            e.into()
+        })?;
+    if let Some(row) = &row {
+        if task.due_at.is_some() {
+            schedule_reminder(&mut tx, tenant, row).await?;
+        }
+        webhooks::enqueue_deliveries(&mut tx, tenant, webhooks::TASK_UPDATED, row).await?;
+        notify_changed(&mut tx, tenant, task_id).await?;
+    }
+    tx.commit().await?;
+
+    Ok(row)
+}
+
+/// Returns `false` when there was no task to delete. Its dependencies go with it.
+pub async fn delete_task(
+    pg_pool: &PgPool,
+    tenant: &Tenant,
+    task_id: i32,
+) -> Result<bool, TaskError> {
+    let mut tx = tenant::begin(pg_pool, tenant).await?;
+    let row = sqlx::query_as!(
+        TaskRow,
+        "DELETE FROM tasks WHERE task_id = $1 RETURNING task_id, name, priority, due_at, parent_id, done",
+        task_id
+    )
+    .fetch_optional(&mut *tx)
+    .await
+    .map_err(|e| {
+        if violates_parent_fkey(&e) {
+            return TaskError::HasSubtasks;
+        }
+        e.into()
+    })?;
+    if let Some(row) = &row {
+        webhooks::enqueue_deliveries(&mut tx, tenant, webhooks::TASK_DELETED, row).await?;
+        notify_changed(&mut tx, tenant, task_id).await?;
+    }
+    tx.commit().await?;
+
+    Ok(row.is_some())
+}
+
+// other instances drop the task from their cache, delivered only if the transaction commits
+async fn notify_changed(
+    tx: &mut sqlx::Transaction<'_, Postgres>,
+    tenant: &Tenant,
+    task_id: i32,
+) -> Result<(), sqlx::Error> {
+    sqlx::query("SELECT pg_notify($1, $2)")
+        .bind(TASK_CHANGES_CHANNEL)
+        .bind(format!("{}:{task_id}", tenant.id()))
+        .execute(&mut **tx)
+        .await?;
+
+    Ok(())
+}
+
+async fn schedule_reminder(
+    tx: &mut sqlx::Transaction<'_, Postgres>,
+    tenant: &Tenant,
+    row: &TaskRow,
+) -> Result<(), sqlx::Error> {
+    if let Some(due_at) = row.due_at {
+        let job = Job::TaskReminder {
+            tenant_id: tenant.id().to_owned(),
+            task_id: row.task_id,
+            due_at,
+        };
+        jobs::enqueue(&mut **tx, &job, due_at).await?;
+    }
+
+    Ok(())
+}
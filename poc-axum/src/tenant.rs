@@ -0,0 +1,200 @@
+use crate::api_error;
+use axum::extract::{FromRef, FromRequestParts};
+use axum::http::header::AUTHORIZATION;
+use axum::http::request::Parts;
+use axum::http::{HeaderMap, StatusCode};
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+use sqlx::{PgPool, Postgres, Transaction};
+
+pub const TENANT_HEADER: &str = "x-tenant-id";
+// role without BYPASSRLS that every tenant transaction switches to, see migrations/*_tenant_isolation.sql
+const TENANT_ROLE: &str = "tasks_app";
+
+/// The team the request acts for, every query on tenant data runs through [`begin`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct Tenant(String);
+
+impl Tenant {
+    pub fn new(tenant_id: &str) -> Result<Self, TenantError> {
+        // the rule is the client's, so tasks-cli checks the same
+        if !tasks_client::is_valid_tenant_id(tenant_id) {
+            return Err(TenantError::Invalid);
+        }
+
+        Ok(Tenant(tenant_id.to_owned()))
+    }
+
+    pub fn id(&self) -> &str {
+        &self.0
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum TenantError {
+    Missing,
+    Invalid,
+    InvalidToken,
+}
+
+impl TenantError {
+    pub fn message(&self) -> &'static str {
+        match self {
+            TenantError::Missing => {
+                "Missing tenant, send X-Tenant-Id or a bearer token with a tenant_id claim"
+            }
+            TenantError::Invalid => "Invalid tenant id",
+            TenantError::InvalidToken => "Invalid bearer token",
+        }
+    }
+}
+
+/// Resolves the tenant of a request, from the `tenant_id` claim of an HS256 bearer token
+/// when `JWT_SECRET` is set, otherwise from the `X-Tenant-Id` header set by the gateway.
+#[derive(Clone)]
+pub struct TenantResolver {
+    jwt_secret: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Claims {
+    tenant_id: String,
+}
+
+impl TenantResolver {
+    pub fn new(jwt_secret: Option<String>) -> Self {
+        TenantResolver { jwt_secret }
+    }
+
+    /// Reads `JWT_SECRET`.
+    pub fn from_env() -> Self {
+        Self::new(std::env::var("JWT_SECRET").ok())
+    }
+
+    pub fn resolve(&self, headers: &HeaderMap) -> Result<Tenant, TenantError> {
+        if let Some(jwt_secret) = &self.jwt_secret {
+            let token = headers
+                .get(AUTHORIZATION)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.strip_prefix("Bearer "))
+                .ok_or(TenantError::Missing)?;
+            let claims = jsonwebtoken::decode::<Claims>(
+                token,
+                &DecodingKey::from_secret(jwt_secret.as_bytes()),
+                &Validation::new(Algorithm::HS256),
+            )
+            .map_err(|_| TenantError::InvalidToken)?
+            .claims;
+            return Tenant::new(&claims.tenant_id);
+        }
+
+        let tenant_id = headers
+            .get(TENANT_HEADER)
+            .ok_or(TenantError::Missing)?
+            .to_str()
+            .map_err(|_| TenantError::Invalid)?;
+        Tenant::new(tenant_id)
+    }
+}
+
+impl<S> FromRequestParts<S> for Tenant
+where
+    TenantResolver: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = (StatusCode, String);
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        TenantResolver::from_ref(state)
+            .resolve(&parts.headers)
+            .map_err(|e| {
+                let status = match e {
+                    TenantError::InvalidToken => StatusCode::UNAUTHORIZED,
+                    TenantError::Missing | TenantError::Invalid => StatusCode::BAD_REQUEST,
+                };
+                api_error(status, e.message())
+            })
+    }
+}
+
+/// Starts a transaction where row level security only shows the rows of `tenant`.
+pub async fn begin(
+    db_pool: &PgPool,
+    tenant: &Tenant,
+) -> Result<Transaction<'static, Postgres>, sqlx::Error> {
+    let mut tx = db_pool.begin().await?;
+    // SET does not take bind parameters, set_config(..., true) is the SET LOCAL equivalent
+    sqlx::query(&format!("SET LOCAL ROLE {TENANT_ROLE}"))
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query("SELECT set_config('app.tenant_id', $1, true)")
+        .bind(tenant.id())
+        .execute(&mut *tx)
+        .await?;
+
+    Ok(tx)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jsonwebtoken::{EncodingKey, Header};
+    use serde_json::json;
+
+    fn headers(name: &str, value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            axum::http::HeaderName::from_bytes(name.as_bytes()).unwrap(),
+            value.parse().unwrap(),
+        );
+        headers
+    }
+
+    fn token(secret: &str, tenant_id: &str) -> String {
+        let claims = json!({ "tenant_id": tenant_id, "exp": 4_000_000_000u64 });
+        jsonwebtoken::encode(
+            &Header::default(),
+            &claims,
+            &EncodingKey::from_secret(secret.as_bytes()),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_resolve_from_header() {
+        let resolver = TenantResolver::new(None);
+        assert_eq!(
+            resolver.resolve(&headers(TENANT_HEADER, "team-a")),
+            Ok(Tenant("team-a".to_owned()))
+        );
+        assert_eq!(
+            resolver.resolve(&HeaderMap::new()),
+            Err(TenantError::Missing)
+        );
+        assert_eq!(
+            resolver.resolve(&headers(TENANT_HEADER, "team a'; --")),
+            Err(TenantError::Invalid)
+        );
+    }
+
+    #[test]
+    fn test_resolve_from_token_claim() {
+        let resolver = TenantResolver::new(Some("secret".to_owned()));
+        let bearer = format!("Bearer {}", token("secret", "team-b"));
+        assert_eq!(
+            resolver.resolve(&headers("authorization", &bearer)),
+            Ok(Tenant("team-b".to_owned()))
+        );
+
+        let forged = format!("Bearer {}", token("other secret", "team-b"));
+        assert_eq!(
+            resolver.resolve(&headers("authorization", &forged)),
+            Err(TenantError::InvalidToken)
+        );
+        // with a secret configured the header alone is not trusted
+        assert_eq!(
+            resolver.resolve(&headers(TENANT_HEADER, "team-b")),
+            Err(TenantError::Missing)
+        );
+    }
+}
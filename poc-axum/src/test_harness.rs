@@ -0,0 +1,191 @@
+use crate::create_tasks_router;
+use crate::hierarchy::TaskTree;
+use crate::tasks::{CreateTaskReq, TaskRow, UpdateTaskReq};
+use crate::tenant::TENANT_HEADER;
+use crate::tests::test_state;
+use axum::Router;
+use axum::http::{Method, StatusCode};
+use serde::de::DeserializeOwned;
+use sqlx::PgPool;
+use tokio::net::TcpListener;
+use tokio::task::JoinHandle;
+
+// black-box tests of the tasks server: the router of main served on an ephemeral port,
+// against the database #[sqlx::test] creates (with the migrations) for the test and drops after it
+
+/// The tasks server, running until dropped.
+pub struct TestServer {
+    base_url: String,
+    server: JoinHandle<()>,
+}
+
+impl TestServer {
+    pub async fn start(db_pool: PgPool) -> Self {
+        Self::serve(create_tasks_router(test_state(db_pool))).await
+    }
+
+    /// The server of `DATABASE_URL=sqlite::memory:`, a fresh database each time.
+    #[cfg(feature = "sqlite")]
+    pub async fn start_sqlite() -> Self {
+        Self::serve(crate::tests::test_sqlite_router().await).await
+    }
+
+    async fn serve(router: Router) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            axum::serve(listener, router).await.unwrap();
+        });
+
+        TestServer { base_url, server }
+    }
+
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    /// A client sending the requests of `tenant_id`.
+    pub fn client(&self, tenant_id: &str) -> TestClient {
+        TestClient {
+            http: reqwest::Client::new(),
+            base_url: self.base_url.clone(),
+            tenant_id: tenant_id.to_owned(),
+        }
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        self.server.abort();
+    }
+}
+
+/// A response outside 2xx, with the message of its `{"success": false, "message": ...}` body.
+#[derive(Debug, PartialEq)]
+pub struct ApiFailure {
+    pub status: StatusCode,
+    pub message: String,
+}
+
+pub type ApiResult<T> = Result<T, ApiFailure>;
+
+/// Typed calls to the `/tasks` endpoints.
+pub struct TestClient {
+    http: reqwest::Client,
+    base_url: String,
+    tenant_id: String,
+}
+
+impl TestClient {
+    pub async fn list_tasks(&self) -> ApiResult<Vec<TaskRow>> {
+        self.json(self.request(Method::GET, "/tasks")).await
+    }
+
+    pub async fn get_task(&self, task_id: i32) -> ApiResult<TaskRow> {
+        self.json(self.request(Method::GET, &format!("/tasks/{task_id}")))
+            .await
+    }
+
+    pub async fn create_task(&self, task: &CreateTaskReq) -> ApiResult<TaskRow> {
+        self.json(self.request(Method::POST, "/tasks").json(task))
+            .await
+    }
+
+    pub async fn update_task(&self, task_id: i32, task: &UpdateTaskReq) -> ApiResult<()> {
+        self.empty(
+            self.request(Method::PATCH, &format!("/tasks/{task_id}"))
+                .json(task),
+        )
+        .await
+    }
+
+    pub async fn delete_task(&self, task_id: i32) -> ApiResult<()> {
+        self.empty(self.request(Method::DELETE, &format!("/tasks/{task_id}")))
+            .await
+    }
+
+    pub async fn task_tree(&self, task_id: i32) -> ApiResult<TaskTree> {
+        self.json(self.request(Method::GET, &format!("/tasks/{task_id}/tree")))
+            .await
+    }
+
+    pub async fn ready_tasks(&self) -> ApiResult<Vec<TaskRow>> {
+        self.json(self.request(Method::GET, "/tasks/ready")).await
+    }
+
+    pub async fn add_blocker(&self, task_id: i32, blocked_by_id: i32) -> ApiResult<()> {
+        let path = format!("/tasks/{task_id}/blocked_by/{blocked_by_id}");
+        self.empty(self.request(Method::PUT, &path)).await
+    }
+
+    pub async fn remove_blocker(&self, task_id: i32, blocked_by_id: i32) -> ApiResult<()> {
+        let path = format!("/tasks/{task_id}/blocked_by/{blocked_by_id}");
+        self.empty(self.request(Method::DELETE, &path)).await
+    }
+
+    /// Creates `count` top level tasks named `{prefix} 1`, `{prefix} 2`...
+    pub async fn create_tasks(&self, prefix: &str, count: usize) -> Vec<TaskRow> {
+        let mut rows = Vec::with_capacity(count);
+        for n in 1..=count {
+            let task = new_task(&format!("{prefix} {n}"));
+            rows.push(self.create_task(&task).await.unwrap());
+        }
+        rows
+    }
+
+    /// With the tenant header, for requests the typed methods can not make (a malformed body...).
+    pub fn request(&self, method: Method, path: &str) -> reqwest::RequestBuilder {
+        self.http
+            .request(method, format!("{}{path}", self.base_url))
+            .header(TENANT_HEADER, &self.tenant_id)
+    }
+
+    /// Sends a request from [`TestClient::request`], reading the JSON body.
+    pub async fn json<T: DeserializeOwned>(
+        &self,
+        request: reqwest::RequestBuilder,
+    ) -> ApiResult<T> {
+        let response = send(request).await?;
+        Ok(response.json().await.unwrap())
+    }
+
+    async fn empty(&self, request: reqwest::RequestBuilder) -> ApiResult<()> {
+        send(request).await.map(|_| ())
+    }
+}
+
+/// Sends the request, turning an error status into an [`ApiFailure`].
+pub async fn send(request: reqwest::RequestBuilder) -> ApiResult<reqwest::Response> {
+    let response = request.send().await.unwrap();
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+
+    // axum's own rejections (a bad JSON body...) are plain text
+    let body = response.text().await.unwrap();
+    let message = serde_json::from_str::<serde_json::Value>(&body)
+        .ok()
+        .and_then(|json| json["message"].as_str().map(ToOwned::to_owned))
+        .unwrap_or(body);
+    Err(ApiFailure { status, message })
+}
+
+/// A top level task without priority or due time, struct update syntax sets the rest:
+/// `CreateTaskReq { priority: Some(1), ..new_task("urgent") }`.
+pub fn new_task(name: &str) -> CreateTaskReq {
+    CreateTaskReq {
+        name: name.to_owned(),
+        priority: None,
+        due_at: None,
+        parent_id: None,
+    }
+}
+
+/// A subtask of `parent_id`.
+pub fn new_subtask(name: &str, parent_id: i32) -> CreateTaskReq {
+    CreateTaskReq {
+        parent_id: Some(parent_id),
+        ..new_task(name)
+    }
+}
@@ -0,0 +1,405 @@
+use crate::debug::ClientAddr;
+use axum::Router;
+use axum::http::header::HOST;
+use axum::http::uri::Authority;
+use axum::http::{HeaderMap, StatusCode, Uri};
+use axum::response::Redirect;
+use axum::serve::Listener;
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use rustls::ServerConfig;
+use rustls::crypto::{CryptoProvider, ring};
+use rustls::pki_types::pem::{self, PemObject};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use std::collections::HashSet;
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio_rustls::TlsAcceptor;
+use tokio_rustls::server::TlsStream;
+
+// a client that connects and never finishes the handshake gives up its slot after this
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+// handshaken connections waiting for the server to take them
+const ACCEPT_BACKLOG: usize = 64;
+
+/// Certificate and key for HTTPS, the server speaks plain HTTP without them.
+pub struct TlsConfig {
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+    /// Where plain HTTP requests are answered with a redirect to HTTPS.
+    pub redirect_address: Option<String>,
+}
+
+impl TlsConfig {
+    /// Reads `TLS_CERT_PATH`, `TLS_KEY_PATH` and `HTTP_REDIRECT_ADDRESS`, `None` unless both paths are set.
+    pub fn from_env() -> Option<Self> {
+        Some(TlsConfig {
+            cert_path: std::env::var("TLS_CERT_PATH").ok()?.into(),
+            key_path: std::env::var("TLS_KEY_PATH").ok()?.into(),
+            redirect_address: std::env::var("HTTP_REDIRECT_ADDRESS").ok(),
+        })
+    }
+}
+
+#[derive(Debug)]
+pub enum TlsError {
+    Pem(pem::Error),
+    NoCertificate,
+    /// The key can not be used, or it is not the key of the certificate.
+    Key(rustls::Error),
+    Watch(notify::Error),
+}
+
+impl fmt::Display for TlsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TlsError::Pem(e) => write!(f, "Invalid PEM file: {e}"),
+            TlsError::NoCertificate => write!(f, "No certificate in the certificate file"),
+            TlsError::Key(e) => write!(f, "Invalid private key: {e}"),
+            TlsError::Watch(e) => write!(f, "Can not watch the certificate files: {e}"),
+        }
+    }
+}
+
+/// Hands out the current certificate to every handshake, `reload` swaps it without a restart.
+#[derive(Debug)]
+pub struct CertResolver {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl CertResolver {
+    pub fn load(cert_path: &Path, key_path: &Path) -> Result<Self, TlsError> {
+        Ok(CertResolver {
+            cert_path: cert_path.to_owned(),
+            key_path: key_path.to_owned(),
+            current: RwLock::new(Arc::new(load_certified_key(cert_path, key_path)?)),
+        })
+    }
+
+    /// Reads the files again, on error the current certificate stays.
+    pub fn reload(&self) -> Result<(), TlsError> {
+        let certified_key = load_certified_key(&self.cert_path, &self.key_path)?;
+        *self.current.write().unwrap() = Arc::new(certified_key);
+
+        Ok(())
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+fn load_certified_key(cert_path: &Path, key_path: &Path) -> Result<CertifiedKey, TlsError> {
+    let certs = CertificateDer::pem_file_iter(cert_path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(TlsError::Pem)?;
+    if certs.is_empty() {
+        return Err(TlsError::NoCertificate);
+    }
+    let key = PrivateKeyDer::from_pem_file(key_path).map_err(TlsError::Pem)?;
+
+    // also checks the key belongs to the certificate, a renewal that replaced only one of them fails here
+    CertifiedKey::from_der(certs, key, &provider()).map_err(TlsError::Key)
+}
+
+fn provider() -> CryptoProvider {
+    ring::default_provider()
+}
+
+/// TLS 1.2 and 1.3 with the certificate of `resolver`, HTTP/2 offered first through ALPN.
+pub fn server_config(resolver: Arc<CertResolver>) -> ServerConfig {
+    let mut config = ServerConfig::builder_with_provider(Arc::new(provider()))
+        .with_safe_default_protocol_versions()
+        .expect("the ring provider supports the default versions")
+        .with_no_client_auth()
+        .with_cert_resolver(resolver);
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    config
+}
+
+/// Reloads the certificate whenever one of its files is written. Stops when the watcher is dropped.
+pub fn watch(resolver: Arc<CertResolver>) -> Result<RecommendedWatcher, TlsError> {
+    let files: HashSet<_> = [&resolver.cert_path, &resolver.key_path]
+        .into_iter()
+        .filter_map(|path| path.file_name().map(ToOwned::to_owned))
+        .collect();
+    // renewal tools usually write a new file and rename it, so the directories are watched
+    let dirs: HashSet<_> = [&resolver.cert_path, &resolver.key_path]
+        .into_iter()
+        .map(|path| match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir.to_owned(),
+            _ => PathBuf::from("."),
+        })
+        .collect();
+
+    let handler_resolver = resolver.clone();
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        let Ok(event) = event else {
+            return;
+        };
+        let written = event.kind.is_create() || event.kind.is_modify();
+        let ours = event.paths.iter().any(|path| {
+            path.file_name()
+                .is_some_and(|file_name| files.contains(file_name))
+        });
+        if written && ours {
+            match handler_resolver.reload() {
+                Ok(()) => println!("Reloaded TLS certificate"),
+                // the other file of the pair may still be on its way
+                Err(e) => eprintln!("Keeping the current TLS certificate: {e}"),
+            }
+        }
+    })
+    .map_err(TlsError::Watch)?;
+    for dir in dirs {
+        watcher
+            .watch(&dir, RecursiveMode::NonRecursive)
+            .map_err(TlsError::Watch)?;
+    }
+
+    Ok(watcher)
+}
+
+/// Connections of a TCP listener after their TLS handshake, for `axum::serve`.
+/// Handshakes run in their own tasks so a slow client does not hold up the others.
+pub struct TlsListener {
+    connections: mpsc::Receiver<(TlsStream<TcpStream>, SocketAddr)>,
+    local_addr: SocketAddr,
+}
+
+impl TlsListener {
+    pub fn new(mut listener: TcpListener, config: ServerConfig) -> io::Result<Self> {
+        let local_addr = listener.local_addr()?;
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+        let (sender, connections) = mpsc::channel(ACCEPT_BACKLOG);
+        tokio::spawn(async move {
+            while !sender.is_closed() {
+                // retries and logs failed accepts like axum::serve does for plain TCP
+                let (stream, address) = Listener::accept(&mut listener).await;
+                let acceptor = acceptor.clone();
+                let sender = sender.clone();
+                tokio::spawn(async move {
+                    match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                        Ok(Ok(stream)) => {
+                            let _ = sender.send((stream, address)).await;
+                        }
+                        Ok(Err(e)) => eprintln!("TLS handshake with {address} failed: {e}"),
+                        Err(_) => eprintln!("TLS handshake with {address} timed out"),
+                    }
+                });
+            }
+        });
+
+        Ok(TlsListener {
+            connections,
+            local_addr,
+        })
+    }
+}
+
+impl Listener for TlsListener {
+    type Io = TlsStream<TcpStream>;
+    type Addr = SocketAddr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        // the accept task only ends once this receiver is dropped
+        self.connections
+            .recv()
+            .await
+            .expect("the accept task outlives the listener")
+    }
+
+    fn local_addr(&self) -> io::Result<Self::Addr> {
+        Ok(self.local_addr)
+    }
+}
+
+/// Serves `router` over HTTPS, HTTP/2 or HTTP/1.1 as negotiated by ALPN.
+pub async fn serve(
+    listener: TcpListener,
+    router: Router,
+    resolver: Arc<CertResolver>,
+) -> io::Result<()> {
+    axum::serve(
+        TlsListener::new(listener, server_config(resolver))?,
+        router.into_make_service_with_connect_info::<ClientAddr>(),
+    )
+    .await
+}
+
+/// Answers every plain HTTP request with a permanent redirect to the same URL over HTTPS on `https_port`.
+pub async fn serve_redirect(listener: TcpListener, https_port: u16) -> io::Result<()> {
+    axum::serve(listener, create_redirect_router(https_port)).await
+}
+
+fn create_redirect_router(https_port: u16) -> Router {
+    Router::new().fallback(move |headers: HeaderMap, uri: Uri| async move {
+        https_redirect(&headers, &uri, https_port)
+    })
+}
+
+// 308 rather than 301, clients repeat a POST instead of turning it into a GET
+fn https_redirect(headers: &HeaderMap, uri: &Uri, https_port: u16) -> Result<Redirect, StatusCode> {
+    let authority: Authority = headers
+        .get(HOST)
+        .and_then(|host| host.to_str().ok())
+        .and_then(|host| host.parse().ok())
+        .ok_or(StatusCode::BAD_REQUEST)?;
+    // the port in Host is the one of the plain HTTP listener
+    let host = authority.host();
+    let authority = match https_port {
+        443 => host.to_owned(),
+        port => format!("{host}:{port}"),
+    };
+    let path = uri.path_and_query().map_or("/", |path| path.as_str());
+
+    Ok(Redirect::permanent(&format!("https://{authority}{path}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+    use axum::http::header::LOCATION;
+    use axum::http::{Request, Version};
+    use axum::routing::get;
+    use http_body_util::BodyExt;
+    use hyper_util::rt::{TokioExecutor, TokioIo};
+    use rustls::{ClientConfig, RootCertStore};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_rustls::TlsConnector;
+    use tokio_rustls::client;
+    use tower::ServiceExt;
+
+    // a new key and self signed certificate for localhost, written where the server reads them
+    fn write_self_signed(cert_path: &Path, key_path: &Path) -> CertificateDer<'static> {
+        let rcgen::CertifiedKey { cert, signing_key } =
+            rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+        std::fs::write(cert_path, cert.pem()).unwrap();
+        std::fs::write(key_path, signing_key.serialize_pem()).unwrap();
+        cert.der().clone()
+    }
+
+    async fn connect(
+        address: SocketAddr,
+        trusted: &CertificateDer<'static>,
+        alpn: &[u8],
+    ) -> io::Result<client::TlsStream<TcpStream>> {
+        let mut roots = RootCertStore::empty();
+        roots.add(trusted.clone()).unwrap();
+        let mut config = ClientConfig::builder_with_provider(Arc::new(provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        config.alpn_protocols = vec![alpn.to_vec()];
+        let stream = TcpStream::connect(address).await?;
+        TlsConnector::from(Arc::new(config))
+            .connect("localhost".try_into().unwrap(), stream)
+            .await
+    }
+
+    #[tokio::test]
+    async fn test_https_with_http2_and_certificate_reload() {
+        let dir = std::env::temp_dir().join(format!("tls-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (cert_path, key_path) = (dir.join("cert.pem"), dir.join("key.pem"));
+        let first = write_self_signed(&cert_path, &key_path);
+
+        let resolver = Arc::new(CertResolver::load(&cert_path, &key_path).unwrap());
+        let _watcher = watch(resolver.clone()).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let router = Router::new().route("/health", get(|| async { "ok" }));
+        tokio::spawn(serve(listener, router, resolver));
+
+        let stream = connect(address, &first, b"h2").await.unwrap();
+        assert_eq!(stream.get_ref().1.alpn_protocol(), Some(&b"h2"[..]));
+        let (mut sender, connection) =
+            hyper::client::conn::http2::handshake(TokioExecutor::new(), TokioIo::new(stream))
+                .await
+                .unwrap();
+        tokio::spawn(connection);
+        let request = Request::get("https://localhost/health")
+            .body(Body::empty())
+            .unwrap();
+        let response = sender.send_request(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.version(), Version::HTTP_2);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(&body[..], b"ok");
+
+        let mut stream = connect(address, &first, b"http/1.1").await.unwrap();
+        stream
+            .write_all(b"GET /health HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{response}");
+
+        // a renewed certificate is served without a restart
+        let second = write_self_signed(&cert_path, &key_path);
+        let mut reloaded = false;
+        for _ in 0..50 {
+            if connect(address, &second, b"h2").await.is_ok() {
+                reloaded = true;
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        assert!(reloaded, "the new certificate was not picked up");
+        assert!(connect(address, &first, b"h2").await.is_err());
+
+        // a key that does not match keeps the certificate in use
+        write_self_signed(&dir.join("other-cert.pem"), &key_path);
+        assert!(matches!(
+            CertResolver::load(&cert_path, &key_path),
+            Err(TlsError::Key(_))
+        ));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_http_redirects_to_https() {
+        let app = create_redirect_router(8443);
+        let request = Request::post("/tasks?name=a")
+            .header(HOST, "localhost:8080")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::PERMANENT_REDIRECT);
+        assert_eq!(
+            response.headers()[LOCATION],
+            "https://localhost:8443/tasks?name=a"
+        );
+
+        let location = |host: &str, https_port| {
+            let mut headers = HeaderMap::new();
+            headers.insert(HOST, host.parse().unwrap());
+            https_redirect(&headers, &Uri::from_static("/"), https_port)
+                .map(|redirect| redirect.location().to_owned())
+        };
+        assert_eq!(
+            location("example.com", 443),
+            Ok("https://example.com/".to_owned())
+        );
+        assert_eq!(
+            location("[::1]:80", 8443),
+            Ok("https://[::1]:8443/".to_owned())
+        );
+        assert_eq!(location("bad host", 443), Err(StatusCode::BAD_REQUEST));
+    }
+}
@@ -0,0 +1,422 @@
+use crate::jobs::{self, Job};
+use crate::tasks::TaskRow;
+use crate::tenant::{self, Tenant};
+use crate::{AppState, api_error, internal_error};
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::Sha256;
+use sqlx::{PgPool, Postgres, Transaction};
+
+pub const TASK_CREATED: &str = "task.created";
+pub const TASK_UPDATED: &str = "task.updated";
+pub const TASK_DELETED: &str = "task.deleted";
+const EVENTS: [&str; 3] = [TASK_CREATED, TASK_UPDATED, TASK_DELETED];
+
+pub const SIGNATURE_HEADER: &str = "x-webhook-signature";
+pub const EVENT_HEADER: &str = "x-webhook-event";
+pub const DELIVERY_HEADER: &str = "x-webhook-delivery";
+
+#[derive(Deserialize)]
+struct CreateWebhookReq {
+    url: String,
+    /// Event names to receive, all of them when empty or missing.
+    #[serde(default)]
+    events: Vec<String>,
+    secret: String,
+}
+
+// the secret is never sent back
+#[derive(Serialize, sqlx::FromRow)]
+struct WebhookRow {
+    webhook_id: i32,
+    url: String,
+    events: Vec<String>,
+    created_at: DateTime<Utc>,
+}
+
+#[derive(Serialize, sqlx::FromRow)]
+struct DeliveryRow {
+    delivery_id: i64,
+    event: String,
+    status: String,
+    attempts: i32,
+    response_status: Option<i32>,
+    last_error: Option<String>,
+    created_at: DateTime<Utc>,
+    delivered_at: Option<DateTime<Utc>>,
+}
+
+/// Webhooks belong to the tenant that created them and only get that tenant's events.
+pub fn create_webhooks_router() -> Router<AppState> {
+    Router::new()
+        .route("/webhooks", post(create_webhook))
+        .route("/webhooks/{webhook_id}/deliveries", get(get_deliveries))
+}
+
+async fn create_webhook(
+    State(db_pool): State<PgPool>,
+    tenant: Tenant,
+    Json(webhook): Json<CreateWebhookReq>,
+) -> Result<(StatusCode, Json<WebhookRow>), (StatusCode, String)> {
+    if reqwest::Url::parse(&webhook.url).is_err() {
+        return Err(api_error(StatusCode::BAD_REQUEST, "Invalid url"));
+    }
+    if let Some(event) = webhook
+        .events
+        .iter()
+        .find(|e| !EVENTS.contains(&e.as_str()))
+    {
+        return Err(api_error(
+            StatusCode::BAD_REQUEST,
+            &format!("Unknown event {event}, expected one of {EVENTS:?}"),
+        ));
+    }
+    if webhook.secret.is_empty() {
+        return Err(api_error(
+            StatusCode::BAD_REQUEST,
+            "Secret must not be empty",
+        ));
+    }
+
+    let mut tx = tenant::begin(&db_pool, &tenant)
+        .await
+        .map_err(internal_error)?;
+    let row = sqlx::query_as(
+        "INSERT INTO webhooks (url, events, secret) VALUES ($1, $2, $3)
+         RETURNING webhook_id, url, events, created_at",
+    )
+    .bind(&webhook.url)
+    .bind(&webhook.events)
+    .bind(&webhook.secret)
+    .fetch_one(&mut *tx)
+    .await
+    .map_err(internal_error)?;
+    tx.commit().await.map_err(internal_error)?;
+
+    Ok((StatusCode::CREATED, Json(row)))
+}
+
+/// Most recent first.
+async fn get_deliveries(
+    State(db_pool): State<PgPool>,
+    tenant: Tenant,
+    Path(webhook_id): Path<i32>,
+) -> Result<Json<Vec<DeliveryRow>>, (StatusCode, String)> {
+    let mut tx = tenant::begin(&db_pool, &tenant)
+        .await
+        .map_err(internal_error)?;
+    let exists: bool =
+        sqlx::query_scalar("SELECT EXISTS (SELECT 1 FROM webhooks WHERE webhook_id = $1)")
+            .bind(webhook_id)
+            .fetch_one(&mut *tx)
+            .await
+            .map_err(internal_error)?;
+    if !exists {
+        return Err(api_error(StatusCode::NOT_FOUND, "Webhook not found"));
+    }
+
+    let rows = sqlx::query_as(
+        "SELECT delivery_id, event, status, attempts, response_status, last_error, created_at, delivered_at
+         FROM webhook_deliveries WHERE webhook_id = $1 ORDER BY delivery_id DESC",
+    )
+    .bind(webhook_id)
+    .fetch_all(&mut *tx)
+    .await
+    .map_err(internal_error)?;
+    tx.commit().await.map_err(internal_error)?;
+
+    Ok(Json(rows))
+}
+
+/// Records a delivery for every webhook subscribed to `event` and enqueues the jobs sending them,
+/// inside the transaction that changed the task so no event is lost or sent for a rolled back change.
+pub async fn enqueue_deliveries(
+    tx: &mut Transaction<'_, Postgres>,
+    tenant: &Tenant,
+    event: &str,
+    task: &TaskRow,
+) -> Result<(), sqlx::Error> {
+    let payload = json!({ "event": event, "occurred_at": Utc::now(), "task": task }).to_string();
+    let delivery_ids: Vec<i64> = sqlx::query_scalar(
+        "INSERT INTO webhook_deliveries (webhook_id, event, payload)
+         SELECT webhook_id, $1, $2 FROM webhooks WHERE cardinality(events) = 0 OR $1 = ANY(events)
+         RETURNING delivery_id",
+    )
+    .bind(event)
+    .bind(&payload)
+    .fetch_all(&mut **tx)
+    .await?;
+
+    for delivery_id in delivery_ids {
+        let job = Job::WebhookDelivery {
+            tenant_id: tenant.id().to_owned(),
+            delivery_id,
+        };
+        jobs::enqueue(&mut **tx, &job, Utc::now()).await?;
+    }
+
+    Ok(())
+}
+
+/// `sha256=` followed by the hex HMAC-SHA256 of the body keyed with the webhook secret.
+pub fn sign(secret: &str, body: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(body.as_bytes());
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+#[derive(sqlx::FromRow)]
+struct PendingDelivery {
+    event: String,
+    payload: String,
+    url: String,
+    secret: String,
+}
+
+/// Run by the job queue, an error makes it retry the delivery with backoff.
+pub async fn deliver(
+    db_pool: &PgPool,
+    http_client: &reqwest::Client,
+    tenant: &Tenant,
+    delivery_id: i64,
+    final_attempt: bool,
+) -> Result<(), String> {
+    let mut tx = tenant::begin(db_pool, tenant)
+        .await
+        .map_err(|e| e.to_string())?;
+    let delivery: Option<PendingDelivery> = sqlx::query_as(
+        "SELECT d.event, d.payload, w.url, w.secret
+         FROM webhook_deliveries d JOIN webhooks w USING (webhook_id)
+         WHERE d.delivery_id = $1",
+    )
+    .bind(delivery_id)
+    .fetch_optional(&mut *tx)
+    .await
+    .map_err(|e| e.to_string())?;
+    tx.commit().await.map_err(|e| e.to_string())?;
+    let Some(delivery) = delivery else {
+        // the webhook was removed together with its deliveries
+        return Ok(());
+    };
+
+    let response = http_client
+        .post(&delivery.url)
+        .timeout(jobs::WEBHOOK_TIMEOUT)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .header(SIGNATURE_HEADER, sign(&delivery.secret, &delivery.payload))
+        .header(EVENT_HEADER, &delivery.event)
+        .header(DELIVERY_HEADER, delivery_id.to_string())
+        .body(delivery.payload)
+        .send()
+        .await;
+    let (response_status, error) = match response {
+        Ok(response) if response.status().is_success() => (Some(response.status()), None),
+        Ok(response) => (
+            Some(response.status()),
+            Some(format!("receiver answered {}", response.status())),
+        ),
+        Err(e) => (None, Some(e.to_string())),
+    };
+
+    let status = match (&error, final_attempt) {
+        (None, _) => "delivered",
+        (Some(_), false) => "pending",
+        (Some(_), true) => "failed",
+    };
+    let mut tx = tenant::begin(db_pool, tenant)
+        .await
+        .map_err(|e| e.to_string())?;
+    sqlx::query(
+        "UPDATE webhook_deliveries
+         SET status = $2, attempts = attempts + 1, response_status = $3, last_error = $4,
+             delivered_at = CASE WHEN $2 = 'delivered' THEN now() END
+         WHERE delivery_id = $1",
+    )
+    .bind(delivery_id)
+    .bind(status)
+    .bind(response_status.map(|status| status.as_u16() as i32))
+    .bind(&error)
+    .execute(&mut *tx)
+    .await
+    .map_err(|e| e.to_string())?;
+    tx.commit().await.map_err(|e| e.to_string())?;
+
+    match error {
+        Some(error) => Err(error),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::jobs::{JobContext, JobsConfig};
+    use crate::tasks::{self, CreateTaskReq};
+    use crate::tenant::TENANT_HEADER;
+    use crate::test_harness::new_task;
+    use axum::body::{Body, Bytes};
+    use axum::http::{HeaderMap, Request};
+    use http_body_util::BodyExt;
+    use serde_json::Value;
+    use tokio::net::TcpListener;
+    use tokio::sync::mpsc;
+    use tower::ServiceExt;
+
+    const SECRET: &str = "s3cr3t";
+
+    fn app(db_pool: PgPool) -> Router {
+        create_webhooks_router().with_state(crate::tests::test_state(db_pool))
+    }
+
+    fn team(tenant_id: &str) -> Tenant {
+        Tenant::new(tenant_id).unwrap()
+    }
+
+    async fn insert_task(db_pool: &PgPool, tenant_id: &str) -> TaskRow {
+        let task = CreateTaskReq {
+            priority: Some(1),
+            ..new_task("ship webhooks")
+        };
+        tasks::insert_task(db_pool, &team(tenant_id), &task)
+            .await
+            .unwrap()
+    }
+
+    // stand-in for the other system, forwards every request it gets
+    async fn start_receiver() -> (String, mpsc::UnboundedReceiver<(HeaderMap, Bytes)>) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let app = Router::new()
+            .route(
+                "/hook",
+                post(
+                    |State(sender): State<mpsc::UnboundedSender<(HeaderMap, Bytes)>>,
+                     headers: HeaderMap,
+                     body: Bytes| async move {
+                        sender.send((headers, body)).unwrap();
+                    },
+                ),
+            )
+            .with_state(sender);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        (url, receiver)
+    }
+
+    async fn send(app: &Router, request: Request<Body>) -> (StatusCode, Value) {
+        let response = app.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = response.collect().await.unwrap().to_bytes();
+        (status, serde_json::from_slice(&body).unwrap_or(Value::Null))
+    }
+
+    async fn subscribe(app: &Router, url: &str, events: &[&str]) -> i64 {
+        let request = Request::builder()
+            .method("POST")
+            .uri("/webhooks")
+            .header("content-type", "application/json")
+            .header(TENANT_HEADER, "team-a")
+            .body(Body::from(
+                json!({ "url": url, "events": events, "secret": SECRET }).to_string(),
+            ))
+            .unwrap();
+        let (status, webhook) = send(app, request).await;
+        assert_eq!(status, StatusCode::CREATED);
+        webhook["webhook_id"].as_i64().unwrap()
+    }
+
+    async fn run_jobs(db_pool: &PgPool) {
+        let context = JobContext::new(
+            db_pool.clone(),
+            JobsConfig {
+                workers: 1,
+                reminder_webhook_url: None,
+            },
+        );
+        while jobs::run_next(&context).await.unwrap() {}
+    }
+
+    #[sqlx::test]
+    async fn test_signed_delivery_on_task_created(db_pool: PgPool) {
+        let app = app(db_pool.clone());
+        let (url, mut received) = start_receiver().await;
+        let webhook_id = subscribe(&app, &url, &[TASK_CREATED]).await;
+        let ignored_webhook_id = subscribe(&app, &url, &[TASK_DELETED]).await;
+
+        let task = insert_task(&db_pool, "team-a").await;
+        // other tenants' events never reach team-a's webhooks
+        insert_task(&db_pool, "team-b").await;
+        run_jobs(&db_pool).await;
+
+        let (headers, body) = received.recv().await.unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert_eq!(headers[SIGNATURE_HEADER], sign(SECRET, &body));
+        assert_eq!(headers[EVENT_HEADER], TASK_CREATED);
+        let payload: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(payload["task"]["task_id"], task.task_id);
+        assert!(received.try_recv().is_err());
+
+        let request = Request::builder()
+            .uri(format!("/webhooks/{webhook_id}/deliveries"))
+            .header(TENANT_HEADER, "team-a")
+            .body(Body::empty())
+            .unwrap();
+        let (status, deliveries) = send(&app, request).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(deliveries.as_array().unwrap().len(), 1);
+        assert_eq!(deliveries[0]["status"], "delivered");
+        assert_eq!(deliveries[0]["response_status"], 200);
+        assert_eq!(deliveries[0]["attempts"], 1);
+
+        let request = Request::builder()
+            .uri(format!("/webhooks/{ignored_webhook_id}/deliveries"))
+            .header(TENANT_HEADER, "team-a")
+            .body(Body::empty())
+            .unwrap();
+        let (_, deliveries) = send(&app, request).await;
+        assert_eq!(deliveries, json!([]));
+
+        let request = Request::builder()
+            .uri(format!("/webhooks/{webhook_id}/deliveries"))
+            .header(TENANT_HEADER, "team-b")
+            .body(Body::empty())
+            .unwrap();
+        let (status, _) = send(&app, request).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[sqlx::test]
+    async fn test_rejects_unknown_event(db_pool: PgPool) {
+        let app = app(db_pool);
+        let request = Request::builder()
+            .method("POST")
+            .uri("/webhooks")
+            .header("content-type", "application/json")
+            .header(TENANT_HEADER, "team-a")
+            .body(Body::from(
+                json!({ "url": "http://127.0.0.1:1/hook", "events": ["task.exploded"], "secret": SECRET })
+                    .to_string(),
+            ))
+            .unwrap();
+
+        let (status, _) = send(&app, request).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn test_sign_matches_known_hmac() {
+        // echo -n 'hello' | openssl dgst -sha256 -hmac 'key'
+        assert_eq!(
+            sign("key", "hello"),
+            "sha256=9307b3b915efb5171ff14d8cb55fbcc798c6c0ef1456d66ded1a6aa723a58b7b"
+        );
+    }
+}
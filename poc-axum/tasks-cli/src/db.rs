@@ -0,0 +1,113 @@
+use sqlx::PgPool;
+use std::error::Error;
+use tasks_client::is_valid_tenant_id;
+
+// for operators, straight on DATABASE_URL. the migrations are the server's, run the same way it does on start
+
+// TASK_CHANGES_CHANNEL of the server's cache.rs, running servers drop what they cached of a task
+// and the task lists of its tenant on a `tenant_id:task_id` notification
+const TASK_CHANGES_CHANNEL: &str = "task_changes";
+
+pub async fn migrate(db_pool: &PgPool) -> Result<(), sqlx::migrate::MigrateError> {
+    sqlx::migrate!("../migrations").run(db_pool).await
+}
+
+/// Inserts `count` top level tasks named `seed task 1`, `seed task 2`... for `tenant`,
+/// with priorities 1 to 3. No reminders or webhooks, those are only queued by the server, but
+/// running servers are notified like after their own writes, so their cached lists show the tasks.
+pub async fn seed(db_pool: &PgPool, tenant: &str, count: u32) -> Result<u64, Box<dyn Error>> {
+    // rows of a tenant the server refuses could never be read through it
+    if !is_valid_tenant_id(tenant) {
+        return Err(format!("Invalid tenant id {tenant:?}").into());
+    }
+    let count = i32::try_from(count)
+        .map_err(|_| format!("Can not seed more than {} tasks at once", i32::MAX))?;
+    let mut tx = db_pool.begin().await?;
+    // tenant_id defaults to this setting, and row level security checks it when the user is not a superuser
+    sqlx::query("SELECT set_config('app.tenant_id', $1, true)")
+        .bind(tenant)
+        .execute(&mut *tx)
+        .await?;
+    // delivered on commit, as the server's own
+    let inserted = sqlx::query(
+        "WITH inserted AS (
+             INSERT INTO tasks (name, priority)
+             SELECT 'seed task ' || n, (n - 1) % 3 + 1 FROM generate_series(1, $1) AS n
+             RETURNING task_id
+         )
+         SELECT pg_notify($2, $3 || ':' || task_id) FROM inserted",
+    )
+    .bind(count)
+    .bind(TASK_CHANGES_CHANNEL)
+    .bind(tenant)
+    .execute(&mut *tx)
+    .await?
+    .rows_affected();
+    tx.commit().await?;
+
+    Ok(inserted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::postgres::PgListener;
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_seed_inserts_for_tenant(db_pool: PgPool) {
+        assert_eq!(seed(&db_pool, "team-a", 4).await.unwrap(), 4);
+        seed(&db_pool, "team-b", 1).await.unwrap();
+
+        let rows: Vec<(String, i32, String)> = sqlx::query_as(
+            "SELECT name, priority, tenant_id FROM tasks WHERE tenant_id = 'team-a' ORDER BY task_id",
+        )
+        .fetch_all(&db_pool)
+        .await
+        .unwrap();
+        let names: Vec<&str> = rows.iter().map(|(name, ..)| name.as_str()).collect();
+        assert_eq!(
+            names,
+            ["seed task 1", "seed task 2", "seed task 3", "seed task 4"]
+        );
+        let priorities: Vec<i32> = rows.iter().map(|(_, priority, _)| *priority).collect();
+        assert_eq!(priorities, [1, 2, 3, 1]);
+
+        // nothing inserted, rather than a count wrapped around or rows no request can reach
+        assert!(seed(&db_pool, "team-a", u32::MAX).await.is_err());
+        assert!(seed(&db_pool, "team a", 1).await.is_err());
+        assert!(seed(&db_pool, &"a".repeat(65), 1).await.is_err());
+        let total: i64 = sqlx::query_scalar("SELECT count(*) FROM tasks")
+            .fetch_one(&db_pool)
+            .await
+            .unwrap();
+        assert_eq!(total, 5);
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    async fn test_seed_notifies_running_servers(db_pool: PgPool) {
+        let mut listener = PgListener::connect_with(&db_pool).await.unwrap();
+        listener.listen(TASK_CHANGES_CHANNEL).await.unwrap();
+        seed(&db_pool, "team-a", 2).await.unwrap();
+
+        let task_ids: Vec<i32> = sqlx::query_scalar("SELECT task_id FROM tasks ORDER BY task_id")
+            .fetch_all(&db_pool)
+            .await
+            .unwrap();
+        let mut payloads = Vec::new();
+        for _ in &task_ids {
+            payloads.push(listener.recv().await.unwrap().payload().to_owned());
+        }
+        payloads.sort();
+        let mut expected: Vec<String> = task_ids.iter().map(|id| format!("team-a:{id}")).collect();
+        expected.sort();
+        assert_eq!(payloads, expected);
+    }
+
+    #[sqlx::test(migrations = false)]
+    async fn test_migrate_creates_tables(db_pool: PgPool) {
+        migrate(&db_pool).await.unwrap();
+        // a second run has nothing left to apply
+        migrate(&db_pool).await.unwrap();
+        seed(&db_pool, "team-a", 1).await.unwrap();
+    }
+}
@@ -0,0 +1,127 @@
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use tasks_client::{ClientError, CreateTaskReq, TaskRow, TasksClient, UpdateTaskReq};
+
+/// A task of the imported file, an exported [`TaskRow`] or just a [`CreateTaskReq`].
+#[derive(Deserialize, Debug)]
+pub struct ImportTask {
+    /// The id the task had where it was exported, subtasks of it refer to it as their parent.
+    pub task_id: Option<i32>,
+    #[serde(flatten)]
+    pub task: CreateTaskReq,
+    #[serde(default)]
+    pub done: bool,
+}
+
+/// Creates the tasks, each parent in the file before its subtasks,
+/// which then get the id of the created parent. Returns the created tasks in file order.
+pub async fn import(
+    client: &TasksClient,
+    tasks: Vec<ImportTask>,
+) -> Result<Vec<TaskRow>, Box<dyn std::error::Error>> {
+    let order = creation_order(&tasks)?;
+    let mut new_ids: HashMap<i32, i32> = HashMap::new();
+    let mut created: Vec<Option<TaskRow>> = vec![None; tasks.len()];
+    for index in order {
+        let import = &tasks[index];
+        let task = CreateTaskReq {
+            parent_id: import
+                .task
+                .parent_id
+                .map(|parent_id| new_ids.get(&parent_id).copied().unwrap_or(parent_id)),
+            ..import.task.clone()
+        };
+        let mut row = client.create_task(&task).await?;
+        if import.done {
+            mark_done(client, row.task_id).await?;
+            row.done = true;
+        }
+        if let Some(old_id) = import.task_id {
+            new_ids.insert(old_id, row.task_id);
+        }
+        created[index] = Some(row);
+    }
+
+    Ok(created.into_iter().flatten().collect())
+}
+
+async fn mark_done(client: &TasksClient, task_id: i32) -> Result<(), ClientError> {
+    let done = UpdateTaskReq {
+        done: Some(true),
+        ..Default::default()
+    };
+    client.update_task(task_id, &done).await
+}
+
+// indexes of `tasks`, parents that are in the file first. a parent outside the file is left to the server
+fn creation_order(tasks: &[ImportTask]) -> Result<Vec<usize>, String> {
+    let in_file: HashSet<i32> = tasks.iter().filter_map(|t| t.task_id).collect();
+    let mut created: HashSet<i32> = HashSet::new();
+    let mut pending: Vec<usize> = (0..tasks.len()).collect();
+    let mut order = Vec::with_capacity(tasks.len());
+    while !pending.is_empty() {
+        let (ready, waiting): (Vec<usize>, Vec<usize>) = pending.into_iter().partition(|&i| {
+            tasks[i].task.parent_id.is_none_or(|parent_id| {
+                !in_file.contains(&parent_id) || created.contains(&parent_id)
+            })
+        });
+        if ready.is_empty() {
+            return Err("The parents in the file form a cycle".to_owned());
+        }
+        created.extend(ready.iter().filter_map(|&i| tasks[i].task_id));
+        order.extend(ready);
+        pending = waiting;
+    }
+
+    Ok(order)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn task(task_id: i32, parent_id: Option<i32>) -> ImportTask {
+        ImportTask {
+            task_id: Some(task_id),
+            task: CreateTaskReq {
+                name: format!("task {task_id}"),
+                priority: None,
+                due_at: None,
+                parent_id,
+            },
+            done: false,
+        }
+    }
+
+    #[test]
+    fn test_parents_are_created_first() {
+        // 3 was moved under 5 after both were created, 9 is not in the file
+        let tasks = [
+            task(3, Some(5)),
+            task(4, None),
+            task(5, Some(4)),
+            task(6, Some(9)),
+        ];
+        assert_eq!(creation_order(&tasks), Ok(vec![1, 3, 2, 0]));
+    }
+
+    #[test]
+    fn test_rejects_parent_cycle() {
+        let tasks = [task(1, Some(2)), task(2, Some(1))];
+        assert!(creation_order(&tasks).is_err());
+    }
+
+    #[test]
+    fn test_reads_exported_tasks() {
+        let json = r#"[
+            {"task_id": 1, "name": "a", "priority": 2, "due_at": null, "parent_id": null, "done": true},
+            {"name": "b"}
+        ]"#;
+        let tasks: Vec<ImportTask> = serde_json::from_str(json).unwrap();
+        assert_eq!(tasks[0].task_id, Some(1));
+        assert_eq!(tasks[0].task.priority, Some(2));
+        assert!(tasks[0].done);
+        assert_eq!((tasks[1].task_id, tasks[1].task.parent_id), (None, None));
+        assert!(!tasks[1].done);
+    }
+}
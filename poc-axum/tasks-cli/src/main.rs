@@ -0,0 +1,298 @@
+use chrono::{DateTime, Utc};
+use clap::{Parser, Subcommand};
+use futures_util::{StreamExt, TryStreamExt};
+use import::ImportTask;
+use owo_colors::OwoColorize;
+use std::error::Error;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use tasks_client::{Auth, CreateTaskReq, ListTasksQuery, TaskRow, TasksClient, UpdateTaskReq};
+
+mod db;
+mod import;
+mod output;
+
+// the task commands go through the HTTP API like any other client, `db` works on the database
+
+#[derive(Parser)]
+#[command(version, about = "Admin tool for the tasks server")]
+struct Cli {
+    /// Address of the tasks server.
+    #[arg(
+        long,
+        env = "TASKS_URL",
+        default_value = "http://127.0.0.1:8080",
+        global = true
+    )]
+    url: String,
+    /// Tenant the commands act for, sent as X-Tenant-Id.
+    #[arg(long, env = "TASKS_TENANT", global = true)]
+    tenant: Option<String>,
+    /// HS256 token with a tenant_id claim, for a server with JWT_SECRET. Wins over --tenant.
+    #[arg(long, env = "TASKS_TOKEN", global = true)]
+    token: Option<String>,
+    /// Print JSON instead of a table.
+    #[arg(short, long, global = true)]
+    json: bool,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Lists the tasks ordered by id.
+    List {
+        #[arg(long)]
+        name_contains: Option<String>,
+        #[arg(long)]
+        min_priority: Option<i32>,
+        #[arg(long)]
+        max_priority: Option<i32>,
+        /// Stop after this many tasks.
+        #[arg(long)]
+        limit: Option<usize>,
+    },
+    Get {
+        task_id: i32,
+    },
+    Create {
+        name: String,
+        #[arg(long)]
+        priority: Option<i32>,
+        /// RFC 3339, e.g. 2030-01-02T03:04:05Z.
+        #[arg(long)]
+        due_at: Option<DateTime<Utc>>,
+        #[arg(long)]
+        parent_id: Option<i32>,
+    },
+    /// Sets the given fields, the others keep their value.
+    Update {
+        task_id: i32,
+        #[arg(long)]
+        name: Option<String>,
+        #[arg(long)]
+        priority: Option<i32>,
+        #[arg(long)]
+        due_at: Option<DateTime<Utc>>,
+        #[arg(long)]
+        parent_id: Option<i32>,
+        /// Make it a top level task again.
+        #[arg(long, conflicts_with = "parent_id")]
+        no_parent: bool,
+        #[arg(long)]
+        done: Option<bool>,
+    },
+    Delete {
+        task_id: i32,
+    },
+    /// Creates the tasks of a JSON array, like the one export writes (`-` reads stdin).
+    /// Subtasks of tasks in the file go under the newly created parent.
+    Import {
+        file: PathBuf,
+    },
+    /// Writes every task as a JSON array, to stdout when there is no file.
+    Export {
+        file: Option<PathBuf>,
+    },
+    /// Operates on DATABASE_URL directly, not through the server.
+    #[command(subcommand)]
+    Db(DbCommand),
+}
+
+#[derive(Subcommand)]
+enum DbCommand {
+    /// Runs the migrations of the server.
+    Migrate,
+    /// Inserts sample tasks for --tenant.
+    Seed {
+        #[arg(long, default_value_t = 10)]
+        count: u32,
+    },
+}
+
+type CliResult = Result<(), Box<dyn Error>>;
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let cli = Cli::parse();
+    match run(cli).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{}", e.red());
+            ExitCode::FAILURE
+        }
+    }
+}
+
+async fn run(cli: Cli) -> CliResult {
+    if let Command::Db(command) = &cli.command {
+        return run_db(command, cli.tenant.as_deref()).await;
+    }
+
+    let auth = match (cli.token, cli.tenant) {
+        (Some(token), _) => Auth::Bearer(token),
+        (None, Some(tenant)) => Auth::Tenant(tenant),
+        (None, None) => return Err("Set --tenant (TASKS_TENANT) or --token (TASKS_TOKEN)".into()),
+    };
+    let client = TasksClient::new(&cli.url, auth)?;
+
+    match cli.command {
+        Command::List {
+            name_contains,
+            min_priority,
+            max_priority,
+            limit,
+        } => {
+            let query = ListTasksQuery {
+                name_contains,
+                min_priority,
+                max_priority,
+                ..Default::default()
+            };
+            let tasks: Vec<TaskRow> = client
+                .tasks(query)
+                .take(limit.unwrap_or(usize::MAX))
+                .try_collect()
+                .await?;
+            output::print_tasks(&tasks, cli.json);
+        }
+        Command::Get { task_id } => {
+            output::print_tasks(&[client.get_task(task_id).await?], cli.json);
+        }
+        Command::Create {
+            name,
+            priority,
+            due_at,
+            parent_id,
+        } => {
+            let task = CreateTaskReq {
+                name,
+                priority,
+                due_at,
+                parent_id,
+            };
+            output::print_tasks(&[client.create_task(&task).await?], cli.json);
+        }
+        Command::Update {
+            task_id,
+            name,
+            priority,
+            due_at,
+            parent_id,
+            no_parent,
+            done,
+        } => {
+            let task = UpdateTaskReq {
+                name,
+                priority,
+                due_at,
+                parent_id: if no_parent {
+                    Some(None)
+                } else {
+                    parent_id.map(Some)
+                },
+                done,
+            };
+            client.update_task(task_id, &task).await?;
+            output::print_tasks(&[client.get_task(task_id).await?], cli.json);
+        }
+        Command::Delete { task_id } => {
+            client.delete_task(task_id).await?;
+            eprintln!("Deleted task {task_id}");
+        }
+        Command::Import { file } => {
+            let json = if file.as_os_str() == "-" {
+                std::io::read_to_string(std::io::stdin())?
+            } else {
+                std::fs::read_to_string(&file)?
+            };
+            let tasks: Vec<ImportTask> = serde_json::from_str(&json)?;
+            let created = import::import(&client, tasks).await?;
+            output::print_tasks(&created, cli.json);
+        }
+        Command::Export { file } => {
+            let tasks: Vec<TaskRow> = client
+                .tasks(ListTasksQuery::default())
+                .try_collect()
+                .await?;
+            let json = serde_json::to_string_pretty(&tasks)?;
+            match file {
+                Some(file) => {
+                    std::fs::write(&file, json)?;
+                    eprintln!("Exported {} tasks to {}", tasks.len(), file.display());
+                }
+                None => println!("{json}"),
+            }
+        }
+        Command::Db(_) => unreachable!("handled before connecting to the server"),
+    }
+
+    Ok(())
+}
+
+async fn run_db(command: &DbCommand, tenant: Option<&str>) -> CliResult {
+    dotenvy::dotenv().ok();
+    let database_url = std::env::var("DATABASE_URL").map_err(|_| "DATABASE_URL must be set")?;
+    let db_pool = sqlx::PgPool::connect(&database_url).await?;
+
+    match command {
+        DbCommand::Migrate => {
+            db::migrate(&db_pool).await?;
+            eprintln!("Migrations are up to date");
+        }
+        DbCommand::Seed { count } => {
+            let tenant = tenant.ok_or("Set --tenant (TASKS_TENANT) for the seeded tasks")?;
+            let inserted = db::seed(&db_pool, tenant, *count).await?;
+            eprintln!("Inserted {inserted} tasks for {tenant}");
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+
+    #[test]
+    fn test_cli_definition() {
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn test_parses_update() {
+        let cli = Cli::try_parse_from([
+            "tasks-cli",
+            "--tenant",
+            "team-a",
+            "update",
+            "7",
+            "--no-parent",
+            "--done",
+            "true",
+        ])
+        .unwrap();
+        assert_eq!(cli.tenant.as_deref(), Some("team-a"));
+        assert!(matches!(
+            cli.command,
+            Command::Update {
+                task_id: 7,
+                no_parent: true,
+                done: Some(true),
+                parent_id: None,
+                ..
+            }
+        ));
+
+        let both = Cli::try_parse_from([
+            "tasks-cli",
+            "update",
+            "7",
+            "--no-parent",
+            "--parent-id",
+            "1",
+        ]);
+        assert!(both.is_err());
+    }
+}
@@ -0,0 +1,49 @@
+use tabled::settings::object::Rows;
+use tabled::settings::{Color, Style};
+use tabled::{Table, Tabled};
+use tasks_client::TaskRow;
+
+#[derive(Tabled)]
+struct TaskLine {
+    #[tabled(rename = "Id")]
+    task_id: i32,
+    #[tabled(rename = "Name")]
+    name: String,
+    #[tabled(rename = "Priority")]
+    priority: String,
+    #[tabled(rename = "Due")]
+    due_at: String,
+    #[tabled(rename = "Parent")]
+    parent_id: String,
+    #[tabled(rename = "Done")]
+    done: bool,
+}
+
+impl From<&TaskRow> for TaskLine {
+    fn from(task: &TaskRow) -> Self {
+        TaskLine {
+            task_id: task.task_id,
+            name: task.name.clone(),
+            priority: task.priority.map(|p| p.to_string()).unwrap_or_default(),
+            due_at: task
+                .due_at
+                .map(|due_at| due_at.format("%Y-%m-%d %H:%M").to_string())
+                .unwrap_or_default(),
+            parent_id: task.parent_id.map(|id| id.to_string()).unwrap_or_default(),
+            done: task.done,
+        }
+    }
+}
+
+pub fn print_tasks(tasks: &[TaskRow], json: bool) {
+    if json {
+        let json = serde_json::to_string(tasks).unwrap_or("Cannot parse json".to_string());
+        println!("{json}");
+        return;
+    }
+
+    let mut table = Table::new(tasks.iter().map(TaskLine::from));
+    table.with(Style::rounded());
+    table.modify(Rows::first(), Color::FG_BRIGHT_GREEN);
+    println!("{table}");
+}
@@ -0,0 +1,474 @@
+use crate::dto::{CreateTaskReq, ListTasksQuery, TaskRow, UpdateTaskReq};
+use crate::error::ClientError;
+use futures_util::{Stream, TryStreamExt, stream};
+use reqwest::{Method, RequestBuilder, Response, StatusCode};
+use serde::de::DeserializeOwned;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+pub const TENANT_HEADER: &str = "x-tenant-id";
+const MAX_TENANT_LEN: usize = 64;
+/// Returned by writes when the server reads from a replica, sent back on the next requests
+/// so they see those writes.
+pub const SESSION_TOKEN_HEADER: &str = "x-session-token";
+
+/// Whether the server takes `tenant_id`: 1 to 64 ASCII letters, digits, `-` and `_`.
+pub fn is_valid_tenant_id(tenant_id: &str) -> bool {
+    !tenant_id.is_empty()
+        && tenant_id.len() <= MAX_TENANT_LEN
+        && tenant_id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Who the requests act for, the server takes the tenant from one or the other.
+#[derive(Clone, Debug)]
+pub enum Auth {
+    /// `X-Tenant-Id`, when the server has no `JWT_SECRET`.
+    Tenant(String),
+    /// An HS256 token with a `tenant_id` claim.
+    Bearer(String),
+}
+
+#[derive(Clone, Debug)]
+pub struct ClientConfig {
+    /// Of each attempt, from connecting to reading the whole body.
+    pub timeout: Duration,
+    pub connect_timeout: Duration,
+    /// Extra attempts on connection errors, timeouts, 429 and 5xx. POST is never retried,
+    /// a create whose response was lost would run twice.
+    pub max_retries: u32,
+    /// Wait before the first retry, doubled for each next one.
+    pub retry_backoff: Duration,
+    /// Tasks per request of [`TasksClient::pages`] when the query has no `limit`.
+    pub page_size: i64,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        ClientConfig {
+            timeout: Duration::from_secs(10),
+            connect_timeout: Duration::from_secs(3),
+            max_retries: 2,
+            retry_backoff: Duration::from_millis(100),
+            page_size: 100,
+        }
+    }
+}
+
+/// Calls the `/tasks` endpoints, cheap to clone (the connection pool is shared).
+/// Clones share the session too, a read sees the writes made through any of them.
+#[derive(Clone)]
+pub struct TasksClient {
+    http: reqwest::Client,
+    base_url: String,
+    auth: Auth,
+    config: ClientConfig,
+    session_token: Arc<Mutex<Option<String>>>,
+}
+
+impl TasksClient {
+    /// `base_url` is the address of the server, e.g. `http://127.0.0.1:8080`.
+    pub fn new(base_url: &str, auth: Auth) -> Result<Self, ClientError> {
+        Self::with_config(base_url, auth, ClientConfig::default())
+    }
+
+    pub fn with_config(
+        base_url: &str,
+        auth: Auth,
+        config: ClientConfig,
+    ) -> Result<Self, ClientError> {
+        let http = reqwest::Client::builder()
+            .timeout(config.timeout)
+            .connect_timeout(config.connect_timeout)
+            .build()?;
+
+        Ok(TasksClient {
+            http,
+            base_url: base_url.trim_end_matches('/').to_owned(),
+            auth,
+            config,
+            session_token: Arc::new(Mutex::new(None)),
+        })
+    }
+
+    /// One page of tasks ordered by id, all the matching tasks when the query has no `limit`.
+    pub async fn list_tasks(&self, query: &ListTasksQuery) -> Result<Vec<TaskRow>, ClientError> {
+        self.json(Method::GET, "/tasks", |request| request.query(query))
+            .await
+    }
+
+    /// Walks the tasks matching `query` page by page, `limit` is the size of the pages.
+    pub fn pages(&self, query: ListTasksQuery) -> TaskPages<'_> {
+        TaskPages {
+            client: self,
+            query,
+            done: false,
+        }
+    }
+
+    /// Every task matching `query`, fetched a page at a time as the stream is polled.
+    pub fn tasks(
+        &self,
+        query: ListTasksQuery,
+    ) -> impl Stream<Item = Result<TaskRow, ClientError>> + '_ {
+        stream::try_unfold(self.pages(query), |mut pages| async move {
+            let page = pages.next_page().await?;
+            let page = page.map(|rows| (stream::iter(rows.into_iter().map(Ok)), pages));
+            Ok::<_, ClientError>(page)
+        })
+        .try_flatten()
+    }
+
+    pub async fn get_task(&self, task_id: i32) -> Result<TaskRow, ClientError> {
+        self.json(Method::GET, &format!("/tasks/{task_id}"), |request| request)
+            .await
+    }
+
+    pub async fn create_task(&self, task: &CreateTaskReq) -> Result<TaskRow, ClientError> {
+        self.json(Method::POST, "/tasks", |request| request.json(task))
+            .await
+    }
+
+    /// Sets the fields present in `task`, the others keep their value.
+    pub async fn update_task(&self, task_id: i32, task: &UpdateTaskReq) -> Result<(), ClientError> {
+        self.send(Method::PATCH, &format!("/tasks/{task_id}"), |request| {
+            request.json(task)
+        })
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn delete_task(&self, task_id: i32) -> Result<(), ClientError> {
+        self.send(Method::DELETE, &format!("/tasks/{task_id}"), |request| {
+            request
+        })
+        .await?;
+
+        Ok(())
+    }
+
+    async fn json<T: DeserializeOwned>(
+        &self,
+        method: Method,
+        path: &str,
+        build: impl Fn(RequestBuilder) -> RequestBuilder,
+    ) -> Result<T, ClientError> {
+        let response = self.send(method, path, build).await?;
+        Ok(response.json().await?)
+    }
+
+    // `build` adds the query or body, it runs again for every attempt
+    async fn send(
+        &self,
+        method: Method,
+        path: &str,
+        build: impl Fn(RequestBuilder) -> RequestBuilder,
+    ) -> Result<Response, ClientError> {
+        let max_retries = if method == Method::POST {
+            0
+        } else {
+            self.config.max_retries
+        };
+        let mut backoff = self.config.retry_backoff;
+        let mut attempt = 0;
+        loop {
+            let request = self
+                .http
+                .request(method.clone(), format!("{}{path}", self.base_url));
+            let result = build(self.authorize(request)).send().await;
+            if let Ok(response) = &result {
+                self.keep_session_token(response);
+            }
+            let retry = match &result {
+                Ok(response) => {
+                    response.status() == StatusCode::TOO_MANY_REQUESTS
+                        || response.status().is_server_error()
+                }
+                Err(e) => e.is_connect() || e.is_timeout(),
+            };
+            if !retry || attempt == max_retries {
+                return check(result?).await;
+            }
+
+            attempt += 1;
+            tokio::time::sleep(backoff).await;
+            backoff *= 2;
+        }
+    }
+
+    fn authorize(&self, request: RequestBuilder) -> RequestBuilder {
+        let request = match &self.auth {
+            Auth::Tenant(tenant_id) => request.header(TENANT_HEADER, tenant_id),
+            Auth::Bearer(token) => request.bearer_auth(token),
+        };
+        match self.session_token.lock().unwrap().as_deref() {
+            Some(session_token) => request.header(SESSION_TOKEN_HEADER, session_token),
+            None => request,
+        }
+    }
+
+    // responses to concurrent writes can arrive in any order, the latest position wins
+    fn keep_session_token(&self, response: &Response) {
+        let Some(token) = response
+            .headers()
+            .get(SESSION_TOKEN_HEADER)
+            .and_then(|token| token.to_str().ok())
+        else {
+            return;
+        };
+        let mut session_token = self.session_token.lock().unwrap();
+        let newer = session_token
+            .as_deref()
+            .is_none_or(|kept| lsn(token) >= lsn(kept));
+        if newer {
+            *session_token = Some(token.to_owned());
+        }
+    }
+}
+
+// the server's tokens are WAL positions, `16/B374D848`
+fn lsn(token: &str) -> Option<(u32, u32)> {
+    let (high, low) = token.split_once('/')?;
+    Some((
+        u32::from_str_radix(high, 16).ok()?,
+        u32::from_str_radix(low, 16).ok()?,
+    ))
+}
+
+async fn check(response: Response) -> Result<Response, ClientError> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+
+    let body = response.text().await?;
+    Err(ClientError::from_response(status, body))
+}
+
+/// Keyset pagination over `GET /tasks`, see [`TasksClient::pages`].
+pub struct TaskPages<'a> {
+    client: &'a TasksClient,
+    query: ListTasksQuery,
+    done: bool,
+}
+
+impl TaskPages<'_> {
+    /// The next page, `None` once all the tasks were returned.
+    pub async fn next_page(&mut self) -> Result<Option<Vec<TaskRow>>, ClientError> {
+        if self.done {
+            return Ok(None);
+        }
+
+        let limit = *self.query.limit.get_or_insert(self.client.config.page_size);
+        let rows = self.client.list_tasks(&self.query).await?;
+        // a short page is the last one, a full one may be too, the next request tells
+        self.done = (rows.len() as i64) < limit;
+        match rows.last() {
+            Some(last) => self.query.after = Some(last.task_id),
+            None => return Ok(None),
+        }
+
+        Ok(Some(rows))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::extract::{Query, State};
+    use axum::http::HeaderMap;
+    use axum::routing::get;
+    use axum::{Json, Router};
+    use serde_json::json;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::net::TcpListener;
+
+    async fn serve(router: Router) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+        base_url
+    }
+
+    fn client(base_url: &str) -> TasksClient {
+        let config = ClientConfig {
+            retry_backoff: Duration::from_millis(1),
+            ..Default::default()
+        };
+        TasksClient::with_config(base_url, Auth::Tenant("team-a".to_owned()), config).unwrap()
+    }
+
+    fn task(task_id: i32) -> TaskRow {
+        TaskRow {
+            task_id,
+            name: format!("task {task_id}"),
+            priority: None,
+            due_at: None,
+            parent_id: None,
+            done: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_maps_error_bodies() {
+        let router = Router::new()
+            .route(
+                "/tasks/{task_id}",
+                get(|| async {
+                    (
+                        StatusCode::NOT_FOUND,
+                        json!({"success": false, "message": "Task not found"}).to_string(),
+                    )
+                })
+                .delete(|| async {
+                    (
+                        StatusCode::CONFLICT,
+                        json!({"error": "Task has subtasks"}).to_string(),
+                    )
+                }),
+            )
+            .route(
+                "/tasks",
+                get(|| async { (StatusCode::BAD_REQUEST, "Invalid tenant id") }),
+            );
+        let client = client(&serve(router).await);
+
+        let e = client.get_task(1).await.unwrap_err();
+        assert!(matches!(&e, ClientError::NotFound(message) if message == "Task not found"));
+        assert_eq!(e.status(), Some(StatusCode::NOT_FOUND));
+        let e = client.delete_task(1).await.unwrap_err();
+        assert!(matches!(e, ClientError::Conflict(message) if message == "Task has subtasks"));
+        let e = client
+            .list_tasks(&ListTasksQuery::default())
+            .await
+            .unwrap_err();
+        assert!(matches!(e, ClientError::InvalidInput(message) if message == "Invalid tenant id"));
+    }
+
+    #[tokio::test]
+    async fn test_retries_all_but_post() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        // fails twice, then answers
+        async fn flaky(State(calls): State<Arc<AtomicUsize>>) -> Result<Json<TaskRow>, StatusCode> {
+            if calls.fetch_add(1, Ordering::SeqCst) < 2 {
+                return Err(StatusCode::SERVICE_UNAVAILABLE);
+            }
+            Ok(Json(task(1)))
+        }
+        let router = Router::new()
+            .route("/tasks/{task_id}", get(flaky))
+            .route("/tasks", axum::routing::post(flaky))
+            .with_state(calls.clone());
+        let client = client(&serve(router).await);
+
+        assert_eq!(client.get_task(1).await.unwrap(), task(1));
+        assert_eq!(calls.swap(0, Ordering::SeqCst), 3);
+
+        let new_task = CreateTaskReq {
+            name: "once".to_owned(),
+            priority: None,
+            due_at: None,
+            parent_id: None,
+        };
+        let e = client.create_task(&new_task).await.unwrap_err();
+        assert_eq!(e.status(), Some(StatusCode::SERVICE_UNAVAILABLE));
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_times_out() {
+        let router = Router::new().route(
+            "/tasks/{task_id}",
+            get(|| async {
+                tokio::time::sleep(Duration::from_secs(5)).await;
+                Json(task(1))
+            }),
+        );
+        let config = ClientConfig {
+            timeout: Duration::from_millis(50),
+            max_retries: 0,
+            ..Default::default()
+        };
+        let client = TasksClient::with_config(
+            &serve(router).await,
+            Auth::Bearer("token".to_owned()),
+            config,
+        )
+        .unwrap();
+
+        assert!(matches!(
+            client.get_task(1).await,
+            Err(ClientError::Timeout)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_pages_through_tasks() {
+        let requests = Arc::new(AtomicUsize::new(0));
+        // 5 tasks, served the way GET /tasks does
+        async fn list(
+            State(requests): State<Arc<AtomicUsize>>,
+            headers: HeaderMap,
+            Query(query): Query<ListTasksQuery>,
+        ) -> Json<Vec<TaskRow>> {
+            assert_eq!(headers[TENANT_HEADER], "team-a");
+            requests.fetch_add(1, Ordering::SeqCst);
+            let after = query.after.unwrap_or(0);
+            let limit = query.limit.unwrap_or(i64::MAX) as usize;
+            Json((after + 1..=5).map(task).take(limit).collect())
+        }
+        let router = Router::new()
+            .route("/tasks", get(list))
+            .with_state(requests.clone());
+        let client = client(&serve(router).await);
+
+        let query = ListTasksQuery {
+            limit: Some(2),
+            ..Default::default()
+        };
+        let tasks: Vec<TaskRow> = client.tasks(query.clone()).try_collect().await.unwrap();
+        assert_eq!(tasks, (1..=5).map(task).collect::<Vec<_>>());
+        assert_eq!(requests.swap(0, Ordering::SeqCst), 3);
+
+        // a last full page takes one more request to know it was the last
+        let mut pages = client.pages(ListTasksQuery {
+            limit: Some(5),
+            ..query
+        });
+        assert_eq!(
+            pages.next_page().await.unwrap().map(|page| page.len()),
+            Some(5)
+        );
+        assert_eq!(pages.next_page().await.unwrap(), None);
+        assert_eq!(pages.next_page().await.unwrap(), None);
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_sends_back_latest_session_token() {
+        let router = Router::new().route(
+            "/tasks/{task_id}",
+            get(|headers: HeaderMap| async move {
+                let token = headers.get(SESSION_TOKEN_HEADER).cloned();
+                Json(task(token.map_or(0, |token| token.len() as i32)))
+            })
+            .delete(|| async { (StatusCode::NO_CONTENT, [(SESSION_TOKEN_HEADER, "1/A")]) })
+            // an older position, from a write that finished last
+            .patch(|| async { [(SESSION_TOKEN_HEADER, "0/FFFFFFFF")] }),
+        );
+        let client = client(&serve(router).await);
+        assert_eq!(client.get_task(1).await.unwrap().task_id, 0);
+
+        client.delete_task(1).await.unwrap();
+        client
+            .update_task(1, &UpdateTaskReq::default())
+            .await
+            .unwrap();
+        assert_eq!(client.session_token.lock().unwrap().as_deref(), Some("1/A"));
+        // the stub answers with the length of the token it got
+        assert_eq!(client.clone().get_task(1).await.unwrap().task_id, 3);
+    }
+}
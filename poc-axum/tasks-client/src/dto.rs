@@ -0,0 +1,81 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Deserializer, Serialize};
+
+// the JSON bodies of the /tasks endpoints, the server (poc-axum) uses these same types
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "sqlx", derive(sqlx::FromRow))]
+pub struct TaskRow {
+    pub task_id: i32,
+    pub name: String,
+    pub priority: Option<i32>,
+    pub due_at: Option<DateTime<Utc>>,
+    /// Set for subtasks.
+    pub parent_id: Option<i32>,
+    pub done: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CreateTaskReq {
+    pub name: String,
+    pub priority: Option<i32>,
+    pub due_at: Option<DateTime<Utc>>,
+    pub parent_id: Option<i32>,
+}
+
+// None fields are left out when serialized, so they read back as absent
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct UpdateTaskReq {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub priority: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub due_at: Option<DateTime<Utc>>,
+    /// Absent keeps the parent, `null` makes it a top level task again.
+    #[serde(
+        default,
+        deserialize_with = "present",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub parent_id: Option<Option<i32>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub done: Option<bool>,
+}
+
+// tells an absent field (None, from serde(default)) apart from an explicit null (Some(None))
+fn present<'de, D, T>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Option::<T>::deserialize(deserializer).map(Some)
+}
+
+/// Query string of `GET /tasks`. Tasks come ordered by id, `after` is the last id of the previous page.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct ListTasksQuery {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name_contains: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_priority: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_priority: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub after: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limit: Option<i64>,
+}
+
+/// The body of error responses, `{"success": false, "message": ...}` or `{"error": ...}`.
+#[derive(Deserialize, Debug)]
+pub(crate) struct ErrorBody {
+    message: Option<String>,
+    error: Option<String>,
+}
+
+impl ErrorBody {
+    pub(crate) fn into_message(self) -> Option<String> {
+        self.message.or(self.error)
+    }
+}
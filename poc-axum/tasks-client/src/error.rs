@@ -0,0 +1,81 @@
+use crate::dto::ErrorBody;
+use reqwest::StatusCode;
+use std::fmt;
+
+/// A failed call, error statuses are mapped from the `{"message": ...}` (or `{"error": ...}`) body.
+#[derive(Debug)]
+pub enum ClientError {
+    /// 404, the task (or what the path points to) does not exist.
+    NotFound(String),
+    /// 400 or 422, a rejected body, path or tenant.
+    InvalidInput(String),
+    /// 409, e.g. a subtask cycle or deleting a task that has subtasks.
+    Conflict(String),
+    /// Any other error status, 5xx and 429 once the retries ran out.
+    Status { status: StatusCode, message: String },
+    /// No response within [`ClientConfig::timeout`](crate::ClientConfig::timeout).
+    Timeout,
+    /// The connection failed or the body was not the expected JSON.
+    Http(reqwest::Error),
+}
+
+impl ClientError {
+    /// The status of the response, `None` when there was no response.
+    pub fn status(&self) -> Option<StatusCode> {
+        match self {
+            ClientError::NotFound(_) => Some(StatusCode::NOT_FOUND),
+            ClientError::InvalidInput(_) => Some(StatusCode::BAD_REQUEST),
+            ClientError::Conflict(_) => Some(StatusCode::CONFLICT),
+            ClientError::Status { status, .. } => Some(*status),
+            ClientError::Timeout => None,
+            ClientError::Http(e) => e.status(),
+        }
+    }
+
+    pub(crate) fn from_response(status: StatusCode, body: String) -> Self {
+        // axum's own rejections (a bad JSON body...) are plain text
+        let message = serde_json::from_str::<ErrorBody>(&body)
+            .ok()
+            .and_then(ErrorBody::into_message)
+            .unwrap_or(body);
+        match status {
+            StatusCode::NOT_FOUND => ClientError::NotFound(message),
+            StatusCode::BAD_REQUEST | StatusCode::UNPROCESSABLE_ENTITY => {
+                ClientError::InvalidInput(message)
+            }
+            StatusCode::CONFLICT => ClientError::Conflict(message),
+            status => ClientError::Status { status, message },
+        }
+    }
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientError::NotFound(message)
+            | ClientError::InvalidInput(message)
+            | ClientError::Conflict(message) => write!(f, "{message}"),
+            ClientError::Status { status, message } => write!(f, "{status}: {message}"),
+            ClientError::Timeout => write!(f, "Request timed out"),
+            ClientError::Http(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ClientError::Http(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for ClientError {
+    fn from(e: reqwest::Error) -> Self {
+        if e.is_timeout() {
+            return ClientError::Timeout;
+        }
+        ClientError::Http(e)
+    }
+}
@@ -0,0 +1,30 @@
+//! Typed client of the poc-axum tasks API, with the same request and response types as the server.
+//!
+//! ```no_run
+//! use tasks_client::{Auth, CreateTaskReq, TasksClient};
+//!
+//! # async fn run() -> Result<(), tasks_client::ClientError> {
+//! let client = TasksClient::new("http://127.0.0.1:8080", Auth::Tenant("team-a".to_owned()))?;
+//! let task = client
+//!     .create_task(&CreateTaskReq {
+//!         name: "write docs".to_owned(),
+//!         priority: Some(1),
+//!         due_at: None,
+//!         parent_id: None,
+//!     })
+//!     .await?;
+//! assert_eq!(client.get_task(task.task_id).await?, task);
+//! # Ok(())
+//! # }
+//! ```
+
+mod client;
+mod dto;
+mod error;
+
+pub use client::{
+    Auth, ClientConfig, SESSION_TOKEN_HEADER, TENANT_HEADER, TaskPages, TasksClient,
+    is_valid_tenant_id,
+};
+pub use dto::{CreateTaskReq, ListTasksQuery, TaskRow, UpdateTaskReq};
+pub use error::ClientError;
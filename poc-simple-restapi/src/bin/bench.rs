@@ -0,0 +1,231 @@
+use clap::Parser;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// starts the server once per --mode, with the users in memory so that only the serving differs,
+// and has keep-alive clients ask for GET /users/1 as fast as the answers come back
+
+#[derive(Parser)]
+#[command(about = "Compares the throughput and latency of the server modes")]
+struct Cli {
+    /// Clients at once, each on a connection of its own.
+    #[arg(long, default_value_t = 32)]
+    connections: usize,
+    /// How long each mode is measured, in seconds.
+    #[arg(long, default_value_t = 5)]
+    seconds: u64,
+    /// The server binary, by default the one next to this one.
+    #[arg(long)]
+    server: Option<PathBuf>,
+}
+
+const MODES: [&str; 2] = ["threads", "event-loop"];
+const PATH: &str = "/users/1";
+const IO_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Default)]
+struct Measured {
+    latencies: Vec<Duration>,
+    /// Failed requests and answers other than 2xx.
+    errors: usize,
+}
+
+fn main() {
+    let cli = Cli::parse();
+    let server = cli.server.unwrap_or_else(server_next_to_bench);
+    let duration = Duration::from_secs(cli.seconds);
+
+    println!("{} connections, {}s per mode", cli.connections, cli.seconds);
+    println!(
+        "{:<12}{:>10}{:>8}{:>10}{:>10}{:>10}{:>10}",
+        "mode", "req/s", "errors", "p50", "p90", "p99", "max"
+    );
+    for mode in MODES {
+        let address = free_address();
+        let child = Command::new(&server)
+            .args([
+                "--in-memory",
+                "--mode",
+                mode,
+                "--bind",
+                &address.to_string(),
+            ])
+            .stdout(Stdio::null())
+            .spawn();
+        let mut child = match child {
+            Ok(child) => child,
+            Err(e) => {
+                println!("Failed to start {}: {}", server.display(), e);
+                return;
+            }
+        };
+
+        let measured = wait_until_up(address)
+            .and_then(|()| seed(address))
+            .map(|()| load(address, cli.connections, duration));
+        let _ = child.kill();
+        let _ = child.wait();
+
+        match measured {
+            Ok(mut measured) => print_row(mode, &mut measured, duration),
+            Err(e) => println!("{:<12}failed: {}", mode, e),
+        }
+    }
+}
+
+fn server_next_to_bench() -> PathBuf {
+    let bench = std::env::current_exe().expect("path of the bench binary");
+    bench.with_file_name(format!(
+        "poc-simple-restapi{}",
+        std::env::consts::EXE_SUFFIX
+    ))
+}
+
+// a port nobody listens on, for the server to take right after
+fn free_address() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("an ephemeral port");
+    listener.local_addr().expect("address of the listener")
+}
+
+fn wait_until_up(address: SocketAddr) -> io::Result<()> {
+    let deadline = Instant::now() + IO_TIMEOUT;
+    loop {
+        match TcpStream::connect(address) {
+            Ok(_) => return Ok(()),
+            Err(e) if Instant::now() > deadline => return Err(e),
+            Err(_) => thread::sleep(Duration::from_millis(20)),
+        }
+    }
+}
+
+// the user the clients ask for
+fn seed(address: SocketAddr) -> io::Result<()> {
+    let body = r#"{"name":"Bench","email":"bench@example.com"}"#;
+    let request = format!(
+        "POST /users HTTP/1.1\r\nHost: bench\r\nContent-Length: {}\r\n\r\n{body}",
+        body.len()
+    );
+    match Client::new(address).send(request.as_bytes())? {
+        201 => Ok(()),
+        status => Err(io::Error::other(format!(
+            "creating the user answered {status}"
+        ))),
+    }
+}
+
+fn load(address: SocketAddr, connections: usize, duration: Duration) -> Measured {
+    let deadline = Instant::now() + duration;
+    let request = format!("GET {PATH} HTTP/1.1\r\nHost: bench\r\n\r\n");
+    let clients: Vec<_> = (0..connections)
+        .map(|_| {
+            let request = request.clone();
+            thread::spawn(move || {
+                let mut client = Client::new(address);
+                let mut measured = Measured::default();
+                while Instant::now() < deadline {
+                    let start = Instant::now();
+                    match client.send(request.as_bytes()) {
+                        Ok(200..=299) => measured.latencies.push(start.elapsed()),
+                        Ok(_) | Err(_) => measured.errors += 1,
+                    }
+                }
+                measured
+            })
+        })
+        .collect();
+
+    let mut total = Measured::default();
+    for client in clients {
+        let measured = client.join().expect("client thread panicked");
+        total.latencies.extend(measured.latencies);
+        total.errors += measured.errors;
+    }
+    total
+}
+
+fn print_row(mode: &str, measured: &mut Measured, duration: Duration) {
+    measured.latencies.sort();
+    let latencies = &measured.latencies;
+    let percentile = |p: usize| match latencies.len() {
+        0 => Duration::ZERO,
+        n => latencies[(n - 1) * p / 100],
+    };
+    let ms = |d: Duration| format!("{:.2}ms", d.as_secs_f64() * 1000.0);
+    println!(
+        "{:<12}{:>10.0}{:>8}{:>10}{:>10}{:>10}{:>10}",
+        mode,
+        latencies.len() as f64 / duration.as_secs_f64(),
+        measured.errors,
+        ms(percentile(50)),
+        ms(percentile(90)),
+        ms(percentile(99)),
+        ms(percentile(100)),
+    );
+}
+
+/// A keep-alive connection, opened again when the server closes it.
+struct Client {
+    address: SocketAddr,
+    connection: Option<BufReader<TcpStream>>,
+}
+
+impl Client {
+    fn new(address: SocketAddr) -> Self {
+        Client {
+            address,
+            connection: None,
+        }
+    }
+
+    /// Sends `request` and reads the response through, its status.
+    fn send(&mut self, request: &[u8]) -> io::Result<u16> {
+        let result = self.exchange(request);
+        if !matches!(result, Ok((_, true))) {
+            self.connection = None;
+        }
+        result.map(|(status, _)| status)
+    }
+
+    // the status, and whether the connection stays open
+    fn exchange(&mut self, request: &[u8]) -> io::Result<(u16, bool)> {
+        if self.connection.is_none() {
+            let stream = TcpStream::connect(self.address)?;
+            stream.set_nodelay(true)?;
+            stream.set_read_timeout(Some(IO_TIMEOUT))?;
+            self.connection = Some(BufReader::new(stream));
+        }
+        let connection = self.connection.as_mut().expect("connected above");
+        connection.get_mut().write_all(request)?;
+
+        let mut line = String::new();
+        connection.read_line(&mut line)?;
+        let status = line
+            .split(' ')
+            .nth(1)
+            .and_then(|status| status.parse().ok())
+            .ok_or_else(|| io::Error::other(format!("bad status line {line:?}")))?;
+        let (mut length, mut keep_alive) = (0u64, true);
+        loop {
+            line.clear();
+            connection.read_line(&mut line)?;
+            let Some((name, value)) = line.trim_end().split_once(": ") else {
+                break;
+            };
+            if name.eq_ignore_ascii_case("content-length") {
+                length = value.parse().map_err(io::Error::other)?;
+            } else if name.eq_ignore_ascii_case("connection") {
+                keep_alive = !value.eq_ignore_ascii_case("close");
+            }
+        }
+        let body = io::copy(&mut connection.take(length), &mut io::sink())?;
+        if body < length {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+
+        Ok((status, keep_alive))
+    }
+}
@@ -0,0 +1,299 @@
+use crate::request::{self, Method, ReadError, Request};
+use crate::response::Response;
+use crate::router::{RouteError, Router};
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+// one connection, several requests: HTTP/1.1 keeps it open unless either side says
+// `Connection: close`. requests pipelined in one read wait in the buffer and are answered in order.
+// `serve` is the blocking loop of a worker, event_loop.rs answers the same way without blocking
+
+/// A client that stops sending or reading mid request for this long loses its connection.
+pub const IO_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long an open connection may wait for its next request, it holds a worker meanwhile.
+pub const IDLE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Then the connection is closed, so one client cannot keep a worker forever.
+pub const MAX_REQUESTS_PER_CONNECTION: usize = 100;
+/// How long a closed connection waits for the client to close too, however it keeps sending.
+pub const DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// A response and how it goes on the connection.
+pub struct Answer {
+    pub response: Response,
+    /// Whether the connection stays open after it.
+    pub keep_alive: bool,
+    /// The answer to HEAD, written without its body.
+    head: bool,
+}
+
+impl Answer {
+    /// The answer to `request`, the `served`th one of its connection.
+    pub fn to_request(
+        router: &Router<Response>,
+        request: &Request,
+        served: usize,
+        shutdown: &AtomicBool,
+    ) -> Self {
+        Answer {
+            response: route(router, request),
+            keep_alive: request.keep_alive()
+                && served < MAX_REQUESTS_PER_CONNECTION
+                && !shutdown.load(Ordering::SeqCst),
+            head: request.method == Method::Head,
+        }
+    }
+
+    /// The last answer of the connection, a request that could not be read.
+    pub fn closing(response: Response) -> Self {
+        Answer {
+            response,
+            keep_alive: false,
+            head: false,
+        }
+    }
+
+    pub fn write_to(&self, writer: &mut impl Write) -> io::Result<()> {
+        if self.head {
+            return self.response.write_head_to(writer, self.keep_alive);
+        }
+        self.response.write_to(writer, self.keep_alive)
+    }
+}
+
+/// Answers the requests of `stream` until the client or the server closes it. Once `shutdown` is
+/// set, the request in flight is the last one.
+pub fn serve(mut stream: TcpStream, router: &Router<Response>, shutdown: &AtomicBool) {
+    if let Err(e) = stream.set_write_timeout(Some(IO_TIMEOUT)) {
+        println!("Failed to set connection timeouts: {}", e);
+        return;
+    }
+    // a request can take several reads, and a read can hold more than one request
+    let mut buffer = Vec::new();
+
+    for served in 1..=MAX_REQUESTS_PER_CONNECTION {
+        let timeout = if served == 1 {
+            IO_TIMEOUT
+        } else {
+            IDLE_TIMEOUT
+        };
+        if let Err(e) = stream.set_read_timeout(Some(timeout)) {
+            println!("Failed to set connection timeouts: {}", e);
+            return;
+        }
+
+        let answer = match request::read_request(&mut stream, &mut buffer) {
+            Ok(Some(request)) => Answer::to_request(router, &request, served, shutdown),
+            // closed between requests
+            Ok(None) => return,
+            // where the next request would start is unknown, so there is no next request
+            Err(ReadError::Parse(e)) => {
+                Answer::closing(Response::error(e.status(), &e.to_string()))
+            }
+            // idle, nothing of a next request came
+            Err(ReadError::Io(e)) if is_timeout(&e) && buffer.is_empty() => return,
+            Err(ReadError::Io(e)) if is_timeout(&e) => {
+                Answer::closing(Response::error(408, "Timed out reading the request"))
+            }
+            Err(ReadError::Io(e)) => {
+                println!("Failed to read from stream to buffer: {}", e);
+                return;
+            }
+        };
+
+        if let Err(e) = answer.write_to(&mut stream) {
+            println!("Failed to write response: {}", e);
+            return;
+        }
+        if !answer.keep_alive {
+            close(stream);
+            return;
+        }
+    }
+}
+
+fn route(router: &Router<Response>, request: &Request) -> Response {
+    let options = request.method == Method::Options;
+    match router.handle(request) {
+        Ok(response) => response,
+        // what the server as a whole supports
+        Err(RouteError::NotFound) if options && request.path == "*" => {
+            Response::new(204).header("Allow", &allow(&router.methods()))
+        }
+        Err(RouteError::NotFound) => Response::error(404, "Resource not found"),
+        Err(RouteError::MethodNotAllowed(allowed)) if options => {
+            Response::new(204).header("Allow", &allow(&allowed))
+        }
+        Err(RouteError::MethodNotAllowed(allowed)) => {
+            Response::error(405, "Method not allowed").header("Allow", &allow(&allowed))
+        }
+    }
+}
+
+fn allow(methods: &[Method]) -> String {
+    let methods: Vec<&str> = methods.iter().map(Method::as_str).collect();
+    methods.join(", ")
+}
+
+/// Answers a connection there is no worker for.
+pub fn reject_busy(mut stream: TcpStream) {
+    let _ = stream.set_write_timeout(Some(IO_TIMEOUT));
+    let response =
+        Response::error(503, "Server is busy, try again later").header("Retry-After", "1");
+    if let Err(e) = response.write_to(&mut stream, false) {
+        println!("Failed to write response: {}", e);
+    }
+}
+
+// a read timeout is WouldBlock on unix and TimedOut on windows
+fn is_timeout(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
+
+// closing with unread bytes, the rest of a pipeline, resets the connection and can lose the last
+// response on its way to the client. so the server stops writing, then reads what is left
+fn close(mut stream: TcpStream) {
+    if stream.shutdown(Shutdown::Write).is_err() {
+        return;
+    }
+    let deadline = Instant::now() + DRAIN_TIMEOUT;
+    let mut discard = [0; 4096];
+    loop {
+        // a zero read timeout is refused, and the time is up anyway
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() || stream.set_read_timeout(Some(left)).is_err() {
+            return;
+        }
+        if !matches!(stream.read(&mut discard), Ok(size) if size > 0) {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use std::thread;
+
+    // a server for one connection, the responses say which request they answer
+    fn connect() -> TcpStream {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let router = Router::new().route(Method::Get, "/{n}", |request, _| {
+                Response::message(200, &request.path)
+            });
+            let (stream, _) = listener.accept().unwrap();
+            serve(stream, &router, &AtomicBool::new(false));
+        });
+        TcpStream::connect(address).unwrap()
+    }
+
+    fn read_to_end(stream: &mut TcpStream) -> String {
+        let mut text = String::new();
+        stream.read_to_string(&mut text).unwrap();
+        text
+    }
+
+    #[test]
+    fn test_pipelined_requests_in_one_write() {
+        let mut stream = connect();
+        stream
+            .write_all(
+                b"GET /1 HTTP/1.1\r\nHost: x\r\n\r\nGET /2 HTTP/1.1\r\nHost: x\r\n\r\n\
+                GET /3 HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n",
+            )
+            .unwrap();
+
+        let text = read_to_end(&mut stream);
+        let responses: Vec<&str> = text.split("HTTP/1.1 200 OK\r\n").skip(1).collect();
+        assert_eq!(responses.len(), 3);
+        for (n, response) in responses.iter().enumerate() {
+            assert!(response.ends_with(&format!(r#"{{"message":"/{}"}}"#, n + 1)));
+        }
+        assert!(responses[1].contains("Connection: keep-alive\r\n"));
+        assert!(responses[2].contains("Connection: close\r\n"));
+    }
+
+    #[test]
+    fn test_head_and_options() {
+        let mut stream = connect();
+        stream
+            .write_all(
+                b"HEAD /1 HTTP/1.1\r\nHost: x\r\n\r\nOPTIONS /1 HTTP/1.1\r\nHost: x\r\n\r\n\
+                OPTIONS * HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n",
+            )
+            .unwrap();
+
+        let text = read_to_end(&mut stream);
+        let responses: Vec<&str> = text.split("HTTP/1.1 ").skip(1).collect();
+        assert_eq!(responses.len(), 3);
+        assert!(responses[0].starts_with("200 OK\r\n"));
+        assert!(responses[0].contains("Content-Length: 16\r\n"));
+        assert!(responses[0].ends_with("\r\n\r\n"));
+        for response in &responses[1..] {
+            assert!(response.starts_with("204 No Content\r\nAllow: GET, HEAD, OPTIONS\r\n"));
+        }
+    }
+
+    #[test]
+    fn test_closes_after_bad_request_and_http_1_0() {
+        let mut stream = connect();
+        stream
+            .write_all(b"GET /1 HTTP/1.1\r\n\r\nGET /2 HTTP/1.1\r\nHost: x\r\n\r\n")
+            .unwrap();
+        let text = read_to_end(&mut stream);
+        assert!(text.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+        assert_eq!(text.matches("HTTP/1.1 ").count(), 1);
+
+        let mut stream = connect();
+        stream.write_all(b"GET /1 HTTP/1.0\r\n\r\n").unwrap();
+        let text = read_to_end(&mut stream);
+        assert!(text.contains("Connection: close\r\n"));
+    }
+
+    #[test]
+    fn test_drain_ends_while_the_client_keeps_sending() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let (done, served) = mpsc::channel();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            serve(stream, &Router::new(), &AtomicBool::new(false));
+            done.send(()).unwrap();
+        });
+
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream
+            .write_all(b"GET /1 HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        // a byte every 50ms, well within the gap a read waits for, until the server is gone
+        thread::spawn(move || {
+            while stream.write_all(b"x").is_ok() {
+                thread::sleep(Duration::from_millis(50));
+            }
+        });
+        assert!(served.recv_timeout(DRAIN_TIMEOUT * 3).is_ok());
+    }
+
+    #[test]
+    fn test_max_requests_per_connection() {
+        let mut stream = connect();
+        let request = b"GET /1 HTTP/1.1\r\nHost: x\r\n\r\n";
+        stream
+            .write_all(&request.repeat(MAX_REQUESTS_PER_CONNECTION + 1))
+            .unwrap();
+        let text = read_to_end(&mut stream);
+        assert_eq!(
+            text.matches("HTTP/1.1 200 OK").count(),
+            MAX_REQUESTS_PER_CONNECTION
+        );
+        assert!(text.ends_with("Connection: close\r\n\r\n{\"message\":\"/1\"}"));
+    }
+}
@@ -0,0 +1,281 @@
+use postgres::{Client, NoTls};
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+// connections are opened on demand up to max_size and kept for the next checkout. one checked
+// out while all max_size are taken waits for one to come back, up to checkout_timeout
+
+/// How the pool opens and checks its connections, [`Postgres`] outside of tests.
+pub trait Manager: Send + Sync {
+    type Connection: Send;
+    type Error;
+
+    fn connect(&self) -> Result<Self::Connection, Self::Error>;
+    /// Asked on checkout of an idle connection, the server may have closed it since.
+    fn is_valid(&self, connection: &mut Self::Connection) -> bool;
+    /// Asked on return, a connection that failed mid query is not kept.
+    fn has_broken(&self, connection: &Self::Connection) -> bool;
+}
+
+pub struct Postgres {
+    pub url: String,
+}
+
+impl Manager for Postgres {
+    type Connection = Client;
+    type Error = postgres::Error;
+
+    fn connect(&self) -> Result<Client, postgres::Error> {
+        Client::connect(&self.url, NoTls)
+    }
+
+    fn is_valid(&self, client: &mut Client) -> bool {
+        client.is_valid(Duration::from_secs(2)).is_ok()
+    }
+
+    fn has_broken(&self, client: &Client) -> bool {
+        client.is_closed()
+    }
+}
+
+pub struct Pool<M: Manager> {
+    manager: M,
+    max_size: usize,
+    checkout_timeout: Duration,
+    state: Mutex<State<M::Connection>>,
+    // signaled when a connection comes back or a slot frees up
+    returned: Condvar,
+}
+
+struct State<C> {
+    idle: Vec<C>,
+    // idle, checked out, or being connected
+    open: usize,
+}
+
+#[derive(Debug)]
+pub enum PoolError<E> {
+    /// All connections stayed checked out for the whole checkout timeout.
+    Timeout,
+    Connect(E),
+}
+
+impl<E: fmt::Display> fmt::Display for PoolError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PoolError::Timeout => write!(f, "Timed out waiting for a database connection"),
+            PoolError::Connect(e) => write!(f, "Failed to connect to the database: {e}"),
+        }
+    }
+}
+
+impl<E: fmt::Debug + fmt::Display> std::error::Error for PoolError<E> {}
+
+impl<M: Manager> Pool<M> {
+    pub fn new(manager: M, max_size: usize, checkout_timeout: Duration) -> Self {
+        assert!(
+            max_size > 0,
+            "a pool needs room for at least one connection"
+        );
+        Pool {
+            manager,
+            max_size,
+            checkout_timeout,
+            state: Mutex::new(State {
+                idle: Vec::new(),
+                open: 0,
+            }),
+            returned: Condvar::new(),
+        }
+    }
+
+    /// An idle connection that still works, else a new one while under max_size, else the first
+    /// one returned within the checkout timeout.
+    pub fn get(&self) -> Result<PooledConnection<'_, M>, PoolError<M::Error>> {
+        let deadline = Instant::now() + self.checkout_timeout;
+        let mut state = self.state.lock().expect("pool lock poisoned");
+        loop {
+            if let Some(mut connection) = state.idle.pop() {
+                // the check is a round trip, not made holding the lock
+                drop(state);
+                if self.manager.is_valid(&mut connection) {
+                    return Ok(self.wrap(connection));
+                }
+                state = self.state.lock().expect("pool lock poisoned");
+                state.open -= 1;
+                continue;
+            }
+
+            if state.open < self.max_size {
+                state.open += 1;
+                drop(state);
+                return match self.manager.connect() {
+                    Ok(connection) => Ok(self.wrap(connection)),
+                    Err(e) => {
+                        self.release_slot();
+                        Err(PoolError::Connect(e))
+                    }
+                };
+            }
+
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            if timeout.is_zero() {
+                return Err(PoolError::Timeout);
+            }
+            state = self
+                .returned
+                .wait_timeout(state, timeout)
+                .expect("pool lock poisoned")
+                .0;
+        }
+    }
+
+    fn wrap(&self, connection: M::Connection) -> PooledConnection<'_, M> {
+        PooledConnection {
+            pool: self,
+            connection: Some(connection),
+        }
+    }
+
+    fn release_slot(&self) {
+        self.state.lock().expect("pool lock poisoned").open -= 1;
+        self.returned.notify_one();
+    }
+
+    fn put_back(&self, connection: M::Connection) {
+        // a broken connection is dropped, the next checkout opens a new one in its place
+        if self.manager.has_broken(&connection) {
+            return self.release_slot();
+        }
+        self.state
+            .lock()
+            .expect("pool lock poisoned")
+            .idle
+            .push(connection);
+        self.returned.notify_one();
+    }
+}
+
+/// A connection checked out of the pool, it goes back when dropped.
+pub struct PooledConnection<'a, M: Manager> {
+    pool: &'a Pool<M>,
+    // only None in drop
+    connection: Option<M::Connection>,
+}
+
+impl<M: Manager> Deref for PooledConnection<'_, M> {
+    type Target = M::Connection;
+
+    fn deref(&self) -> &M::Connection {
+        self.connection.as_ref().expect("connection until dropped")
+    }
+}
+
+impl<M: Manager> DerefMut for PooledConnection<'_, M> {
+    fn deref_mut(&mut self) -> &mut M::Connection {
+        self.connection.as_mut().expect("connection until dropped")
+    }
+}
+
+impl<M: Manager> Drop for PooledConnection<'_, M> {
+    fn drop(&mut self) {
+        if let Some(connection) = self.connection.take() {
+            self.pool.put_back(connection);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::thread;
+
+    // connections are numbers, in the order they were opened
+    #[derive(Default)]
+    struct Fake {
+        connected: AtomicUsize,
+        refuse: AtomicBool,
+        invalid: Mutex<Vec<usize>>,
+    }
+
+    struct FakeConnection {
+        id: usize,
+        broken: bool,
+    }
+
+    impl Manager for Fake {
+        type Connection = FakeConnection;
+        type Error = &'static str;
+
+        fn connect(&self) -> Result<FakeConnection, &'static str> {
+            if self.refuse.load(Ordering::SeqCst) {
+                return Err("refused");
+            }
+            Ok(FakeConnection {
+                id: self.connected.fetch_add(1, Ordering::SeqCst),
+                broken: false,
+            })
+        }
+
+        fn is_valid(&self, connection: &mut FakeConnection) -> bool {
+            !self.invalid.lock().unwrap().contains(&connection.id)
+        }
+
+        fn has_broken(&self, connection: &FakeConnection) -> bool {
+            connection.broken
+        }
+    }
+
+    fn pool(max_size: usize) -> Pool<Fake> {
+        Pool::new(Fake::default(), max_size, Duration::from_millis(50))
+    }
+
+    #[test]
+    fn test_reuses_connections_up_to_max_size() {
+        let pool = pool(2);
+        let first = pool.get().unwrap();
+        let second = pool.get().unwrap();
+        assert_eq!((first.id, second.id), (0, 1));
+        assert!(matches!(pool.get(), Err(PoolError::Timeout)));
+
+        drop(first);
+        assert_eq!(pool.get().unwrap().id, 0);
+        assert_eq!(pool.manager.connected.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_waits_for_a_connection_to_come_back() {
+        let pool = Pool::new(Fake::default(), 1, Duration::from_secs(5));
+        let connection = pool.get().unwrap();
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                thread::sleep(Duration::from_millis(20));
+                drop(connection);
+            });
+            assert_eq!(pool.get().unwrap().id, 0);
+        });
+    }
+
+    #[test]
+    fn test_replaces_invalid_and_broken_connections() {
+        let pool = pool(1);
+        drop(pool.get().unwrap());
+        pool.manager.invalid.lock().unwrap().push(0);
+        assert_eq!(pool.get().unwrap().id, 1);
+
+        pool.get().unwrap().broken = true;
+        assert_eq!(pool.get().unwrap().id, 2);
+    }
+
+    #[test]
+    fn test_failed_connect_frees_its_slot() {
+        let pool = pool(1);
+        pool.manager.refuse.store(true, Ordering::SeqCst);
+        assert!(matches!(pool.get(), Err(PoolError::Connect("refused"))));
+        pool.manager.refuse.store(false, Ordering::SeqCst);
+        assert_eq!(pool.get().unwrap().id, 0);
+    }
+}
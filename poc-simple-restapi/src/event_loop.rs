@@ -0,0 +1,471 @@
+use crate::connection::{Answer, DRAIN_TIMEOUT, IDLE_TIMEOUT, IO_TIMEOUT};
+use crate::request;
+use crate::response::Response;
+use crate::router::Router;
+use mio::net::{TcpListener, TcpStream};
+use mio::{Events, Interest, Poll, Registry, Token};
+use std::collections::HashMap;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::Shutdown;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+// the --mode event-loop server: one thread, every connection. poll (epoll) says which sockets can
+// be read or written without blocking, and each connection picks up where it stopped. answers are
+// the same as in connection.rs, but the handlers still block: on Postgres a slow query holds up
+// every connection, with --in-memory nothing does
+
+const LISTENER: Token = Token(0);
+// how often timeouts and the shutdown flag are looked at when nothing happens
+const TICK: Duration = Duration::from_millis(500);
+const READ_CHUNK: usize = 4096;
+// what a connection reads before answering: a request as large as the parser allows
+const MAX_READ: usize = request::MAX_HEAD_BYTES + request::MAX_CHUNKED_BYTES + READ_CHUNK;
+// answers the client has not taken yet, past this it is neither read from nor answered until it
+// takes them. the blocking mode gets the same from the socket's send buffer
+const MAX_WRITE: usize = MAX_READ;
+
+/// Serves until `shutdown` is set and the listener is woken up, then writes out the answers
+/// already made, for up to [`IO_TIMEOUT`].
+pub fn run(
+    listener: std::net::TcpListener,
+    router: Router<Response>,
+    shutdown: &AtomicBool,
+) -> io::Result<()> {
+    listener.set_nonblocking(true)?;
+    let mut listener = TcpListener::from_std(listener);
+    let mut poll = Poll::new()?;
+    poll.registry()
+        .register(&mut listener, LISTENER, Interest::READABLE)?;
+    let mut events = Events::with_capacity(1024);
+    let mut connections = HashMap::new();
+    let mut next_token = LISTENER.0 + 1;
+
+    while !shutdown.load(Ordering::SeqCst) {
+        wait(&mut poll, &mut events)?;
+        for event in &events {
+            if event.token() == LISTENER {
+                accept(
+                    &listener,
+                    poll.registry(),
+                    &mut connections,
+                    &mut next_token,
+                );
+            } else {
+                advance(&mut connections, event.token(), &router, shutdown);
+            }
+        }
+        let now = Instant::now();
+        connections.retain(|_, connection| !connection.timed_out(now));
+    }
+
+    poll.registry().deregister(&mut listener)?;
+    drop(listener);
+    let deadline = Instant::now() + IO_TIMEOUT;
+    loop {
+        connections.retain(|_, connection| !connection.write.is_empty());
+        if connections.is_empty() || Instant::now() > deadline {
+            return Ok(());
+        }
+        wait(&mut poll, &mut events)?;
+        for event in &events {
+            advance(&mut connections, event.token(), &router, shutdown);
+        }
+    }
+}
+
+fn wait(poll: &mut Poll, events: &mut Events) -> io::Result<()> {
+    match poll.poll(events, Some(TICK)) {
+        // a signal, Ctrl-C among them, interrupts the wait
+        Err(e) if e.kind() == ErrorKind::Interrupted => {
+            events.clear();
+            Ok(())
+        }
+        result => result,
+    }
+}
+
+fn accept(
+    listener: &TcpListener,
+    registry: &Registry,
+    connections: &mut HashMap<Token, Connection>,
+    next_token: &mut usize,
+) {
+    loop {
+        match listener.accept() {
+            Ok((mut stream, _)) => {
+                let token = Token(*next_token);
+                *next_token += 1;
+                // both, for good: events only come on a change, a spurious one costs nothing
+                let interest = Interest::READABLE | Interest::WRITABLE;
+                if let Err(e) = registry.register(&mut stream, token, interest) {
+                    println!("Failed to register connection: {}", e);
+                    continue;
+                }
+                connections.insert(token, Connection::new(stream));
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock => return,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => {
+                println!("Error incoming stream: {}", e);
+                return;
+            }
+        }
+    }
+}
+
+fn advance(
+    connections: &mut HashMap<Token, Connection>,
+    token: Token,
+    router: &Router<Response>,
+    shutdown: &AtomicBool,
+) {
+    let Some(connection) = connections.get_mut(&token) else {
+        return;
+    };
+    if !connection.advance(router, shutdown) {
+        connections.remove(&token);
+    }
+}
+
+enum State {
+    /// Reading requests, answering each one as soon as all of it is there.
+    Open,
+    /// The last answer is made, writing out what is left of it.
+    Closing,
+    /// All written and the write side shut, discarding what the client still sends until it
+    /// closes too, as `connection::close` does.
+    Draining,
+}
+
+enum Filled {
+    /// All there was for now.
+    WouldBlock,
+    /// The client closed its side.
+    Eof,
+    /// [`MAX_READ`] bytes are waiting, more may come once they are answered.
+    Full,
+    /// Not read, [`MAX_WRITE`] bytes of answers are waiting for the client.
+    Backlogged,
+}
+
+struct Connection {
+    stream: TcpStream,
+    state: State,
+    // bytes of requests not answered yet
+    read: Vec<u8>,
+    // answers not written yet
+    write: Vec<u8>,
+    served: usize,
+    // when the client last sent or took bytes
+    active: Instant,
+}
+
+impl Connection {
+    fn new(stream: TcpStream) -> Self {
+        Connection {
+            stream,
+            state: State::Open,
+            read: Vec::new(),
+            write: Vec::new(),
+            served: 0,
+            active: Instant::now(),
+        }
+    }
+
+    /// Reads, answers and writes as far as the socket lets it, false once the connection is over.
+    fn advance(&mut self, router: &Router<Response>, shutdown: &AtomicBool) -> bool {
+        let eof = loop {
+            let filled = match self.fill() {
+                Ok(filled) => filled,
+                Err(e) => {
+                    println!("Failed to read from stream to buffer: {}", e);
+                    return false;
+                }
+            };
+            let backlogged = match self.state {
+                State::Open => self.answer(router, shutdown),
+                // nothing more is answered, only read to know when the client is done
+                State::Closing | State::Draining => {
+                    self.read.clear();
+                    false
+                }
+            };
+            if let Err(e) = self.flush() {
+                println!("Failed to write response: {}", e);
+                return false;
+            }
+            // flush stopped on WouldBlock, the writable event once the client takes more goes on
+            if self.write.len() >= MAX_WRITE {
+                break false;
+            }
+            match filled {
+                // requests left for the backlog, which is written out now
+                _ if backlogged => continue,
+                Filled::WouldBlock => break false,
+                Filled::Eof => break true,
+                Filled::Backlogged => continue,
+                // left for the timeout, a client sending without end does not hold up the loop
+                Filled::Full if !matches!(self.state, State::Open) => break false,
+                Filled::Full if self.read.len() < MAX_READ => continue,
+                // nothing answered out of a full buffer. the parser fails sooner, this is a safeguard
+                // against reading the same bytes again and again
+                Filled::Full => {
+                    self.queue(Answer::closing(Response::error(
+                        400,
+                        "Bad request: no request in the bytes read",
+                    )));
+                    break false;
+                }
+            }
+        };
+
+        if eof && matches!(self.state, State::Open) {
+            // the client sent all it will, what is left in the buffer is not a whole request
+            if !self.read.is_empty() {
+                self.queue(Answer::closing(Response::error(
+                    400,
+                    "Bad request: connection closed mid request",
+                )));
+            }
+            self.state = State::Closing;
+        }
+        if let Err(e) = self.flush() {
+            println!("Failed to write response: {}", e);
+            return false;
+        }
+        if self.write.is_empty() && matches!(self.state, State::Closing) {
+            // the write side only, so the last answer is not lost to a reset
+            let _ = self.stream.shutdown(Shutdown::Write);
+            self.state = State::Draining;
+            self.active = Instant::now();
+        }
+
+        !(eof && self.write.is_empty())
+    }
+
+    fn fill(&mut self) -> io::Result<Filled> {
+        if self.write.len() >= MAX_WRITE {
+            return Ok(Filled::Backlogged);
+        }
+        let mut chunk = [0; READ_CHUNK];
+        while self.read.len() < MAX_READ {
+            match self.stream.read(&mut chunk) {
+                Ok(0) => return Ok(Filled::Eof),
+                Ok(size) => {
+                    self.read.extend_from_slice(&chunk[..size]);
+                    // a draining client gets DRAIN_TIMEOUT in all, however it keeps sending
+                    if !matches!(self.state, State::Draining) {
+                        self.active = Instant::now();
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(Filled::WouldBlock),
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(Filled::Full)
+    }
+
+    // every request whole in the buffer, in order, until one ends the connection. true when the
+    // answers pile up to MAX_WRITE first, the rest wait until they are written
+    fn answer(&mut self, router: &Router<Response>, shutdown: &AtomicBool) -> bool {
+        while matches!(self.state, State::Open) {
+            if self.write.len() >= MAX_WRITE {
+                return true;
+            }
+            let answer = match request::parse(&self.read) {
+                Ok(Some((request, len))) => {
+                    self.read.drain(..len);
+                    self.served += 1;
+                    Answer::to_request(router, &request, self.served, shutdown)
+                }
+                Ok(None) => return false,
+                Err(e) => Answer::closing(Response::error(e.status(), &e.to_string())),
+            };
+            self.queue(answer);
+        }
+
+        false
+    }
+
+    fn queue(&mut self, answer: Answer) {
+        // writing to a Vec does not fail
+        let _ = answer.write_to(&mut self.write);
+        if !answer.keep_alive {
+            self.state = State::Closing;
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        while !self.write.is_empty() {
+            match self.stream.write(&self.write) {
+                Ok(0) => return Err(ErrorKind::WriteZero.into()),
+                Ok(size) => {
+                    self.write.drain(..size);
+                    self.active = Instant::now();
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(())
+    }
+
+    // the timeouts of connection::serve, a connection past them is dropped. one stalled mid
+    // request is answered 408 first, and gets another IO_TIMEOUT to take it
+    fn timed_out(&mut self, now: Instant) -> bool {
+        let idle = self.served > 0 && self.read.is_empty() && self.write.is_empty();
+        let timeout = match self.state {
+            State::Open if idle => IDLE_TIMEOUT,
+            State::Open | State::Closing => IO_TIMEOUT,
+            State::Draining => DRAIN_TIMEOUT,
+        };
+        if now.duration_since(self.active) < timeout {
+            return false;
+        }
+        if !matches!(self.state, State::Open) || self.read.is_empty() {
+            return true;
+        }
+
+        self.queue(Answer::closing(Response::error(
+            408,
+            "Timed out reading the request",
+        )));
+        self.active = now;
+        self.flush().is_err()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection::MAX_REQUESTS_PER_CONNECTION;
+    use crate::request::Method;
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use std::thread::{self, JoinHandle};
+
+    // the loop on an ephemeral port, the responses say which request they answer
+    fn start() -> (SocketAddr, Arc<AtomicBool>, JoinHandle<()>) {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let server = {
+            let shutdown = Arc::clone(&shutdown);
+            thread::spawn(move || {
+                let router = Router::new()
+                    .route(Method::Get, "/{n}", |request, _| {
+                        Response::message(200, &request.path)
+                    })
+                    .route(Method::Get, "/big/{n}", |_, _| {
+                        Response::message(200, &"x".repeat(64 * 1024))
+                    });
+                run(listener, router, &shutdown).unwrap();
+            })
+        };
+        (address, shutdown, server)
+    }
+
+    fn read_to_end(stream: &mut std::net::TcpStream) -> String {
+        let mut text = String::new();
+        stream.read_to_string(&mut text).unwrap();
+        text
+    }
+
+    #[test]
+    fn test_pipelined_and_trickled_requests() {
+        let (address, _, _) = start();
+        let mut pipelined = std::net::TcpStream::connect(address).unwrap();
+        let mut trickled = std::net::TcpStream::connect(address).unwrap();
+        pipelined
+            .write_all(
+                b"GET /1 HTTP/1.1\r\nHost: x\r\n\r\nHEAD /2 HTTP/1.1\r\nHost: x\r\n\r\n\
+                GET /3 HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n",
+            )
+            .unwrap();
+        // the other connection is served while this one is half way through its request
+        for &b in b"GET /4 HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n" {
+            trickled.write_all(&[b]).unwrap();
+        }
+
+        let text = read_to_end(&mut pipelined);
+        let responses: Vec<&str> = text.split("HTTP/1.1 200 OK\r\n").skip(1).collect();
+        assert_eq!(responses.len(), 3);
+        assert!(responses[0].ends_with(r#"{"message":"/1"}"#));
+        assert!(responses[1].ends_with("Connection: keep-alive\r\n\r\n"));
+        assert!(responses[2].ends_with(r#"{"message":"/3"}"#));
+        assert!(read_to_end(&mut trickled).ends_with(r#"{"message":"/4"}"#));
+    }
+
+    #[test]
+    fn test_bad_and_cut_requests() {
+        let (address, _, _) = start();
+        let mut stream = std::net::TcpStream::connect(address).unwrap();
+        stream
+            .write_all(b"GET /1 HTTP/1.1\r\n\r\nGET /2 HTTP/1.1\r\nHost: x\r\n\r\n")
+            .unwrap();
+        let text = read_to_end(&mut stream);
+        assert!(text.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+        assert_eq!(text.matches("HTTP/1.1 ").count(), 1);
+
+        let mut stream = std::net::TcpStream::connect(address).unwrap();
+        stream.write_all(b"GET /1 HTTP/1.1\r\nHo").unwrap();
+        stream.shutdown(Shutdown::Write).unwrap();
+        assert!(read_to_end(&mut stream).starts_with("HTTP/1.1 400 Bad Request\r\n"));
+    }
+
+    #[test]
+    fn test_flood_without_a_request_does_not_hold_up_others() {
+        let (address, _, _) = start();
+        let mut flood = std::net::TcpStream::connect(address).unwrap();
+        thread::spawn(move || {
+            // the server answers 431 and closes long before all of it is sent
+            let _ = flood.write_all(&b"\r\n".repeat(700_000));
+        });
+
+        let mut stream = std::net::TcpStream::connect(address).unwrap();
+        stream.set_read_timeout(Some(IO_TIMEOUT / 2)).unwrap();
+        stream
+            .write_all(b"GET /1 HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        assert!(read_to_end(&mut stream).ends_with(r#"{"message":"/1"}"#));
+    }
+
+    #[test]
+    fn test_answers_wait_for_a_client_not_reading() {
+        let (address, _, _) = start();
+        let mut stream = std::net::TcpStream::connect(address).unwrap();
+        let request = b"GET /big/1 HTTP/1.1\r\nHost: x\r\n\r\n";
+        // far more answers than MAX_WRITE, the last one closes the connection
+        stream
+            .write_all(&request.repeat(MAX_REQUESTS_PER_CONNECTION))
+            .unwrap();
+        thread::sleep(Duration::from_millis(200));
+
+        let text = read_to_end(&mut stream);
+        assert!(text.len() > MAX_WRITE);
+        assert_eq!(
+            text.matches("HTTP/1.1 200 OK").count(),
+            MAX_REQUESTS_PER_CONNECTION
+        );
+    }
+
+    #[test]
+    fn test_returns_once_shut_down() {
+        let (address, shutdown, server) = start();
+        let mut stream = std::net::TcpStream::connect(address).unwrap();
+        stream
+            .write_all(b"GET /1 HTTP/1.1\r\nHost: x\r\n\r\n")
+            .unwrap();
+        let mut response = [0; 16];
+        stream.read_exact(&mut response).unwrap();
+
+        shutdown.store(true, Ordering::SeqCst);
+        std::net::TcpStream::connect(address).unwrap();
+        server.join().unwrap();
+    }
+}
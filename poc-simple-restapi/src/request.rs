@@ -0,0 +1,680 @@
+use std::fmt;
+use std::io::{self, Read};
+
+// HTTP/1.1 requests (RFC 9112) parsed from the bytes read so far: `parse` either needs more bytes,
+// returns a request and how many bytes it took, or fails with the status to answer
+
+pub const MAX_HEAD_BYTES: usize = 8 * 1024;
+pub const MAX_BODY_BYTES: usize = 1024 * 1024;
+/// A chunked body as sent, chunk sizes, extensions and trailers included. Room for chunks of a few
+/// dozen bytes, tiny ones with long extensions would otherwise make a request without end.
+pub const MAX_CHUNKED_BYTES: usize = MAX_BODY_BYTES + MAX_BODY_BYTES / 4;
+const READ_CHUNK: usize = 4096;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Method {
+    Get,
+    Head,
+    Post,
+    Put,
+    Patch,
+    Delete,
+    Options,
+}
+
+impl Method {
+    fn parse(method: &str) -> Result<Self, ParseError> {
+        match method {
+            "GET" => Ok(Method::Get),
+            "HEAD" => Ok(Method::Head),
+            "POST" => Ok(Method::Post),
+            "PUT" => Ok(Method::Put),
+            "PATCH" => Ok(Method::Patch),
+            "DELETE" => Ok(Method::Delete),
+            "OPTIONS" => Ok(Method::Options),
+            m if !m.is_empty() && m.bytes().all(is_token_byte) => Err(ParseError::NotImplemented),
+            _ => Err(ParseError::BadRequest("invalid method")),
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Method::Get => "GET",
+            Method::Head => "HEAD",
+            Method::Post => "POST",
+            Method::Put => "PUT",
+            Method::Patch => "PATCH",
+            Method::Delete => "DELETE",
+            Method::Options => "OPTIONS",
+        }
+    }
+}
+
+impl fmt::Display for Method {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Request {
+    pub method: Method,
+    /// Percent-decoded, without the query string.
+    pub path: String,
+    /// Decoded `name=value` pairs in the order they came.
+    pub query: Vec<(String, String)>,
+    /// Names in lower case, values without surrounding whitespace.
+    pub headers: Vec<(String, String)>,
+    /// Already de-chunked when it came with `Transfer-Encoding: chunked`.
+    pub body: Vec<u8>,
+    /// `HTTP/1.0` or `HTTP/1.1`, the minor version.
+    pub minor_version: u8,
+}
+
+impl Request {
+    /// The first value of the header, `name` in lower case.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// The first value of the query parameter.
+    pub fn query_param(&self, name: &str) -> Option<&str> {
+        self.query
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Whether the client wants the connection kept open after the response: the default in
+    /// HTTP/1.1 unless it says `Connection: close`, in HTTP/1.0 only with `Connection: keep-alive`.
+    pub fn keep_alive(&self) -> bool {
+        let connection_has = |option: &str| {
+            self.header("connection").is_some_and(|value| {
+                value
+                    .split(',')
+                    .any(|o| o.trim().eq_ignore_ascii_case(option))
+            })
+        };
+        if self.minor_version == 0 {
+            return connection_has("keep-alive");
+        }
+        !connection_has("close")
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ParseError {
+    BadRequest(&'static str),
+    /// Request line and headers over [`MAX_HEAD_BYTES`].
+    HeadersTooLarge,
+    /// Body over [`MAX_BODY_BYTES`].
+    BodyTooLarge,
+    /// A method or transfer coding the server does not know.
+    NotImplemented,
+}
+
+impl ParseError {
+    pub fn status(&self) -> u16 {
+        match self {
+            ParseError::BadRequest(_) => 400,
+            ParseError::BodyTooLarge => 413,
+            ParseError::HeadersTooLarge => 431,
+            ParseError::NotImplemented => 501,
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::BadRequest(reason) => write!(f, "Bad request: {reason}"),
+            ParseError::HeadersTooLarge => {
+                write!(f, "Request headers larger than {MAX_HEAD_BYTES} bytes")
+            }
+            ParseError::BodyTooLarge => {
+                write!(f, "Request body larger than {MAX_BODY_BYTES} bytes")
+            }
+            ParseError::NotImplemented => write!(f, "Method or transfer encoding not implemented"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ReadError {
+    Parse(ParseError),
+    Io(io::Error),
+}
+
+impl From<io::Error> for ReadError {
+    fn from(e: io::Error) -> Self {
+        ReadError::Io(e)
+    }
+}
+
+impl From<ParseError> for ReadError {
+    fn from(e: ParseError) -> Self {
+        ReadError::Parse(e)
+    }
+}
+
+/// Reads from `stream` until `buffer` holds a whole request, the bytes after it stay in `buffer`.
+/// `None` when the client closed the connection before sending anything.
+pub fn read_request(
+    stream: &mut impl Read,
+    buffer: &mut Vec<u8>,
+) -> Result<Option<Request>, ReadError> {
+    loop {
+        if let Some((request, len)) = parse(buffer)? {
+            buffer.drain(..len);
+            return Ok(Some(request));
+        }
+
+        let mut chunk = [0; READ_CHUNK];
+        let size = stream.read(&mut chunk)?;
+        if size == 0 {
+            if buffer.is_empty() {
+                return Ok(None);
+            }
+            return Err(ParseError::BadRequest("connection closed mid request").into());
+        }
+        buffer.extend_from_slice(&chunk[..size]);
+    }
+}
+
+/// The request at the start of `buffer` and its length in bytes, `None` until all of it is there.
+pub fn parse(buffer: &[u8]) -> Result<Option<(Request, usize)>, ParseError> {
+    // empty lines before the request line are ignored (RFC 9112 section 2.2), but count as head
+    let start = buffer
+        .iter()
+        .position(|&b| b != b'\r' && b != b'\n')
+        .unwrap_or(buffer.len());
+    let buffer_after_start = &buffer[start..];
+    let Some(head_len) = find_head_end(buffer_after_start) else {
+        if buffer.len() > MAX_HEAD_BYTES {
+            return Err(ParseError::HeadersTooLarge);
+        }
+        return Ok(None);
+    };
+    if start + head_len > MAX_HEAD_BYTES {
+        return Err(ParseError::HeadersTooLarge);
+    }
+
+    let head = std::str::from_utf8(&buffer_after_start[..head_len])
+        .map_err(|_| ParseError::BadRequest("head is not UTF-8"))?;
+    let mut lines = head
+        .split('\n')
+        .map(|line| line.strip_suffix('\r').unwrap_or(line));
+    let (method, target, minor_version) = parse_request_line(lines.next().unwrap_or_default())?;
+    let mut headers = Vec::new();
+    for line in lines.take_while(|line| !line.is_empty()) {
+        headers.push(parse_header(line)?);
+    }
+
+    let (path, query) = parse_target(target)?;
+    let mut request = Request {
+        method,
+        path,
+        query,
+        headers,
+        body: Vec::new(),
+        minor_version,
+    };
+    if minor_version == 1 && request.header("host").is_none() {
+        return Err(ParseError::BadRequest("missing Host header"));
+    }
+
+    let body_start = start + head_len;
+    let body_len = match body_framing(&request)? {
+        BodyFraming::Empty => 0,
+        BodyFraming::Length(len) => {
+            if len > MAX_BODY_BYTES {
+                return Err(ParseError::BodyTooLarge);
+            }
+            let Some(body) = buffer[body_start..].get(..len) else {
+                return Ok(None);
+            };
+            request.body = body.to_vec();
+            len
+        }
+        BodyFraming::Chunked => match parse_chunked(&buffer[body_start..])? {
+            Some((body, len)) => {
+                request.body = body;
+                len
+            }
+            None => return Ok(None),
+        },
+    };
+
+    Ok(Some((request, body_start + body_len)))
+}
+
+// the length of the head including the empty line ending it
+fn find_head_end(buffer: &[u8]) -> Option<usize> {
+    let mut line_start = 0;
+    for (i, &b) in buffer.iter().enumerate() {
+        if b != b'\n' {
+            continue;
+        }
+        let line = &buffer[line_start..i];
+        if line.is_empty() || line == b"\r" {
+            return Some(i + 1);
+        }
+        line_start = i + 1;
+    }
+    None
+}
+
+fn parse_request_line(line: &str) -> Result<(Method, &str, u8), ParseError> {
+    let mut parts = line.split(' ');
+    let (Some(method), Some(target), Some(version), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return Err(ParseError::BadRequest("invalid request line"));
+    };
+    let minor_version = match version {
+        "HTTP/1.1" => 1,
+        "HTTP/1.0" => 0,
+        _ => return Err(ParseError::BadRequest("unsupported HTTP version")),
+    };
+
+    Ok((Method::parse(method)?, target, minor_version))
+}
+
+fn parse_header(line: &str) -> Result<(String, String), ParseError> {
+    // a line folded onto the previous one, obsolete and rejected (RFC 9112 section 5.2)
+    if line.starts_with([' ', '\t']) {
+        return Err(ParseError::BadRequest("folded header line"));
+    }
+    let (name, value) = line
+        .split_once(':')
+        .ok_or(ParseError::BadRequest("header without a colon"))?;
+    if name.is_empty() || !name.bytes().all(is_token_byte) {
+        return Err(ParseError::BadRequest("invalid header name"));
+    }
+    let value = value.trim_matches([' ', '\t']);
+    if value.bytes().any(|b| b.is_ascii_control() && b != b'\t') {
+        return Err(ParseError::BadRequest("invalid header value"));
+    }
+
+    Ok((name.to_ascii_lowercase(), value.to_owned()))
+}
+
+fn is_token_byte(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)
+}
+
+// origin-form only, `/path?query`. `*` is taken for OPTIONS
+fn parse_target(target: &str) -> Result<(String, Vec<(String, String)>), ParseError> {
+    if target == "*" {
+        return Ok(("*".to_owned(), Vec::new()));
+    }
+    if !target.starts_with('/') {
+        return Err(ParseError::BadRequest("request target must start with /"));
+    }
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let path = percent_decode(path, false)?;
+    let query = query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            Ok((percent_decode(name, true)?, percent_decode(value, true)?))
+        })
+        .collect::<Result<_, ParseError>>()?;
+
+    Ok((path, query))
+}
+
+fn percent_decode(s: &str, plus_is_space: bool) -> Result<String, ParseError> {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => {
+                let hex = bytes
+                    .get(i + 1..i + 3)
+                    .and_then(|hex| std::str::from_utf8(hex).ok())
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                    .ok_or(ParseError::BadRequest("invalid percent encoding"))?;
+                decoded.push(hex);
+                i += 3;
+            }
+            b'+' if plus_is_space => {
+                decoded.push(b' ');
+                i += 1;
+            }
+            b => {
+                decoded.push(b);
+                i += 1;
+            }
+        }
+    }
+
+    String::from_utf8(decoded).map_err(|_| ParseError::BadRequest("percent encoding is not UTF-8"))
+}
+
+/// `s` as a query string name or value, the unreserved characters of RFC 3986 left as they are.
+pub fn percent_encode(s: &str) -> String {
+    let mut encoded = String::with_capacity(s.len());
+    for b in s.bytes() {
+        if b.is_ascii_alphanumeric() || matches!(b, b'-' | b'.' | b'_' | b'~') {
+            encoded.push(b as char);
+        } else {
+            encoded.push_str(&format!("%{b:02X}"));
+        }
+    }
+
+    encoded
+}
+
+enum BodyFraming {
+    Empty,
+    Length(usize),
+    Chunked,
+}
+
+fn body_framing(request: &Request) -> Result<BodyFraming, ParseError> {
+    let transfer_encoding = request.header("transfer-encoding");
+    let lengths: Vec<&str> = request
+        .headers
+        .iter()
+        .filter(|(name, _)| name == "content-length")
+        .flat_map(|(_, value)| value.split(','))
+        .map(str::trim)
+        .collect();
+
+    // both at once is how requests get smuggled past proxies, refuse it (RFC 9112 section 6.1)
+    match (transfer_encoding, lengths.as_slice()) {
+        (Some(_), [_, ..]) => Err(ParseError::BadRequest(
+            "both Transfer-Encoding and Content-Length",
+        )),
+        (Some(coding), []) if coding.eq_ignore_ascii_case("chunked") => Ok(BodyFraming::Chunked),
+        (Some(_), []) => Err(ParseError::NotImplemented),
+        (None, []) => Ok(BodyFraming::Empty),
+        (None, [first, rest @ ..]) => {
+            if rest.iter().any(|len| len != first) {
+                return Err(ParseError::BadRequest("conflicting Content-Length"));
+            }
+            if first.is_empty() || !first.bytes().all(|b| b.is_ascii_digit()) {
+                return Err(ParseError::BadRequest("invalid Content-Length"));
+            }
+            // too many digits for usize is too large anyway
+            let len = first.parse().map_err(|_| ParseError::BodyTooLarge)?;
+            Ok(BodyFraming::Length(len))
+        }
+    }
+}
+
+// the de-chunked body and how many bytes the chunks took, trailers are read and dropped
+fn parse_chunked(buffer: &[u8]) -> Result<Option<(Vec<u8>, usize)>, ParseError> {
+    // all of it within MAX_CHUNKED_BYTES, or not at all
+    match dechunk(&buffer[..buffer.len().min(MAX_CHUNKED_BYTES)])? {
+        None if buffer.len() > MAX_CHUNKED_BYTES => Err(ParseError::BodyTooLarge),
+        chunked => Ok(chunked),
+    }
+}
+
+fn dechunk(buffer: &[u8]) -> Result<Option<(Vec<u8>, usize)>, ParseError> {
+    let mut body = Vec::new();
+    let mut pos = 0;
+    loop {
+        let Some(line) = next_line(&buffer[pos..])? else {
+            return Ok(None);
+        };
+        pos += line.len;
+        // chunk extensions after ';' are allowed and ignored
+        let size = line
+            .text
+            .split(';')
+            .next()
+            .unwrap_or_default()
+            .trim_end_matches([' ', '\t']);
+        if size.is_empty() || !size.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(ParseError::BadRequest("invalid chunk size"));
+        }
+        let size = usize::from_str_radix(size, 16).map_err(|_| ParseError::BodyTooLarge)?;
+        if size == 0 {
+            break;
+        }
+        if body.len() + size > MAX_BODY_BYTES {
+            return Err(ParseError::BodyTooLarge);
+        }
+
+        let Some(data) = buffer[pos..].get(..size) else {
+            return Ok(None);
+        };
+        body.extend_from_slice(data);
+        pos += size;
+        match buffer[pos..] {
+            [] | [b'\r'] => return Ok(None),
+            [b'\r', b'\n', ..] => pos += 2,
+            [b'\n', ..] => pos += 1,
+            _ => return Err(ParseError::BadRequest("chunk not followed by a line break")),
+        }
+    }
+
+    let trailers_start = pos;
+    loop {
+        let Some(line) = next_line(&buffer[pos..])? else {
+            return Ok(None);
+        };
+        pos += line.len;
+        if line.text.is_empty() {
+            return Ok(Some((body, pos)));
+        }
+        parse_header(line.text)?;
+        if pos - trailers_start > MAX_HEAD_BYTES {
+            return Err(ParseError::HeadersTooLarge);
+        }
+    }
+}
+
+struct Line<'a> {
+    text: &'a str,
+    /// With the line break.
+    len: usize,
+}
+
+fn next_line(buffer: &[u8]) -> Result<Option<Line<'_>>, ParseError> {
+    let Some(end) = buffer.iter().position(|&b| b == b'\n') else {
+        if buffer.len() > MAX_HEAD_BYTES {
+            return Err(ParseError::HeadersTooLarge);
+        }
+        return Ok(None);
+    };
+    let line = buffer[..end].strip_suffix(b"\r").unwrap_or(&buffer[..end]);
+    let text =
+        std::str::from_utf8(line).map_err(|_| ParseError::BadRequest("line is not UTF-8"))?;
+
+    Ok(Some(Line { text, len: end + 1 }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const POST: &[u8] = b"POST /users?page=2&q=a+b%21 HTTP/1.1\r\nHost: localhost\r\n\
+        Content-Type: application/json\r\nContent-Length: 13\r\n\r\n{\"name\":\"a\"}\n";
+    const CHUNKED: &[u8] =
+        b"PUT /users/1 HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: chunked\r\n\r\n\
+        5;ext=1\r\nhello\r\n7\r\n, world\r\n0\r\nX-Trailer: 1\r\n\r\n";
+
+    fn parse_all(bytes: &[u8]) -> Result<Request, ParseError> {
+        let (request, len) = parse(bytes)?.expect("a whole request");
+        assert_eq!(len, bytes.len());
+        Ok(request)
+    }
+
+    #[test]
+    fn test_parse_request() {
+        let request = parse_all(POST).unwrap();
+        assert_eq!(request.method, Method::Post);
+        assert_eq!(request.path, "/users");
+        assert_eq!(
+            request.query,
+            [
+                ("page".to_owned(), "2".to_owned()),
+                ("q".to_owned(), "a b!".to_owned())
+            ]
+        );
+        assert_eq!(request.header("content-type"), Some("application/json"));
+        assert_eq!(request.query_param("q"), Some("a b!"));
+        assert_eq!(request.body, b"{\"name\":\"a\"}\n");
+
+        let request = parse_all(CHUNKED).unwrap();
+        assert_eq!(request.body, b"hello, world");
+    }
+
+    #[test]
+    fn test_rejects_malformed() {
+        let cases: [(&[u8], u16); 12] = [
+            (b"GET /users\r\n\r\n", 400),
+            (b"GET users HTTP/1.1\r\nHost: x\r\n\r\n", 400),
+            (b"GET /users HTTP/2.0\r\nHost: x\r\n\r\n", 400),
+            (b"GET /users HTTP/1.1\r\n\r\n", 400),
+            (b"GET /users HTTP/1.1\r\nHost: x\r\nBad Name: 1\r\n\r\n", 400),
+            (b"GET /users HTTP/1.1\r\nHost: x\r\n folded\r\n\r\n", 400),
+            (b"GET /%zz HTTP/1.1\r\nHost: x\r\n\r\n", 400),
+            (b"POST /users HTTP/1.1\r\nHost: x\r\nContent-Length: 1\r\nContent-Length: 2\r\n\r\nab", 400),
+            (b"POST /users HTTP/1.1\r\nHost: x\r\nContent-Length: 1\r\nTransfer-Encoding: chunked\r\n\r\n", 400),
+            (b"POST /users HTTP/1.1\r\nHost: x\r\nContent-Length: 99999999\r\n\r\n", 413),
+            (b"POST /users HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: gzip\r\n\r\n", 501),
+            (b"BREW /pot HTTP/1.1\r\nHost: x\r\n\r\n", 501),
+        ];
+        for (bytes, status) in cases {
+            let error = parse(bytes).unwrap_err();
+            assert_eq!(error.status(), status, "{}", String::from_utf8_lossy(bytes));
+        }
+
+        let mut huge_header = b"GET / HTTP/1.1\r\nHost: x\r\nX-Big: ".to_vec();
+        huge_header.extend(vec![b'a'; MAX_HEAD_BYTES]);
+        assert_eq!(parse(&huge_header), Err(ParseError::HeadersTooLarge));
+        let mut huge_chunk =
+            b"POST / HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: chunked\r\n\r\n".to_vec();
+        huge_chunk.extend(format!("{:x}\r\n", MAX_BODY_BYTES + 1).as_bytes());
+        assert_eq!(parse(&huge_chunk), Err(ParseError::BodyTooLarge));
+
+        // bytes that never make a request are limited too, not only the request they make
+        let empty_lines = b"\r\n".repeat(MAX_HEAD_BYTES / 2 + 1);
+        assert_eq!(parse(&empty_lines), Err(ParseError::HeadersTooLarge));
+        let mut tiny_chunks =
+            b"POST / HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: chunked\r\n\r\n".to_vec();
+        let chunk = format!("1;{}\r\na\r\n", "e".repeat(1000));
+        while tiny_chunks.len() <= MAX_CHUNKED_BYTES {
+            tiny_chunks.extend(chunk.as_bytes());
+        }
+        assert_eq!(parse(&tiny_chunks), Err(ParseError::BodyTooLarge));
+    }
+
+    #[test]
+    fn test_pipelined_requests() {
+        let mut bytes = POST.to_vec();
+        bytes.extend_from_slice(CHUNKED);
+        let (first, len) = parse(&bytes).unwrap().unwrap();
+        assert_eq!(first.method, Method::Post);
+        let (second, _) = parse(&bytes[len..]).unwrap().unwrap();
+        assert_eq!(second.method, Method::Put);
+    }
+
+    #[test]
+    fn test_percent_encode_round_trip() {
+        let value = "a+b c&d=é/~";
+        assert_eq!(percent_encode(value), "a%2Bb%20c%26d%3D%C3%A9%2F~");
+        assert_eq!(percent_decode(&percent_encode(value), true).unwrap(), value);
+    }
+
+    #[test]
+    fn test_keep_alive() {
+        let cases = [
+            ("HTTP/1.1\r\nHost: x", true),
+            ("HTTP/1.1\r\nHost: x\r\nConnection: close", false),
+            ("HTTP/1.1\r\nHost: x\r\nConnection: Upgrade, Close", false),
+            ("HTTP/1.0", false),
+            ("HTTP/1.0\r\nConnection: Keep-Alive", true),
+        ];
+        for (rest, keep_alive) in cases {
+            let bytes = format!("GET / {rest}\r\n\r\n");
+            let request = parse_all(bytes.as_bytes()).unwrap();
+            assert_eq!(request.keep_alive(), keep_alive, "{rest}");
+        }
+    }
+
+    #[test]
+    fn test_read_request_in_small_reads() {
+        // a reader that hands out a few bytes at a time, like a slow client
+        struct Trickle<'a>(&'a [u8]);
+        impl Read for Trickle<'_> {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                let n = self.0.len().min(buf.len()).min(3);
+                buf[..n].copy_from_slice(&self.0[..n]);
+                self.0 = &self.0[n..];
+                Ok(n)
+            }
+        }
+
+        let mut buffer = Vec::new();
+        let mut stream = Trickle(CHUNKED);
+        let request = read_request(&mut stream, &mut buffer).unwrap().unwrap();
+        assert_eq!(request.body, b"hello, world");
+        assert!(read_request(&mut stream, &mut buffer).unwrap().is_none());
+
+        let mut truncated = Trickle(&POST[..POST.len() - 1]);
+        let result = read_request(&mut truncated, &mut Vec::new());
+        assert!(matches!(
+            result,
+            Err(ReadError::Parse(ParseError::BadRequest(_)))
+        ));
+    }
+
+    #[test]
+    fn test_every_prefix_is_incomplete() {
+        for request in [POST, CHUNKED] {
+            for end in 0..request.len() {
+                assert_eq!(parse(&request[..end]), Ok(None), "prefix of {end} bytes");
+            }
+        }
+    }
+
+    // xorshift, so the fuzz cases are the same on every run
+    fn next_random(state: &mut u64) -> u64 {
+        *state ^= *state << 13;
+        *state ^= *state >> 7;
+        *state ^= *state << 17;
+        *state
+    }
+
+    #[test]
+    fn test_fuzz_mutations_never_panic() {
+        let mut state = 0x9E37_79B9_7F4A_7C15;
+        let alphabet = b"\r\n :;/?%&=+0123456789abcdefABCDEF\x00\xff";
+        for _ in 0..20_000 {
+            let mut bytes = if next_random(&mut state).is_multiple_of(2) {
+                POST
+            } else {
+                CHUNKED
+            }
+            .to_vec();
+            for _ in 0..1 + next_random(&mut state) % 4 {
+                let at = (next_random(&mut state) as usize) % bytes.len();
+                let byte = alphabet[(next_random(&mut state) as usize) % alphabet.len()];
+                match next_random(&mut state) % 3 {
+                    0 => bytes[at] = byte,
+                    1 => bytes.insert(at, byte),
+                    _ => {
+                        bytes.remove(at);
+                    }
+                }
+            }
+
+            // whatever it is, a request taking no more bytes than there are, more bytes or an error
+            if let Ok(Some((request, len))) = parse(&bytes) {
+                assert!(len <= bytes.len());
+                assert!(request.body.len() <= MAX_BODY_BYTES);
+            }
+        }
+    }
+}
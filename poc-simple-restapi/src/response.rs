@@ -0,0 +1,147 @@
+use serde::Serialize;
+use serde_json::json;
+use std::io::{self, Write};
+use std::time::SystemTime;
+
+// every response is framed by Content-Length, so the client knows where the body ends
+// without waiting for the connection to close
+
+#[derive(Debug)]
+pub struct Response {
+    pub status: u16,
+    /// Besides Content-Length, Date and Connection, which `write_to` sets.
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    pub fn new(status: u16) -> Self {
+        Response {
+            status,
+            headers: Vec::new(),
+            body: Vec::new(),
+        }
+    }
+
+    /// `value` as the JSON body.
+    pub fn json(status: u16, value: &impl Serialize) -> Self {
+        match serde_json::to_vec(value) {
+            Ok(body) => Response {
+                body,
+                ..Response::new(status)
+            }
+            .header("Content-Type", "application/json"),
+            Err(e) => Response::error(500, &format!("Failed to serialize response: {e}")),
+        }
+    }
+
+    /// `{"message": ...}`, for a success without a resource to return.
+    pub fn message(status: u16, message: &str) -> Self {
+        Response::json(status, &json!({ "message": message }))
+    }
+
+    /// `{"error": ...}`, the body of every error.
+    pub fn error(status: u16, message: &str) -> Self {
+        Response::json(status, &json!({ "error": message }))
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_owned(), value.to_owned()));
+        self
+    }
+
+    /// Writes the response as HTTP/1.1, saying whether the connection stays open after it.
+    pub fn write_to(&self, writer: &mut impl Write, keep_alive: bool) -> io::Result<()> {
+        self.write(writer, keep_alive, true)
+    }
+
+    /// Writes the response to a HEAD request: all but the body, with the body's Content-Length.
+    pub fn write_head_to(&self, writer: &mut impl Write, keep_alive: bool) -> io::Result<()> {
+        self.write(writer, keep_alive, false)
+    }
+
+    fn write(&self, writer: &mut impl Write, keep_alive: bool, with_body: bool) -> io::Result<()> {
+        let mut head = format!("HTTP/1.1 {} {}\r\n", self.status, reason(self.status));
+        for (name, value) in &self.headers {
+            head.push_str(&format!("{name}: {value}\r\n"));
+        }
+        // a 204 has no body, not even an empty one
+        if self.status != 204 {
+            head.push_str(&format!("Content-Length: {}\r\n", self.body.len()));
+        }
+        head.push_str(&format!(
+            "Date: {}\r\n",
+            httpdate::fmt_http_date(SystemTime::now())
+        ));
+        let connection = if keep_alive { "keep-alive" } else { "close" };
+        head.push_str(&format!("Connection: {connection}\r\n\r\n"));
+
+        let mut bytes = head.into_bytes();
+        if with_body {
+            bytes.extend_from_slice(&self.body);
+        }
+        writer.write_all(&bytes)?;
+        writer.flush()
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        201 => "Created",
+        204 => "No Content",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        409 => "Conflict",
+        413 => "Content Too Large",
+        422 => "Unprocessable Content",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        503 => "Service Unavailable",
+        _ => "",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_to() {
+        let response = Response::error(404, "User not found").header("X-Id", "7");
+        let mut bytes = Vec::new();
+        response.write_to(&mut bytes, false).unwrap();
+        let text = String::from_utf8(bytes).unwrap();
+
+        let (head, body) = text.split_once("\r\n\r\n").unwrap();
+        let lines: Vec<&str> = head.split("\r\n").collect();
+        assert_eq!(lines[0], "HTTP/1.1 404 Not Found");
+        assert_eq!(&lines[1..3], ["Content-Type: application/json", "X-Id: 7"]);
+        assert_eq!(lines[3], format!("Content-Length: {}", body.len()));
+        assert!(lines[4].starts_with("Date: ") && lines[4].ends_with(" GMT"));
+        assert_eq!(lines[5], "Connection: close");
+        assert_eq!(body, r#"{"error":"User not found"}"#);
+    }
+
+    #[test]
+    fn test_write_head_to() {
+        let mut bytes = Vec::new();
+        let response = Response::message(200, "hello");
+        response.write_head_to(&mut bytes, true).unwrap();
+        let text = String::from_utf8(bytes).unwrap();
+        assert!(text.contains("Content-Length: 19\r\n"));
+        assert!(text.ends_with("\r\n\r\n"));
+    }
+
+    #[test]
+    fn test_empty_body() {
+        let mut bytes = Vec::new();
+        Response::new(204).write_to(&mut bytes, true).unwrap();
+        let text = String::from_utf8(bytes).unwrap();
+        assert!(text.starts_with("HTTP/1.1 204 No Content\r\nDate: "));
+        assert!(text.ends_with("Connection: keep-alive\r\n\r\n"));
+    }
+}
@@ -0,0 +1,257 @@
+use crate::request::{Method, Request};
+use std::str::FromStr;
+
+// routes are (method, pattern) pairs, a pattern segment in braces is a parameter: /users/{id}.
+// the query string is not part of the match, it is already split off into Request::query.
+// a GET route answers HEAD too, and every path with a route allows OPTIONS
+
+type Handler<T> = Box<dyn Fn(&Request, &Params) -> T + Send + Sync>;
+
+pub struct Router<T> {
+    routes: Vec<Route<T>>,
+}
+
+struct Route<T> {
+    method: Method,
+    pattern: Vec<Segment>,
+    handler: Handler<T>,
+}
+
+#[derive(Debug, PartialEq)]
+enum Segment {
+    Literal(String),
+    Param(String),
+}
+
+#[derive(Debug, PartialEq)]
+pub enum RouteError {
+    NotFound,
+    /// The path exists, with other methods: the ones of the `Allow` header, which is also the
+    /// answer to OPTIONS.
+    MethodNotAllowed(Vec<Method>),
+}
+
+/// The path parameters of the route that matched, by name.
+#[derive(Debug, Default, PartialEq)]
+pub struct Params(Vec<(String, String)>);
+
+#[derive(Debug, PartialEq)]
+pub struct ParamError {
+    pub name: String,
+}
+
+impl Params {
+    /// The parameter parsed as `T`, an error when it is missing or does not parse.
+    pub fn get<T: FromStr>(&self, name: &str) -> Result<T, ParamError> {
+        self.0
+            .iter()
+            .find(|(n, _)| n == name)
+            .and_then(|(_, value)| value.parse().ok())
+            .ok_or_else(|| ParamError {
+                name: name.to_owned(),
+            })
+    }
+}
+
+impl<T> Router<T> {
+    pub fn new() -> Self {
+        Router { routes: Vec::new() }
+    }
+
+    /// Adds a route, `pattern` like `/users/{id}`.
+    pub fn route(
+        mut self,
+        method: Method,
+        pattern: &str,
+        handler: impl Fn(&Request, &Params) -> T + Send + Sync + 'static,
+    ) -> Self {
+        let pattern = segments(pattern)
+            .map(
+                |segment| match segment.strip_prefix('{').and_then(|s| s.strip_suffix('}')) {
+                    Some(name) => Segment::Param(name.to_owned()),
+                    None => Segment::Literal(segment.to_owned()),
+                },
+            )
+            .collect();
+        self.routes.push(Route {
+            method,
+            pattern,
+            handler: Box::new(handler),
+        });
+        self
+    }
+
+    /// Runs the handler of the route matching the method and path of `request`. HEAD runs the
+    /// GET handler when there is no HEAD route, the caller leaves the body out.
+    pub fn handle(&self, request: &Request) -> Result<T, RouteError> {
+        let found = self.find(request.method, &request.path).or_else(|| {
+            (request.method == Method::Head)
+                .then(|| self.find(Method::Get, &request.path))
+                .flatten()
+        });
+        if let Some((route, params)) = found {
+            return Ok((route.handler)(request, &params));
+        }
+
+        let allowed = self.allowed(&request.path);
+        if allowed.is_empty() {
+            return Err(RouteError::NotFound);
+        }
+        Err(RouteError::MethodNotAllowed(allowed))
+    }
+
+    /// The methods of every route, for `OPTIONS *`.
+    pub fn methods(&self) -> Vec<Method> {
+        with_implied(self.routes.iter().map(|route| route.method))
+    }
+
+    fn allowed(&self, path: &str) -> Vec<Method> {
+        with_implied(
+            self.routes
+                .iter()
+                .filter(|route| route.matches(path).is_some())
+                .map(|route| route.method),
+        )
+    }
+
+    fn find(&self, method: Method, path: &str) -> Option<(&Route<T>, Params)> {
+        self.routes
+            .iter()
+            .filter(|route| route.method == method)
+            .find_map(|route| Some((route, route.matches(path)?)))
+    }
+}
+
+// each method once, HEAD after GET and OPTIONS last, nothing when there is no route
+fn with_implied(methods: impl Iterator<Item = Method>) -> Vec<Method> {
+    let mut allowed = Vec::new();
+    for method in methods {
+        let implied = match method {
+            Method::Get => [Method::Get, Method::Head].as_slice(),
+            method => &[method],
+        };
+        for &method in implied {
+            if !allowed.contains(&method) {
+                allowed.push(method);
+            }
+        }
+    }
+    if !allowed.is_empty() && !allowed.contains(&Method::Options) {
+        allowed.push(Method::Options);
+    }
+
+    allowed
+}
+
+impl<T> Route<T> {
+    fn matches(&self, path: &str) -> Option<Params> {
+        let mut params = Vec::new();
+        let mut segments = segments(path);
+        for expected in &self.pattern {
+            let segment = segments.next()?;
+            match expected {
+                Segment::Literal(literal) if literal == segment => {}
+                Segment::Literal(_) => return None,
+                // an empty segment, as in /users/, is not a value
+                Segment::Param(_) if segment.is_empty() => return None,
+                Segment::Param(name) => params.push((name.clone(), segment.to_owned())),
+            }
+        }
+        if segments.next().is_some() {
+            return None;
+        }
+
+        Some(Params(params))
+    }
+}
+
+// "/" has no segments, "/users/" has "users" and ""
+fn segments(path: &str) -> impl Iterator<Item = &str> {
+    path.strip_prefix('/')
+        .unwrap_or(path)
+        .split('/')
+        .filter(move |_| path != "/")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(method: Method, target: &str) -> Request {
+        let bytes = format!("{method} {target} HTTP/1.1\r\nHost: x\r\n\r\n");
+        crate::request::parse(bytes.as_bytes()).unwrap().unwrap().0
+    }
+
+    fn router() -> Router<String> {
+        Router::new()
+            .route(Method::Get, "/", |_, _| "root".to_owned())
+            .route(Method::Get, "/users", |_, _| "list".to_owned())
+            .route(Method::Get, "/users/{id}", |_, params| {
+                match params.get::<i32>("id") {
+                    Ok(id) => format!("user {id}"),
+                    Err(e) => format!("bad {}", e.name),
+                }
+            })
+            .route(Method::Delete, "/users/{id}", |_, _| "delete".to_owned())
+    }
+
+    #[test]
+    fn test_matches_routes_and_params() {
+        let router = router();
+        let handle = |method, target| router.handle(&request(method, target));
+        assert_eq!(handle(Method::Get, "/"), Ok("root".to_owned()));
+        assert_eq!(handle(Method::Get, "/users"), Ok("list".to_owned()));
+        assert_eq!(handle(Method::Get, "/users?limit=2"), Ok("list".to_owned()));
+        assert_eq!(handle(Method::Get, "/users/7"), Ok("user 7".to_owned()));
+        assert_eq!(handle(Method::Get, "/users/abc"), Ok("bad id".to_owned()));
+        assert_eq!(handle(Method::Delete, "/users/7"), Ok("delete".to_owned()));
+    }
+
+    #[test]
+    fn test_not_found_and_method_not_allowed() {
+        let router = router();
+        let handle = |method, target| router.handle(&request(method, target));
+        for target in ["/usersXYZ", "/users/", "/users/7/x", "/other"] {
+            assert_eq!(
+                handle(Method::Get, target),
+                Err(RouteError::NotFound),
+                "{target}"
+            );
+        }
+        assert_eq!(
+            handle(Method::Put, "/users/7"),
+            Err(RouteError::MethodNotAllowed(vec![
+                Method::Get,
+                Method::Head,
+                Method::Delete,
+                Method::Options
+            ]))
+        );
+    }
+
+    #[test]
+    fn test_head_and_options() {
+        let router = router();
+        let handle = |method, target| router.handle(&request(method, target));
+        assert_eq!(handle(Method::Head, "/users/7"), Ok("user 7".to_owned()));
+        assert_eq!(
+            handle(Method::Options, "/users"),
+            Err(RouteError::MethodNotAllowed(vec![
+                Method::Get,
+                Method::Head,
+                Method::Options
+            ]))
+        );
+        assert_eq!(handle(Method::Options, "/other"), Err(RouteError::NotFound));
+
+        let router = router.route(Method::Head, "/users", |_, _| "head".to_owned());
+        assert_eq!(
+            router.handle(&request(Method::Head, "/users")),
+            Ok("head".to_owned())
+        );
+        assert_eq!(
+            router.methods(),
+            [Method::Get, Method::Head, Method::Delete, Method::Options]
+        );
+    }
+}
@@ -0,0 +1,127 @@
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+// a fixed number of workers taking items off a bounded queue. when the queue is full the item is
+// handed back instead of waiting, so the caller can turn it away (503) rather than pile up work
+
+pub struct ThreadPool<T> {
+    // None once shutting down, dropping it tells the workers to stop when the queue is empty
+    sender: Option<SyncSender<T>>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl<T: Send + 'static> ThreadPool<T> {
+    /// `size` workers running `handler` on each item, at most `queue_capacity` items waiting.
+    pub fn new(
+        size: usize,
+        queue_capacity: usize,
+        handler: impl Fn(T) + Send + Sync + 'static,
+    ) -> Self {
+        assert!(size > 0, "a thread pool needs at least one worker");
+        let (sender, receiver) = mpsc::sync_channel(queue_capacity);
+        let receiver = Arc::new(Mutex::new(receiver));
+        let handler = Arc::new(handler);
+
+        let workers = (0..size)
+            .map(|id| {
+                let receiver = Arc::clone(&receiver);
+                let handler = Arc::clone(&handler);
+                thread::Builder::new()
+                    .name(format!("worker-{id}"))
+                    .spawn(move || work(&receiver, &*handler))
+                    .expect("Failed to spawn worker thread")
+            })
+            .collect();
+
+        ThreadPool {
+            sender: Some(sender),
+            workers,
+        }
+    }
+
+    /// Queues `item`, or gives it back when the queue is full.
+    pub fn try_execute(&self, item: T) -> Result<(), T> {
+        let sender = self.sender.as_ref().expect("pool is running until dropped");
+        match sender.try_send(item) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(item) | TrySendError::Disconnected(item)) => Err(item),
+        }
+    }
+}
+
+fn work<T>(receiver: &Mutex<Receiver<T>>, handler: &(impl Fn(T) + ?Sized)) {
+    loop {
+        // the lock is only held while waiting for the next item, not while handling it
+        let item = receiver
+            .lock()
+            .expect("worker panicked holding the queue")
+            .recv();
+        match item {
+            Ok(item) => handler(item),
+            // the pool was dropped and the queue is empty
+            Err(_) => return,
+        }
+    }
+}
+
+impl<T> Drop for ThreadPool<T> {
+    /// Lets the workers finish what is queued and in flight, then joins them.
+    fn drop(&mut self) {
+        drop(self.sender.take());
+        for worker in self.workers.drain(..) {
+            if worker.join().is_err() {
+                println!("Worker thread panicked");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    #[test]
+    fn test_runs_everything_before_shutdown() {
+        let done = Arc::new(AtomicUsize::new(0));
+        let pool = {
+            let done = Arc::clone(&done);
+            ThreadPool::new(4, 100, move |n: usize| {
+                thread::sleep(Duration::from_millis(1));
+                done.fetch_add(n, Ordering::SeqCst);
+            })
+        };
+        for n in 1..=100 {
+            pool.try_execute(n).unwrap();
+        }
+        drop(pool);
+        assert_eq!(done.load(Ordering::SeqCst), 5050);
+    }
+
+    #[test]
+    fn test_gives_items_back_when_full() {
+        let (release, released) = mpsc::channel::<()>();
+        let released = Mutex::new(released);
+        let pool = ThreadPool::new(1, 1, move |_: u32| {
+            released.lock().unwrap().recv().unwrap();
+        });
+
+        // one item on the worker, one in the queue, the next has no room
+        pool.try_execute(1).unwrap();
+        let mut queued = false;
+        for _ in 0..100 {
+            if pool.try_execute(2).is_ok() {
+                queued = true;
+                break;
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert!(queued);
+        assert_eq!(pool.try_execute(3), Err(3));
+
+        release.send(()).unwrap();
+        release.send(()).unwrap();
+    }
+}
@@ -0,0 +1,439 @@
+use crate::db_pool::{Pool, PoolError, Postgres};
+use postgres::error::SqlState;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+// where the users are kept, the handlers only see UserRepository. PostgresUsers for the server,
+// MemoryUsers for tests. emails are unique regardless of case, Ana@x.io and ana@x.io are one user
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct User {
+    /// Set by the repository, ignored in a request body.
+    pub id: Option<i32>,
+    pub name: String,
+    pub email: String,
+}
+
+/// The fields of a PATCH, the ones left out keep their value.
+#[derive(Debug, Default, Deserialize)]
+pub struct UserPatch {
+    pub name: Option<String>,
+    pub email: Option<String>,
+}
+
+/// Which users `list` returns, by id.
+#[derive(Debug)]
+pub struct UserQuery {
+    /// Matched regardless of case, like the unique index.
+    pub email: Option<String>,
+    pub limit: i64,
+    pub offset: i64,
+}
+
+impl User {
+    /// Why the user cannot be stored, if it cannot.
+    pub fn validate(&self) -> Result<(), &'static str> {
+        validate_name(&self.name)?;
+        validate_email(&self.email)
+    }
+}
+
+impl UserPatch {
+    pub fn validate(&self) -> Result<(), &'static str> {
+        self.name.as_deref().map_or(Ok(()), validate_name)?;
+        self.email.as_deref().map_or(Ok(()), validate_email)
+    }
+}
+
+fn validate_name(name: &str) -> Result<(), &'static str> {
+    if name.trim().is_empty() {
+        return Err("Name is empty");
+    }
+
+    Ok(())
+}
+
+fn validate_email(email: &str) -> Result<(), &'static str> {
+    if !is_email(email) {
+        return Err("Email is not valid");
+    }
+
+    Ok(())
+}
+
+// the shape of an address, local@domain.tld, not whether it exists
+fn is_email(email: &str) -> bool {
+    let Some((local, domain)) = email.split_once('@') else {
+        return false;
+    };
+    let labels_ok = domain.split('.').all(|label| {
+        !label.is_empty()
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+            && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+    });
+
+    email.len() <= 254
+        && !local.is_empty()
+        && local.len() <= 64
+        && !local
+            .chars()
+            .any(|c| c.is_whitespace() || c.is_control() || c == '@')
+        && domain.contains('.')
+        && labels_ok
+}
+
+#[derive(Debug)]
+pub enum RepositoryError {
+    NotFound,
+    /// Another user has the email.
+    EmailTaken,
+    /// No connection, or a query that failed.
+    Database(Box<dyn Error + Send + Sync>),
+}
+
+impl fmt::Display for RepositoryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RepositoryError::NotFound => write!(f, "User not found"),
+            RepositoryError::EmailTaken => write!(f, "Email is taken by another user"),
+            RepositoryError::Database(e) => write!(f, "Database error: {e}"),
+        }
+    }
+}
+
+impl From<postgres::Error> for RepositoryError {
+    fn from(e: postgres::Error) -> Self {
+        if e.code() == Some(&SqlState::UNIQUE_VIOLATION) {
+            return RepositoryError::EmailTaken;
+        }
+        RepositoryError::Database(Box::new(e))
+    }
+}
+
+impl From<PoolError<postgres::Error>> for RepositoryError {
+    fn from(e: PoolError<postgres::Error>) -> Self {
+        RepositoryError::Database(Box::new(e))
+    }
+}
+
+pub trait UserRepository: Send + Sync {
+    /// The user as stored, with its new id. `user` is already validated.
+    fn create(&self, user: &User) -> Result<User, RepositoryError>;
+    fn get(&self, id: i32) -> Result<User, RepositoryError>;
+    fn list(&self, query: &UserQuery) -> Result<Vec<User>, RepositoryError>;
+    /// Replaces the name and email of user `id`.
+    fn update(&self, id: i32, user: &User) -> Result<User, RepositoryError>;
+    /// Changes the fields of user `id` that `patch` has.
+    fn patch(&self, id: i32, patch: &UserPatch) -> Result<User, RepositoryError>;
+    fn delete(&self, id: i32) -> Result<(), RepositoryError>;
+}
+
+pub struct PostgresUsers {
+    db: Arc<Pool<Postgres>>,
+}
+
+impl PostgresUsers {
+    pub fn new(db: Arc<Pool<Postgres>>) -> Self {
+        PostgresUsers { db }
+    }
+
+    pub fn create_table(&self) -> Result<(), RepositoryError> {
+        self.db.get()?.batch_execute(
+            "CREATE TABLE IF NOT EXISTS users (
+                id SERIAL PRIMARY KEY,
+                name VARCHAR NOT NULL,
+                email VARCHAR NOT NULL
+            );
+            CREATE UNIQUE INDEX IF NOT EXISTS users_email_key ON users (lower(email))",
+        )?;
+
+        Ok(())
+    }
+}
+
+fn user_from_row(row: &postgres::Row) -> User {
+    User {
+        id: row.get("id"),
+        name: row.get("name"),
+        email: row.get("email"),
+    }
+}
+
+impl UserRepository for PostgresUsers {
+    fn create(&self, user: &User) -> Result<User, RepositoryError> {
+        let row = self.db.get()?.query_one(
+            "INSERT INTO users (name, email) VALUES ($1, $2) RETURNING id, name, email",
+            &[&user.name, &user.email],
+        )?;
+
+        Ok(user_from_row(&row))
+    }
+
+    fn get(&self, id: i32) -> Result<User, RepositoryError> {
+        let row = self
+            .db
+            .get()?
+            .query_opt("SELECT id, name, email FROM users WHERE id = $1", &[&id])?;
+
+        row.as_ref()
+            .map(user_from_row)
+            .ok_or(RepositoryError::NotFound)
+    }
+
+    fn list(&self, query: &UserQuery) -> Result<Vec<User>, RepositoryError> {
+        let rows = self.db.get()?.query(
+            "SELECT id, name, email FROM users
+             WHERE $1::VARCHAR IS NULL OR lower(email) = lower($1)
+             ORDER BY id LIMIT $2 OFFSET $3",
+            &[&query.email, &query.limit, &query.offset],
+        )?;
+
+        Ok(rows.iter().map(user_from_row).collect())
+    }
+
+    fn update(&self, id: i32, user: &User) -> Result<User, RepositoryError> {
+        let row = self.db.get()?.query_opt(
+            "UPDATE users SET name = $1, email = $2 WHERE id = $3 RETURNING id, name, email",
+            &[&user.name, &user.email, &id],
+        )?;
+
+        row.as_ref()
+            .map(user_from_row)
+            .ok_or(RepositoryError::NotFound)
+    }
+
+    fn patch(&self, id: i32, patch: &UserPatch) -> Result<User, RepositoryError> {
+        let row = self.db.get()?.query_opt(
+            "UPDATE users SET name = COALESCE($1, name), email = COALESCE($2, email) WHERE id = $3
+             RETURNING id, name, email",
+            &[&patch.name, &patch.email, &id],
+        )?;
+
+        row.as_ref()
+            .map(user_from_row)
+            .ok_or(RepositoryError::NotFound)
+    }
+
+    fn delete(&self, id: i32) -> Result<(), RepositoryError> {
+        let rows_affected = self
+            .db
+            .get()?
+            .execute("DELETE FROM users WHERE id = $1", &[&id])?;
+        if rows_affected == 0 {
+            return Err(RepositoryError::NotFound);
+        }
+
+        Ok(())
+    }
+}
+
+/// Users in a map, gone with the process. Ids start at 1 like a SERIAL.
+#[derive(Default)]
+pub struct MemoryUsers {
+    state: Mutex<MemoryState>,
+}
+
+#[derive(Default)]
+struct MemoryState {
+    last_id: i32,
+    users: BTreeMap<i32, User>,
+}
+
+impl MemoryUsers {
+    fn state(&self) -> std::sync::MutexGuard<'_, MemoryState> {
+        self.state.lock().expect("users lock poisoned")
+    }
+}
+
+impl MemoryState {
+    // like the lower(email) unique index
+    fn check_email_free(&self, email: &str, id: Option<i32>) -> Result<(), RepositoryError> {
+        let taken = self
+            .users
+            .values()
+            .any(|user| user.email.to_lowercase() == email.to_lowercase() && user.id != id);
+        if taken {
+            return Err(RepositoryError::EmailTaken);
+        }
+
+        Ok(())
+    }
+}
+
+impl UserRepository for MemoryUsers {
+    fn create(&self, user: &User) -> Result<User, RepositoryError> {
+        let mut state = self.state();
+        state.check_email_free(&user.email, None)?;
+        state.last_id += 1;
+        let id = state.last_id;
+        let user = User {
+            id: Some(id),
+            ..user.clone()
+        };
+        state.users.insert(id, user.clone());
+
+        Ok(user)
+    }
+
+    fn get(&self, id: i32) -> Result<User, RepositoryError> {
+        self.state()
+            .users
+            .get(&id)
+            .cloned()
+            .ok_or(RepositoryError::NotFound)
+    }
+
+    fn list(&self, query: &UserQuery) -> Result<Vec<User>, RepositoryError> {
+        let email = query.email.as_ref().map(|email| email.to_lowercase());
+        let users = self
+            .state()
+            .users
+            .values()
+            .filter(|user| {
+                email
+                    .as_ref()
+                    .is_none_or(|e| user.email.to_lowercase() == *e)
+            })
+            .skip(query.offset.try_into().unwrap_or(0))
+            .take(query.limit.try_into().unwrap_or(0))
+            .cloned()
+            .collect();
+
+        Ok(users)
+    }
+
+    fn update(&self, id: i32, user: &User) -> Result<User, RepositoryError> {
+        let mut state = self.state();
+        if state.users.contains_key(&id) {
+            state.check_email_free(&user.email, Some(id))?;
+        }
+        let stored = state.users.get_mut(&id).ok_or(RepositoryError::NotFound)?;
+        stored.name = user.name.clone();
+        stored.email = user.email.clone();
+
+        Ok(stored.clone())
+    }
+
+    fn patch(&self, id: i32, patch: &UserPatch) -> Result<User, RepositoryError> {
+        let mut state = self.state();
+        if let Some(email) = &patch.email
+            && state.users.contains_key(&id)
+        {
+            state.check_email_free(email, Some(id))?;
+        }
+        let stored = state.users.get_mut(&id).ok_or(RepositoryError::NotFound)?;
+        if let Some(name) = &patch.name {
+            stored.name = name.clone();
+        }
+        if let Some(email) = &patch.email {
+            stored.email = email.clone();
+        }
+
+        Ok(stored.clone())
+    }
+
+    fn delete(&self, id: i32) -> Result<(), RepositoryError> {
+        self.state()
+            .users
+            .remove(&id)
+            .map(|_| ())
+            .ok_or(RepositoryError::NotFound)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(name: &str, email: &str) -> User {
+        User {
+            id: None,
+            name: name.to_owned(),
+            email: email.to_owned(),
+        }
+    }
+
+    #[test]
+    fn test_validate() {
+        for email in ["ana@example.com", "a.b+tag@mail.example.co", "x@a-b.io"] {
+            assert_eq!(user("Ana", email).validate(), Ok(()), "{email}");
+        }
+        let invalid = [
+            "",
+            "ana",
+            "@example.com",
+            "ana@",
+            "ana@example",
+            "ana@@example.com",
+            "ana@exa mple.com",
+            "an a@example.com",
+            "ana@example..com",
+            "ana@-example.com",
+        ];
+        for email in invalid {
+            assert_eq!(
+                user("Ana", email).validate(),
+                Err("Email is not valid"),
+                "{email}"
+            );
+        }
+        assert_eq!(
+            user("  ", "ana@example.com").validate(),
+            Err("Name is empty")
+        );
+    }
+
+    #[test]
+    fn test_memory_emails_are_unique() {
+        let users = MemoryUsers::default();
+        let ana = users.create(&user("Ana", "ana@example.com")).unwrap();
+        let bo = users.create(&user("Bo", "bo@example.com")).unwrap();
+
+        let result = users.create(&user("Other Ana", "ANA@example.com"));
+        assert!(matches!(result, Err(RepositoryError::EmailTaken)));
+        let result = users.update(bo.id.unwrap(), &user("Bo", "Ana@Example.com"));
+        assert!(matches!(result, Err(RepositoryError::EmailTaken)));
+        // keeping its own email is not a conflict
+        let ana_id = ana.id.unwrap();
+        assert!(
+            users
+                .update(ana_id, &user("Ana B", "ana@example.com"))
+                .is_ok()
+        );
+        let result = users.update(99, &user("Nobody", "ana@example.com"));
+        assert!(matches!(result, Err(RepositoryError::NotFound)));
+        let patch = UserPatch {
+            email: Some("BO@example.com".to_owned()),
+            ..Default::default()
+        };
+        assert!(users.patch(ana_id, &patch).is_err());
+        assert_eq!(users.patch(bo.id.unwrap(), &patch).unwrap().name, "Bo");
+    }
+
+    #[test]
+    fn test_memory_list() {
+        let users = MemoryUsers::default();
+        for n in 1..=5 {
+            let email = format!("user{n}@example.com");
+            users.create(&user(&format!("User {n}"), &email)).unwrap();
+        }
+        let query = |email: Option<&str>, limit, offset| UserQuery {
+            email: email.map(str::to_owned),
+            limit,
+            offset,
+        };
+        let ids = |query| -> Vec<i32> {
+            let users = users.list(&query).unwrap();
+            users.iter().map(|user| user.id.unwrap()).collect()
+        };
+
+        assert_eq!(ids(query(None, 2, 0)), [1, 2]);
+        assert_eq!(ids(query(None, 2, 4)), [5]);
+        assert_eq!(ids(query(Some("USER3@example.com"), 10, 0)), [3]);
+        assert!(ids(query(Some("user3@example.com"), 10, 1)).is_empty());
+    }
+}